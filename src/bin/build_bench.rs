@@ -0,0 +1,99 @@
+use clap::Parser;
+use vector_engine::core::config::HnswConfig;
+use vector_engine::core::flat::FlatIndex;
+use vector_engine::core::hnsw::HNSW;
+use std::time::Instant;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+/// Build throughput benchmark: times serial and parallel graph construction on the same
+/// seeded data and checks recall@10, so speedups can be compared without losing quality.
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    #[arg(short, long, default_value_t = 20_000)]
+    num_vectors: usize,
+
+    #[arg(short, long, default_value_t = 128)]
+    dim: usize,
+
+    #[arg(short, long, default_value_t = 16)]
+    m: usize,
+
+    #[arg(short = 'c', long, default_value_t = 100)]
+    ef: usize,
+
+    #[arg(short, long, default_value_t = 3)]
+    runs: usize,
+
+    #[arg(short, long, default_value_t = 100)]
+    queries: usize,
+
+    #[arg(short, long, default_value_t = 42)]
+    seed: u64,
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = Args::parse();
+
+    let mut rng = StdRng::seed_from_u64(args.seed);
+    let mut random_vec = || -> Vec<f32> { (0..args.dim).map(|_| rng.gen::<f32>()).collect() };
+    let data: Vec<Vec<f32>> = (0..args.num_vectors).map(|_| random_vec()).collect();
+    let queries: Vec<Vec<f32>> = (0..args.queries).map(|_| random_vec()).collect();
+
+    let config = HnswConfig::builder(args.dim)
+        .m(args.m)
+        .ef_construction(args.ef)
+        .seed(args.seed)
+        .build()?;
+
+    println!(
+        "{} vectors x {} dims, M = {}, ef_construction = {}, {} runs",
+        args.num_vectors, args.dim, args.m, args.ef, args.runs
+    );
+
+    let mut best_serial = f64::MAX;
+    let mut best_parallel = f64::MAX;
+    let mut index = HNSW::new(config)?;
+    for run in 0..args.runs {
+        let start = Instant::now();
+        let mut serial = HNSW::new(config)?;
+        for v in &data {
+            serial.insert(v.clone())?;
+        }
+        let serial_secs = start.elapsed().as_secs_f64();
+        best_serial = best_serial.min(serial_secs);
+
+        let start = Instant::now();
+        let mut parallel = HNSW::new(config)?;
+        parallel.insert_parallel(data.clone())?;
+        let parallel_secs = start.elapsed().as_secs_f64();
+        best_parallel = best_parallel.min(parallel_secs);
+
+        println!("  run {}: serial {:.2}s, parallel {:.2}s", run + 1, serial_secs, parallel_secs);
+        index = serial;
+    }
+
+    let throughput = |secs: f64| args.num_vectors as f64 / secs;
+    println!("Serial:   {:>10.0} inserts/s (best {:.2}s)", throughput(best_serial), best_serial);
+    println!(
+        "Parallel: {:>10.0} inserts/s (best {:.2}s, {} threads)",
+        throughput(best_parallel),
+        best_parallel,
+        rayon::current_num_threads()
+    );
+
+    let k = 10;
+    let mut flat = FlatIndex::new(args.dim, config.metric);
+    for v in &data {
+        flat.insert(v.clone())?;
+    }
+    let mut hits = 0;
+    for query in &queries {
+        let truth: Vec<u64> = flat.search(query, k).iter().map(|(id, _)| *id).collect();
+        hits += index.search(query, k).iter().filter(|(key, _)| truth.contains(key)).count();
+    }
+    println!("Recall@{} (serial build): {:.3}", k, hits as f64 / (queries.len() * k) as f64);
+
+    Ok(())
+}
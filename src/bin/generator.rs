@@ -1,8 +1,12 @@
 use clap::Parser;
+use vector_engine::core::config::{ElementType, HnswConfig, Metric, Quantization, SaveOptions, VectorPlacement};
+use vector_engine::core::diagnostics::Diagnostics;
 use vector_engine::core::hnsw::HNSW;
+use vector_engine::storage::mmap::MmapIndex;
 use std::path::PathBuf;
 use std::time::Instant;
-use rand::Rng;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -21,35 +25,115 @@ struct Args {
     
     #[arg(short = 'c', long, default_value_t = 100)]
     ef: usize,
+
+    /// Distance metric: l2, ip (inner product) or cosine.
+    #[arg(long, default_value_t = Metric::L2)]
+    metric: Metric,
+
+    /// Store product-quantized codes with this many bytes per vector (must divide --dim)
+    /// instead of the one-byte-per-dimension scalar arena.
+    #[arg(long)]
+    pq: Option<usize>,
+
+    /// Learn per-dimension u8 ranges from the data instead of the fixed [-1, 1] (ignored with --pq).
+    #[arg(long)]
+    trained_sq: bool,
+
+    /// Element type of the full-precision (rerank) arena: f32, f16 or bf16.
+    #[arg(long, default_value_t = ElementType::F32)]
+    element_type: ElementType,
+
+    /// Full-precision arena placement: inline, sidecar (<output>.vectors) or omit (quantized-only serving).
+    #[arg(long, default_value_t = VectorPlacement::Inline)]
+    vectors: VectorPlacement,
+
+    /// Store 4-bit codes (two per byte) instead of one byte per dimension (ignored with --pq).
+    #[arg(long)]
+    sq4: bool,
+
+    /// Also write the 1-bit sign arena for binary-cascade searches.
+    #[arg(long)]
+    binary: bool,
+
+    /// Seed for data and level generation. Trade-off: a seeded build runs on ONE thread, because
+    /// parallel linking depends on thread scheduling; the output file is byte-identical across
+    /// runs, but the build is many times slower. Omit it for a fast, non-reproducible build.
+    #[arg(short, long)]
+    seed: Option<u64>,
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args = Args::parse();
     
-    println!("Generating {} vectors of dimension {}...", args.num_vectors, args.dim);
+    println!("Generating {} vectors of dimension {} ({})...", args.num_vectors, args.dim, args.metric);
     let start = Instant::now();
 
-    let mut index = HNSW::new(args.dim, args.m, args.m, args.ef);
+    let mut builder = HnswConfig::builder(args.dim)
+        .m(args.m)
+        .ef_construction(args.ef)
+        .metric(args.metric);
+    if let Some(seed) = args.seed {
+        builder = builder.seed(seed);
+    }
+    let mut index = HNSW::new(builder.build()?)?;
     
-    let mut rng = rand::thread_rng();
+    let mut rng = match args.seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_entropy(),
+    };
+    let data: Vec<Vec<f32>> = (0..args.num_vectors)
+        .map(|_| (0..args.dim).map(|_| rng.gen::<f32>()).collect())
+        .collect();
     
-    for i in 0..args.num_vectors {
-        let vec: Vec<f32> = (0..args.dim).map(|_| rng.gen::<f32>()).collect();
-        index.insert(vec);
-        
-        if (i+1) % 1000 == 0 {
-            print!("\rInserted {} / {}", i+1, args.num_vectors);
-            use std::io::Write;
-            std::io::stdout().flush()?;
+    if args.seed.is_some() {
+        // Parallel linking depends on thread scheduling: deterministic output needs the serial path.
+        eprintln!("warning: --seed disables the parallel build; linking {} vectors on one thread", data.len());
+        println!("Data ready in {:.2?}, building graph serially (seeded)...", start.elapsed());
+        let total = data.len();
+        for (i, vec) in data.into_iter().enumerate() {
+            index.insert(vec)?;
+            
+            if (i+1) % 1000 == 0 {
+                print!("\rInserted {} / {}", i+1, total);
+                use std::io::Write;
+                std::io::stdout().flush()?;
+            }
         }
+        println!();
+    } else {
+        println!("Data ready in {:.2?}, building graph on {} threads...", start.elapsed(), rayon::current_num_threads());
+        index.insert_parallel(data)?;
     }
     
-    println!("\nBuild complete in {:.2?}s", start.elapsed());
+    println!("Build complete in {:.2?}", start.elapsed());
+    let build_memory = index.memory_usage();
+    
+    println!("Layer histogram (actual / expected):");
+    let expected = index.expected_layer_histogram(index.len());
+    for (level, count) in index.layer_histogram().iter().enumerate() {
+        println!("  L{:<2} {:>10} / {:>12.1}", level, count, expected[level]);
+    }
     
     println!("Saving to {:?}...", args.output);
     let save_start = Instant::now();
-    index.save(&args.output)?;
-    println!("Saved in {:.2?}s", save_start.elapsed());
+    let quantization = match args.pq {
+        Some(subspaces) => Quantization::Product { subspaces },
+        None if args.trained_sq => Quantization::TrainedScalar8,
+        None if args.sq4 => Quantization::Scalar4,
+        None => Quantization::Scalar8,
+    };
+    index.save_with(&args.output, &SaveOptions { quantization, binary: args.binary, element_type: args.element_type, vectors: args.vectors })?;
+    println!("Saved in {:.2?}", save_start.elapsed());
+    println!("Index: {}", Diagnostics::info(&MmapIndex::load(&args.output)?));
+
+    let mib = |bytes: u64| bytes as f64 / (1024.0 * 1024.0);
+    let file_size = std::fs::metadata(&args.output)?.len();
+    println!(
+        "Build memory: {:.1} MiB, file: {:.1} MiB ({:.2}x)",
+        mib(build_memory as u64),
+        mib(file_size),
+        build_memory as f64 / file_size as f64
+    );
     
     Ok(())
 }
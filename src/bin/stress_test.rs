@@ -20,6 +20,8 @@ use ratatui::{
 };
 use vector_engine::storage::mmap::MmapIndex;
 use vector_engine::core::runtime::RuntimeConfig;
+use vector_engine::core::search::{Cascade, Rerank, SearchContext, SearchParams};
+use vector_engine::core::flat::FlatIndex;
 use rand::Rng;
 use sysinfo::{System, Pid};
 use hdrhistogram::Histogram;
@@ -44,6 +46,14 @@ struct Args {
 
     #[arg(long)]
     safe_mode: bool,
+
+    /// Traverse on the binary arena and rerank this many candidates (index built with --binary).
+    #[arg(long)]
+    binary_keep: Option<usize>,
+
+    /// Rerank only this many stage-1 candidates in f32 (0 = skip the rerank).
+    #[arg(long)]
+    rerank: Option<usize>,
 }
 
 #[derive(PartialEq, Clone, Copy)]
@@ -102,7 +112,8 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     let state = Arc::new(Mutex::new(AppState::Calibrating));
     let running_flag = Arc::new(std::sync::atomic::AtomicBool::new(false));
     let calibrated_ef = Arc::new(AtomicUsize::new(args.ef.unwrap_or(64)));
-    let is_auto_ef = args.ef.is_none();
+    // Calibration needs exact ground truth from the f32 arena; without it, keep the default ef
+    let is_auto_ef = args.ef.is_none() && index.has_full_vectors();
 
     // 2. Resource Monitor
     let stats_mon = stats.clone();
@@ -136,6 +147,12 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     // 4. Search Workers
     let dim = index.header().dimension as usize;
+    let cascade = args.binary_keep.map_or(Cascade::Quantized, |keep| Cascade::Binary { keep });
+    let rerank = match args.rerank {
+        None => Rerank::All,
+        Some(0) => Rerank::Skip,
+        Some(n) => Rerank::Top(n),
+    };
     let mut handles = Vec::new();
     for i in 0..concurrency {
         let index_ref = index.clone();
@@ -150,6 +167,8 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             let mut rng = rand::thread_rng();
             let mut local_hist = Histogram::<u64>::new(3).unwrap();
             let mut batch = 0;
+            let mut search_ctx = SearchContext::new();
+            let mut query = vec![0.0f32; dim];
 
             while !flag_ref.load(Ordering::Acquire) {
                 thread::sleep(Duration::from_millis(10));
@@ -157,9 +176,9 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
             while flag_ref.load(Ordering::Relaxed) {
                 let ef = ef_atomic.load(Ordering::Relaxed);
-                let query: Vec<f32> = (0..dim).map(|_| rng.gen::<f32>()).collect();
+                query.iter_mut().for_each(|x| *x = rng.gen::<f32>());
                 let start = Instant::now();
-                let _res = index_ref.search_two_stage(&query, k, ef);
+                let _res = index_ref.search_with_context(&mut search_ctx, &query, k, SearchParams::new(ef).with_cascade(cascade).with_rerank(rerank));
                 let lat = start.elapsed().as_micros() as u64;
 
                 stats_ref.total_queries.fetch_add(1, Ordering::Relaxed);
@@ -209,7 +228,6 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             if is_auto_ef {
                 // Perform calibration in background or here? 
                 // Let's do a simplified calibration to avoid blocking TUI for too long
-                let truth_ef = 256;
                 let mut best_ef = 64;
                 
                 // Sample queries
@@ -218,15 +236,16 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                     (0..dim).map(|_| rng.gen::<f32>()).collect()
                 }).collect();
 
-                let ground_truth: Vec<Vec<usize>> = calibrate_queries.iter().map(|q| {
-                    index.search_two_stage(q, args.k, truth_ef).into_iter().map(|(id, _)| id).collect()
+                // Exact top-k from a full scan of the f32 arena
+                let ground_truth: Vec<Vec<u64>> = calibrate_queries.iter().map(|q| {
+                    FlatIndex::search_mmap(&index, q, args.k).into_iter().map(|(id, _)| id).collect()
                 }).collect();
 
                     for test_ef in [16, 32, 48, 64, 80, 96, 128] {
                         let mut matches = 0;
                         let mut total = 0;
                         for (i, q) in calibrate_queries.iter().enumerate() {
-                            let results: Vec<usize> = index.search_two_stage(q, args.k, test_ef).into_iter().map(|(id, _)| id).collect();
+                            let results: Vec<u64> = index.search_with_params(q, args.k, SearchParams::new(test_ef).with_cascade(cascade).with_rerank(rerank)).into_iter().map(|(id, _)| id).collect();
                             for id in &results {
                                 if ground_truth[i].contains(id) { matches += 1; }
                             }
@@ -482,6 +501,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     println!("{}", "=".repeat(50));
     println!("{:<25} : {}", "Total Vectors (N)", h.num_elements);
     println!("{:<25} : {}", "Dimensions", h.dimension);
+    println!("{:<25} : {}", "Metric", index.metric().map_or_else(|| "custom".to_string(), |m| m.to_string()));
     println!("{:<25} : {}", "Concurrency (Auto)", concurrency);
     println!("{:<25} : {}", "Search EF (Calibrated)", calibrated_ef.load(Ordering::Relaxed));
     println!("{}", "-".repeat(50));
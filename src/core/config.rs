@@ -0,0 +1,418 @@
+use crate::core::hnsw::{HnswError, NeighborSelection};
+use crate::core::ivf::IvfError;
+
+/// Distance metric an index is built and searched with.
+/// Stored in the `Header` as its discriminant. Every search reports a distance where lower is closer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[repr(u32)]
+pub enum Metric {
+    /// Euclidean distance. Vectors are stored as inserted.
+    #[default]
+    L2 = 0,
+    /// Maximum inner product: distance = -(q . x). Vectors are stored as inserted.
+    InnerProduct = 1,
+    /// Cosine: distance = 1 - cos(q, x). Vectors are L2-normalized on insert.
+    Cosine = 2,
+}
+
+impl Metric {
+    pub fn from_u32(value: u32) -> Option<Self> {
+        match value {
+            0 => Some(Metric::L2),
+            1 => Some(Metric::InnerProduct),
+            2 => Some(Metric::Cosine),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for Metric {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Metric::L2 => "l2",
+            Metric::InnerProduct => "ip",
+            Metric::Cosine => "cosine",
+        })
+    }
+}
+
+impl std::str::FromStr for Metric {
+    type Err = String;
+
+    /// Accepts the `Display` names plus a few common aliases.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "l2" | "euclidean" => Ok(Metric::L2),
+            "ip" | "inner_product" | "dot" => Ok(Metric::InnerProduct),
+            "cosine" | "cos" => Ok(Metric::Cosine),
+            _ => Err(format!("unknown metric '{}' (expected l2, ip or cosine)", s)),
+        }
+    }
+}
+
+/// Stage-1 representation `MmapIndex` scans before reranking on the f32 arena.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Quantization {
+    /// One byte per dimension: `Quantizer::quantize_u8` of x / max_norm, scored with the int8 kernel.
+    #[default]
+    Scalar8,
+    /// One byte per dimension over a per-dimension range learned at save time. See `TrainedQuantizer`.
+    TrainedScalar8,
+    /// Half a byte per dimension: `Quantizer::quantize_u4` of x / max_norm, two codes per byte.
+    Scalar4,
+    /// Product quantization: `subspaces` bytes per vector (must divide the dimension),
+    /// scored with per-query ADC tables. See `ProductQuantizer`.
+    Product { subspaces: usize },
+}
+
+impl std::fmt::Display for Quantization {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Quantization::Scalar8 => f.write_str("sq8"),
+            Quantization::TrainedScalar8 => f.write_str("sq8t"),
+            Quantization::Scalar4 => f.write_str("sq4"),
+            Quantization::Product { subspaces } => write!(f, "pq{}", subspaces),
+        }
+    }
+}
+
+/// Element type of the full-precision arena `MmapIndex` reranks on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ElementType {
+    #[default]
+    F32,
+    /// IEEE half precision: 2 bytes, 11-bit mantissa, range +-65504.
+    F16,
+    /// bfloat16: 2 bytes, the f32 exponent range with an 8-bit mantissa.
+    BF16,
+}
+
+impl std::fmt::Display for ElementType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            ElementType::F32 => "f32",
+            ElementType::F16 => "f16",
+            ElementType::BF16 => "bf16",
+        })
+    }
+}
+
+impl std::str::FromStr for ElementType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "f32" => Ok(ElementType::F32),
+            "f16" | "half" => Ok(ElementType::F16),
+            "bf16" => Ok(ElementType::BF16),
+            _ => Err(format!("unknown element type '{}' (expected f32, f16 or bf16)", s)),
+        }
+    }
+}
+
+/// Where `HNSW::save_with` writes the full-precision arena.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum VectorPlacement {
+    /// In the index file.
+    #[default]
+    Inline,
+    /// In a separate file next to the index (`vectors_sidecar_path`), which `MmapIndex::load`
+    /// maps when it is present. Nodes without it serve quantized-only.
+    Sidecar,
+    /// Not written: the index serves quantized-only and cannot be reopened with `HNSW::from_mmap`.
+    Omitted,
+}
+
+impl std::fmt::Display for VectorPlacement {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            VectorPlacement::Inline => "inline",
+            VectorPlacement::Sidecar => "sidecar",
+            VectorPlacement::Omitted => "omit",
+        })
+    }
+}
+
+impl std::str::FromStr for VectorPlacement {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "inline" => Ok(VectorPlacement::Inline),
+            "sidecar" => Ok(VectorPlacement::Sidecar),
+            "omit" | "none" => Ok(VectorPlacement::Omitted),
+            _ => Err(format!("unknown vector placement '{}' (expected inline, sidecar or omit)", s)),
+        }
+    }
+}
+
+/// File layout choices for `HNSW::save_with`. The default is what `HNSW::save` writes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SaveOptions {
+    pub quantization: Quantization,
+    /// Also write the 1-bit sign arena that `Cascade::Binary` searches traverse.
+    pub binary: bool,
+    /// Element type of the full-precision arena. 16-bit types halve it at a small rerank cost.
+    pub element_type: ElementType,
+    pub vectors: VectorPlacement,
+}
+
+/// Validated HNSW build parameters. Create with `HnswConfig::builder(dimension)`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HnswConfig {
+    /// Vector length. Inserts of any other length are rejected.
+    pub dimension: usize,
+    /// Max links per node on layers >= 1.
+    pub m: usize,
+    /// Max links per node on layer 0.
+    pub m0: usize,
+    /// Beam width while linking new nodes.
+    pub ef_construction: usize,
+    /// mL: scale of the exponential level distribution.
+    pub level_multiplier: f64,
+    /// Hard cap on the number of layers (levels are 0..max_layers).
+    pub max_layers: usize,
+    /// RNG seed for level assignment, `None` = from entropy.
+    pub seed: Option<u64>,
+    pub metric: Metric,
+    pub neighbor_selection: NeighborSelection,
+}
+
+impl HnswConfig {
+    pub fn builder(dimension: usize) -> HnswConfigBuilder {
+        HnswConfigBuilder {
+            dimension,
+            m: 16,
+            m0: None,
+            ef_construction: 100,
+            level_multiplier: None,
+            max_layers: 16,
+            seed: None,
+            metric: Metric::default(),
+            neighbor_selection: NeighborSelection::default(),
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), HnswError> {
+        let invalid = |msg: String| Err(HnswError::InvalidConfig(msg));
+
+        if self.dimension == 0 {
+            return invalid("dimension must be > 0".to_string());
+        }
+        if self.m < 2 {
+            return invalid(format!("m must be >= 2, got {}", self.m));
+        }
+        if self.m0 < self.m {
+            return invalid(format!("m0 ({}) must be >= m ({})", self.m0, self.m));
+        }
+        if self.ef_construction == 0 {
+            return invalid("ef_construction must be > 0".to_string());
+        }
+        if !self.level_multiplier.is_finite() || self.level_multiplier <= 0.0 {
+            return invalid(format!("level_multiplier must be finite and > 0, got {}", self.level_multiplier));
+        }
+        // Layer counts are stored as u8 per node on disk.
+        if self.max_layers == 0 || self.max_layers > u8::MAX as usize {
+            return invalid(format!("max_layers must be in 1..=255, got {}", self.max_layers));
+        }
+        Ok(())
+    }
+}
+
+/// Builder for `HnswConfig`. Unset values default to M = 16, M0 = 2 * M, ef_construction = 100,
+/// mL = 1 / ln(M), 16 layers, no seed, L2.
+#[derive(Debug, Clone)]
+pub struct HnswConfigBuilder {
+    dimension: usize,
+    m: usize,
+    m0: Option<usize>,
+    ef_construction: usize,
+    level_multiplier: Option<f64>,
+    max_layers: usize,
+    seed: Option<u64>,
+    metric: Metric,
+    neighbor_selection: NeighborSelection,
+}
+
+impl HnswConfigBuilder {
+    pub fn m(mut self, m: usize) -> Self {
+        self.m = m;
+        self
+    }
+
+    pub fn m0(mut self, m0: usize) -> Self {
+        self.m0 = Some(m0);
+        self
+    }
+
+    pub fn ef_construction(mut self, ef_construction: usize) -> Self {
+        self.ef_construction = ef_construction;
+        self
+    }
+
+    pub fn level_multiplier(mut self, level_multiplier: f64) -> Self {
+        self.level_multiplier = Some(level_multiplier);
+        self
+    }
+
+    pub fn max_layers(mut self, max_layers: usize) -> Self {
+        self.max_layers = max_layers;
+        self
+    }
+
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    pub fn metric(mut self, metric: Metric) -> Self {
+        self.metric = metric;
+        self
+    }
+
+    pub fn neighbor_selection(mut self, neighbor_selection: NeighborSelection) -> Self {
+        self.neighbor_selection = neighbor_selection;
+        self
+    }
+
+    pub fn build(self) -> Result<HnswConfig, HnswError> {
+        let config = HnswConfig {
+            dimension: self.dimension,
+            m: self.m,
+            m0: self.m0.unwrap_or(self.m * 2),
+            ef_construction: self.ef_construction,
+            level_multiplier: self.level_multiplier.unwrap_or(1.0 / (self.m as f64).ln()),
+            max_layers: self.max_layers,
+            seed: self.seed,
+            metric: self.metric,
+            neighbor_selection: self.neighbor_selection,
+        };
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+/// Validated IVF build parameters. Create with `IvfConfig::builder(dimension)`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IvfConfig {
+    pub dimension: usize,
+    /// Number of k-means centroids, i.e. inverted lists.
+    pub nlist: usize,
+    /// Lloyd iterations while training.
+    pub train_iters: usize,
+    /// RNG seed for centroid initialization, `None` = from entropy.
+    pub seed: Option<u64>,
+    pub metric: Metric,
+}
+
+impl IvfConfig {
+    pub fn builder(dimension: usize) -> IvfConfigBuilder {
+        IvfConfigBuilder {
+            dimension,
+            nlist: 256,
+            train_iters: 20,
+            seed: None,
+            metric: Metric::default(),
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), IvfError> {
+        let invalid = |msg: String| Err(IvfError::InvalidConfig(msg));
+
+        if self.dimension == 0 {
+            return invalid("dimension must be > 0".to_string());
+        }
+        // List ids are stored as u32 on disk.
+        if self.nlist == 0 || self.nlist > u32::MAX as usize {
+            return invalid(format!("nlist must be in 1..=u32::MAX, got {}", self.nlist));
+        }
+        if self.train_iters == 0 {
+            return invalid("train_iters must be > 0".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Builder for `IvfConfig`. Unset values default to 256 lists, 20 iterations, no seed, L2.
+#[derive(Debug, Clone)]
+pub struct IvfConfigBuilder {
+    dimension: usize,
+    nlist: usize,
+    train_iters: usize,
+    seed: Option<u64>,
+    metric: Metric,
+}
+
+impl IvfConfigBuilder {
+    pub fn nlist(mut self, nlist: usize) -> Self {
+        self.nlist = nlist;
+        self
+    }
+
+    pub fn train_iters(mut self, train_iters: usize) -> Self {
+        self.train_iters = train_iters;
+        self
+    }
+
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    pub fn metric(mut self, metric: Metric) -> Self {
+        self.metric = metric;
+        self
+    }
+
+    pub fn build(self) -> Result<IvfConfig, IvfError> {
+        let config = IvfConfig {
+            dimension: self.dimension,
+            nlist: self.nlist,
+            train_iters: self.train_iters,
+            seed: self.seed,
+            metric: self.metric,
+        };
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_builder_defaults_and_validation() {
+        let config = HnswConfig::builder(128).m(12).build().unwrap();
+        assert_eq!(config.m0, 24);
+        assert!((config.level_multiplier - 1.0 / 12f64.ln()).abs() < 1e-12);
+        assert_eq!(config.metric, Metric::L2);
+
+        for metric in [Metric::L2, Metric::InnerProduct, Metric::Cosine] {
+            assert_eq!(Metric::from_u32(metric as u32), Some(metric));
+            assert_eq!(metric.to_string().parse::<Metric>(), Ok(metric));
+        }
+        assert_eq!(Metric::from_u32(3), None);
+        assert!("manhattan".parse::<Metric>().is_err());
+
+        let invalid = [
+            HnswConfig::builder(0).build(),
+            HnswConfig::builder(8).m(1).build(),
+            HnswConfig::builder(8).m(16).m0(8).build(),
+            HnswConfig::builder(8).ef_construction(0).build(),
+            HnswConfig::builder(8).level_multiplier(0.0).build(),
+            HnswConfig::builder(8).level_multiplier(f64::NAN).build(),
+            HnswConfig::builder(8).max_layers(0).build(),
+            HnswConfig::builder(8).max_layers(300).build(),
+        ];
+        for result in invalid {
+            assert!(matches!(result, Err(HnswError::InvalidConfig(_))), "{:?}", result);
+        }
+
+        let ivf = IvfConfig::builder(64).nlist(16).build().unwrap();
+        assert_eq!((ivf.nlist, ivf.train_iters, ivf.metric), (16, 20, Metric::L2));
+        for result in [IvfConfig::builder(0).build(), IvfConfig::builder(8).nlist(0).build(), IvfConfig::builder(8).train_iters(0).build()] {
+            assert!(matches!(result, Err(IvfError::InvalidConfig(_))), "{:?}", result);
+        }
+    }
+}
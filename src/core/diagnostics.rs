@@ -1,5 +1,7 @@
+use crate::core::config::{ElementType, Metric, Quantization};
+use crate::core::distance::Distance;
 use crate::storage::mmap::MmapIndex;
-use crate::storage::format::Header;
+use crate::storage::format::{Header, METRIC_CUSTOM};
 
 #[derive(Debug)]
 pub enum HealthStatus {
@@ -8,12 +10,47 @@ pub enum HealthStatus {
     Suspicious(String),
 }
 
+/// What a loaded index was built with, for logs and CLI output.
+#[derive(Debug, Clone, PartialEq)]
+pub struct IndexInfo {
+    pub version: u32,
+    /// `None` for a custom `Distance`.
+    pub metric: Option<Metric>,
+    /// Stage-1 representation.
+    pub quantization: Quantization,
+    /// Whether a 1-bit sign arena is stored for `Cascade::Binary`.
+    pub binary: bool,
+    /// Element type of the full-precision arena.
+    pub element_type: ElementType,
+    pub dimension: usize,
+    pub num_elements: usize,
+    pub num_deleted: usize,
+    pub max_layer: usize,
+    pub m: usize,
+    pub m0: usize,
+    /// Scale of the quantized arena (1.0 for cosine).
+    pub max_norm: f32,
+}
+
+impl std::fmt::Display for IndexInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "v{} {} x {}d, metric {}, {}{}, {}, {} deleted, top layer {}, M {}/{}, max norm {:.3}",
+            self.version, self.num_elements, self.dimension,
+            self.metric.map_or_else(|| "custom".to_string(), |m| m.to_string()), self.quantization,
+            if self.binary { "+binary" } else { "" }, self.element_type, self.num_deleted,
+            self.max_layer, self.m, self.m0, self.max_norm
+        )
+    }
+}
+
 pub struct Diagnostics;
 
 impl Diagnostics {
     /// Performs a full health check on the loaded index.
     /// Corresponds to Risk Register items R01 (Corruption) and R05 (DoS).
-    pub fn check_health(index: &MmapIndex) -> HealthStatus {
+    pub fn check_health<D: Distance>(index: &MmapIndex<D>) -> HealthStatus {
         let header = index.header();
         
         // Check 1: Magic Bytes (R01)
@@ -30,6 +67,10 @@ impl Diagnostics {
             return HealthStatus::Suspicious(format!("Unusually high element count: {}", header.num_elements));
         }
 
+        if Metric::from_u32(header.metric).is_none() && header.metric != METRIC_CUSTOM {
+            return HealthStatus::Corrupted(format!("Unknown metric: {}", header.metric));
+        }
+
         // Check 3: Bounds Consistency (R01)
         // Ensure offsets are strictly increasing and within file bounds.
         // We can't easily check file size here without the file handle, but MmapIndex checked it on load.
@@ -37,13 +78,74 @@ impl Diagnostics {
         if header.nodes_offset < std::mem::size_of::<Header>() as u64 {
             return HealthStatus::Corrupted("Nodes offset overlaps header".to_string());
         }
-        if header.vectors_offset < header.nodes_offset {
-            return HealthStatus::Corrupted("Vectors offset before nodes".to_string());
+        // Files whose f32 arena lives in a sidecar (or was omitted) have no vectors offset.
+        if header.vectors_offset != 0 {
+            if header.vectors_offset < header.nodes_offset {
+                return HealthStatus::Corrupted("Vectors offset before nodes".to_string());
+            }
+            if header.connections_offset < header.vectors_offset {
+                return HealthStatus::Corrupted("Connections offset before vectors".to_string());
+            }
+        } else if header.connections_offset < header.nodes_offset {
+            return HealthStatus::Corrupted("Connections offset before nodes".to_string());
+        }
+        if header.deleted_offset != 0 && header.deleted_offset < header.connections_offset + header.connections_size {
+            return HealthStatus::Corrupted("Deleted bitmap overlaps connections".to_string());
         }
-        if header.connections_offset < header.vectors_offset {
-            return HealthStatus::Corrupted("Connections offset before vectors".to_string());
+        if header.keys_offset != 0 && header.key_index_offset < header.keys_offset + header.num_elements as u64 * 8 {
+            return HealthStatus::Corrupted("Key index overlaps keys".to_string());
+        }
+
+        if header.norms_offset != 0 && header.norms_offset < header.key_index_offset + header.num_keys * 16 {
+            return HealthStatus::Corrupted("Norms overlap key index".to_string());
         }
 
         HealthStatus::Healthy
     }
+
+    pub fn info<D: Distance>(index: &MmapIndex<D>) -> IndexInfo {
+        let header = index.header();
+        IndexInfo {
+            version: header.version,
+            metric: index.metric(),
+            quantization: index.quantization(),
+            binary: index.has_binary(),
+            element_type: index.element_type(),
+            dimension: header.dimension as usize,
+            num_elements: header.num_elements as usize,
+            num_deleted: index.deleted_bitmap().map_or(0, |bits| bits.iter().map(|w| w.count_ones() as usize).sum()),
+            max_layer: header.max_layer as usize,
+            m: header.m_max as usize,
+            m0: header.m_max_0 as usize,
+            max_norm: index.max_norm(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::config::HnswConfig;
+    use crate::core::hnsw::HNSW;
+
+    #[test]
+    fn test_info_reports_metric() -> Result<(), Box<dyn std::error::Error>> {
+        let config = HnswConfig::builder(3).metric(Metric::Cosine).build()?;
+        let mut index = HNSW::new(config)?;
+        for v in [[1.0, 0.0, 0.0], [0.0, 2.0, 0.0], [0.0, 0.0, 3.0]] {
+            index.insert(v.to_vec())?;
+        }
+        index.delete(1);
+
+        let temp_file = tempfile::NamedTempFile::new()?;
+        index.save(temp_file.path())?;
+        let mmap_index = MmapIndex::load(temp_file.path())?;
+
+        assert!(matches!(Diagnostics::check_health(&mmap_index), HealthStatus::Healthy));
+        let info = Diagnostics::info(&mmap_index);
+        assert_eq!((info.metric, info.num_elements, info.num_deleted, info.max_norm), (Some(Metric::Cosine), 3, 1, 1.0));
+        assert!(info.to_string().contains("metric cosine, sq8"));
+
+        Ok(())
+    }
 }
@@ -0,0 +1,64 @@
+use crate::core::config::Metric;
+use crate::core::quantization::Quantizer;
+use crate::simd::{DistanceFunc, Kernels};
+
+/// Distance an index is built and searched with. Lower is closer.
+///
+/// `HNSW` and `MmapIndex` are generic over it and default to `MetricDistance` (the built-in metrics).
+/// Implementations are free to specialize `distance` with SIMD; `MetricDistance` resolves its kernel
+/// once at construction so the hot loop is a single indirect call.
+pub trait Distance: Send + Sync {
+    fn distance(&self, a: &[f32], b: &[f32]) -> f32;
+
+    /// Applied to every vector before it is stored and to every query (e.g. normalization for cosine).
+    fn prepare(&self, _vector: &mut [f32]) {}
+
+    /// The built-in metric this distance computes, if any.
+    /// Only built-in metrics can use the quantized first stage of `MmapIndex`; any other
+    /// distance is searched exactly over the full-precision arena.
+    fn metric(&self) -> Option<Metric> {
+        None
+    }
+}
+
+/// One of the built-in `Metric`s, backed by the best SIMD kernel available on this CPU.
+#[derive(Clone, Copy)]
+pub struct MetricDistance {
+    metric: Metric,
+    kernel: DistanceFunc,
+}
+
+impl MetricDistance {
+    pub fn new(metric: Metric) -> Self {
+        Self::with_kernels(metric, &Kernels::detect())
+    }
+
+    /// `metric` computed with the kernel from `kernels`, e.g. `Kernels::scalar()` in tests.
+    pub fn with_kernels(metric: Metric, kernels: &Kernels) -> Self {
+        Self { metric, kernel: kernels.distance(metric) }
+    }
+}
+
+impl std::fmt::Debug for MetricDistance {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MetricDistance").field("metric", &self.metric).finish()
+    }
+}
+
+impl Distance for MetricDistance {
+    fn distance(&self, a: &[f32], b: &[f32]) -> f32 {
+        assert_eq!(a.len(), b.len());
+        // The kernels only need equal lengths and the CPU features checked by `Kernels::detect`.
+        unsafe { (self.kernel)(a, b) }
+    }
+
+    fn prepare(&self, vector: &mut [f32]) {
+        if self.metric == Metric::Cosine {
+            Quantizer::l2_normalize(vector);
+        }
+    }
+
+    fn metric(&self) -> Option<Metric> {
+        Some(self.metric)
+    }
+}
@@ -0,0 +1,252 @@
+use crate::core::config::Metric;
+use crate::core::distance::{Distance, MetricDistance};
+use crate::core::hnsw::{next_key_after, HnswError};
+use crate::core::search::{push_bounded, Candidate};
+use crate::storage::mmap::MmapIndex;
+use rayon::prelude::*;
+use std::collections::{BinaryHeap, HashMap};
+
+/// Ids scanned per rayon task. Large enough to amortize the heap merge, small enough to balance.
+const SCAN_CHUNK: usize = 4096;
+
+/// Exact brute-force index: every query scans the whole f32 arena with the SIMD kernel on the
+/// rayon pool. The ground truth for recall measurements, and a fine index on its own for
+/// collections up to ~100k vectors, where it needs no build and never misses.
+pub struct FlatIndex<D = MetricDistance> {
+    dimension: usize,
+    distance: D,
+    /// Vector slab, `dimension` floats per id.
+    vectors: Vec<f32>,
+    keys: Vec<u64>,
+    key_to_id: HashMap<u64, usize>,
+    /// `None` once `u64::MAX` is in use, like `HNSW`.
+    next_key: Option<u64>,
+}
+
+impl FlatIndex {
+    pub fn new(dimension: usize, metric: Metric) -> Self {
+        Self::with_distance(dimension, MetricDistance::new(metric))
+    }
+
+    /// Exact top-`k` over the full-precision arena of a saved index, skipping deleted nodes.
+    /// Distances match what `MmapIndex::search_two_stage` reports for the same vectors.
+    /// Empty if the index was saved without its full-precision arena.
+    pub fn search_mmap<E: Distance>(index: &MmapIndex<E>, query: &[f32], k: usize) -> Vec<(u64, f32)> {
+        if !index.has_full_vectors() {
+            return Vec::new();
+        }
+        let distance = index.distance();
+        let mut query = query.to_vec();
+        distance.prepare(&mut query);
+        let num_nodes = index.header().num_elements as usize;
+        // Per-chunk buffer for f16/bf16 arenas, widened with the kernel resolved at load
+        scan_top_k(num_nodes, k, Vec::new, |buf, id| {
+            (!index.is_deleted(id)).then(|| distance.distance(&query, index.full_vector_into(id, buf)))
+        })
+        .into_iter()
+        .map(|(id, dist)| (index.get_key(id), dist))
+        .collect()
+    }
+}
+
+impl<D: Distance> FlatIndex<D> {
+    pub fn with_distance(dimension: usize, distance: D) -> Self {
+        Self { dimension, distance, vectors: Vec::new(), keys: Vec::new(), key_to_id: HashMap::new(), next_key: Some(0) }
+    }
+
+    pub fn dimension(&self) -> usize {
+        self.dimension
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    /// Insert under the next free key and return it.
+    pub fn insert(&mut self, vector: Vec<f32>) -> Result<u64, HnswError> {
+        let key = self.next_key.ok_or(HnswError::KeysExhausted)?;
+        self.insert_with_key(key, vector)?;
+        Ok(key)
+    }
+
+    pub fn insert_with_key(&mut self, key: u64, mut vector: Vec<f32>) -> Result<(), HnswError> {
+        if vector.len() != self.dimension {
+            return Err(HnswError::DimensionMismatch { expected: self.dimension, actual: vector.len() });
+        }
+        if self.key_to_id.contains_key(&key) {
+            return Err(HnswError::DuplicateKey(key));
+        }
+        self.distance.prepare(&mut vector);
+        self.key_to_id.insert(key, self.keys.len());
+        self.keys.push(key);
+        self.vectors.extend_from_slice(&vector);
+        self.next_key = next_key_after(self.next_key, key);
+        Ok(())
+    }
+
+    /// Remove a key. The last vector moves into the freed slot, so the arena stays dense.
+    pub fn delete_by_key(&mut self, key: u64) -> bool {
+        let Some(id) = self.key_to_id.remove(&key) else {
+            return false;
+        };
+        let dim = self.dimension;
+        let last = self.keys.len() - 1;
+        self.vectors.copy_within(last * dim..(last + 1) * dim, id * dim);
+        self.vectors.truncate(last * dim);
+        self.keys.swap_remove(id);
+        if id != last {
+            self.key_to_id.insert(self.keys[id], id);
+        }
+        true
+    }
+
+    /// The stored vector (unit length for cosine).
+    pub fn get_vector_by_key(&self, key: u64) -> Option<&[f32]> {
+        let dim = self.dimension;
+        self.key_to_id.get(&key).map(|&id| &self.vectors[id * dim..(id + 1) * dim])
+    }
+
+    /// Exact top-`k` as `(key, distance)` pairs, closest first.
+    pub fn search(&self, query: &[f32], k: usize) -> Vec<(u64, f32)> {
+        let mut query = query.to_vec();
+        self.distance.prepare(&mut query);
+        let dim = self.dimension;
+        scan_top_k(self.len(), k, || (), |_, id| Some(self.distance.distance(&query, &self.vectors[id * dim..(id + 1) * dim])))
+            .into_iter()
+            .map(|(id, dist)| (self.keys[id], dist))
+            .collect()
+    }
+}
+
+/// Best `k` of ids `0..n` by `score` (`None` = skip), sorted by distance then id.
+/// Each chunk keeps a bounded max-heap and its own `init()` scratch; the per-chunk heaps are
+/// merged pairwise.
+fn scan_top_k<S>(n: usize, k: usize, init: impl Fn() -> S + Sync, score: impl Fn(&mut S, usize) -> Option<f32> + Sync) -> Vec<(usize, f32)> {
+    if k == 0 {
+        return Vec::new();
+    }
+    let heap = (0..n.div_ceil(SCAN_CHUNK))
+        .into_par_iter()
+        .map(|chunk| {
+            let mut heap = BinaryHeap::with_capacity(k + 1);
+            let mut scratch = init();
+            for id in chunk * SCAN_CHUNK..((chunk + 1) * SCAN_CHUNK).min(n) {
+                if let Some(distance) = score(&mut scratch, id) {
+                    push_bounded(&mut heap, Candidate { distance, node_id: id }, k);
+                }
+            }
+            heap
+        })
+        .reduce(BinaryHeap::new, |mut a, b| {
+            for c in b {
+                push_bounded(&mut a, c, k);
+            }
+            a
+        });
+
+    let mut results: Vec<(usize, f32)> = heap.into_iter().map(|c| (c.node_id, c.distance)).collect();
+    results.sort_unstable_by(|a, b| a.1.partial_cmp(&b.1).unwrap().then(a.0.cmp(&b.0)));
+    results
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::config::{ElementType, HnswConfig, SaveOptions, VectorPlacement};
+    use crate::core::hnsw::HNSW;
+    use rand::Rng;
+    use tempfile::NamedTempFile;
+
+    #[test]
+    fn test_flat_matches_sorted_scan() -> Result<(), Box<dyn std::error::Error>> {
+        let mut rng = rand::thread_rng();
+        // More than one chunk so the heap merge is exercised
+        let data: Vec<Vec<f32>> = (0..10_000).map(|_| (0..8).map(|_| rng.gen_range(-1.0f32..1.0)).collect()).collect();
+
+        for metric in [Metric::L2, Metric::InnerProduct, Metric::Cosine] {
+            let distance = MetricDistance::new(metric);
+            let mut flat = FlatIndex::new(8, metric);
+            let mut prepared = Vec::new();
+            for v in &data {
+                flat.insert(v.clone())?;
+                let mut v = v.clone();
+                distance.prepare(&mut v);
+                prepared.push(v);
+            }
+
+            let mut query: Vec<f32> = (0..8).map(|_| rng.gen_range(-1.0f32..1.0)).collect();
+            let results = flat.search(&query, 10);
+            distance.prepare(&mut query);
+            let mut expected: Vec<(u64, f32)> = prepared.iter().enumerate().map(|(id, v)| (id as u64, distance.distance(&query, v))).collect();
+            expected.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap().then(a.0.cmp(&b.0)));
+            expected.truncate(10);
+            assert_eq!(results, expected, "{:?}", metric);
+        }
+
+        let mut flat = FlatIndex::new(2, Metric::L2);
+        assert!(flat.search(&[0.0, 0.0], 5).is_empty());
+        assert!(matches!(flat.insert(vec![1.0]), Err(HnswError::DimensionMismatch { .. })));
+        flat.insert_with_key(7, vec![1.0, 0.0])?;
+        flat.insert_with_key(3, vec![0.0, 1.0])?;
+        assert!(matches!(flat.insert_with_key(7, vec![0.0, 0.0]), Err(HnswError::DuplicateKey(7))));
+        assert_eq!(flat.insert(vec![5.0, 5.0])?, 8);
+        assert!(flat.delete_by_key(7));
+        assert!(!flat.delete_by_key(7));
+        assert_eq!(flat.len(), 2);
+        assert_eq!(flat.get_vector_by_key(8), Some(&[5.0, 5.0][..]));
+        assert_eq!(flat.search(&[0.0, 0.9], 5).iter().map(|r| r.0).collect::<Vec<_>>(), vec![3, 8]);
+        flat.insert_with_key(u64::MAX, vec![1.0, 1.0])?;
+        assert!(matches!(flat.insert(vec![0.0, 0.0]), Err(HnswError::KeysExhausted)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_search_mmap_is_exact() -> Result<(), Box<dyn std::error::Error>> {
+        let mut rng = rand::thread_rng();
+        let config = HnswConfig::builder(8).m(6).ef_construction(50).build()?;
+        let mut index = HNSW::new(config)?;
+        let mut flat = FlatIndex::new(8, Metric::L2);
+        for _ in 0..500 {
+            let v: Vec<f32> = (0..8).map(|_| rng.gen_range(-1.0f32..1.0)).collect();
+            index.insert(v.clone())?;
+            flat.insert(v)?;
+        }
+        index.delete_by_key(4);
+        flat.delete_by_key(4);
+
+        let temp_file = NamedTempFile::new()?;
+        index.save(temp_file.path())?;
+        let mmap_index = MmapIndex::load(temp_file.path())?;
+
+        for _ in 0..10 {
+            let query: Vec<f32> = (0..8).map(|_| rng.gen_range(-1.0f32..1.0)).collect();
+            assert_eq!(FlatIndex::search_mmap(&mmap_index, &query, 10), flat.search(&query, 10));
+        }
+
+        // A 16-bit arena scans the widened vectors
+        let half_file = NamedTempFile::new()?;
+        index.save_with(half_file.path(), &SaveOptions { element_type: ElementType::F16, ..SaveOptions::default() })?;
+        let half = MmapIndex::load(half_file.path())?;
+        let mut widened = FlatIndex::new(8, Metric::L2);
+        let mut buf = Vec::new();
+        for id in 0..half.header().num_elements as usize {
+            widened.insert_with_key(half.get_key(id), half.full_vector_into(id, &mut buf).to_vec())?;
+        }
+        widened.delete_by_key(4);
+        let query: Vec<f32> = (0..8).map(|_| rng.gen_range(-1.0f32..1.0)).collect();
+        assert_eq!(FlatIndex::search_mmap(&half, &query, 10), widened.search(&query, 10));
+
+        // Nothing to scan without the f32 arena
+        let omitted_file = NamedTempFile::new()?;
+        index.save_with(omitted_file.path(), &SaveOptions { vectors: VectorPlacement::Omitted, ..SaveOptions::default() })?;
+        let omitted = MmapIndex::load(omitted_file.path())?;
+        assert!(FlatIndex::search_mmap(&omitted, &[0.0; 8], 10).is_empty());
+
+        Ok(())
+    }
+}
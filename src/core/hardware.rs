@@ -5,6 +5,8 @@
 pub struct CpuFeatures {
     pub avx2: bool,
     pub avx512f: bool,
+    /// AVX-512 VNNI with the AVX-512BW byte loads its int8 kernel needs.
+    pub avx512vnni: bool,
     pub fma: bool,
     pub neon: bool, // For future ARM support
 }
@@ -17,6 +19,10 @@ impl CpuFeatures {
         let avx512f = cfg!(any(target_arch = "x86", target_arch = "x86_64"))
             && is_x86_feature_detected!("avx512f");
 
+        let avx512vnni = avx512f
+            && is_x86_feature_detected!("avx512bw")
+            && is_x86_feature_detected!("avx512vnni");
+
         let fma = cfg!(any(target_arch = "x86", target_arch = "x86_64"))
             && is_x86_feature_detected!("fma");
             
@@ -26,6 +32,7 @@ impl CpuFeatures {
         Self {
             avx2,
             avx512f,
+            avx512vnni,
             fma,
             neon,
         }
@@ -1,297 +1,1021 @@
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use rayon::prelude::*;
+use std::cell::RefCell;
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::ops::Range;
+use std::sync::atomic::{AtomicU32, Ordering as AtomicOrdering};
+use std::sync::Mutex;
+use thiserror::Error;
+use crate::core::config::{ElementType, HnswConfig, Metric, Quantization, SaveOptions, VectorPlacement};
+use crate::core::pq::ProductQuantizer;
+use crate::core::distance::{Distance, MetricDistance};
+use crate::core::quantization::{Quantizer, TrainedQuantizer};
+use crate::core::search::{Candidate, SearchContext, SearchParams};
+use crate::storage::mmap::MmapIndex;
+use crate::simd::binary::sign_bits_into;
+use crate::simd::Kernels;
 
-use rand::Rng;
-use std::cmp::Ordering;
-use std::collections::BinaryHeap;
+#[derive(Error, Debug, PartialEq)]
+pub enum HnswError {
+    #[error("Duplicate key: {0}")]
+    DuplicateKey(u64),
+    #[error("Invalid config: {0}")]
+    InvalidConfig(String),
+    #[error("Dimension mismatch: expected {expected}, got {actual}")]
+    DimensionMismatch { expected: usize, actual: usize },
+    #[error("No automatic keys left: key {} is in use", u64::MAX)]
+    KeysExhausted,
+}
+
+/// Next automatic key once `key` is in use: one past the largest key seen, `None` once
+/// `u64::MAX` is taken (only explicit keys can be inserted from then on).
+pub(crate) fn next_key_after(next_key: Option<u64>, key: u64) -> Option<u64> {
+    next_key.and_then(|next| Some(next.max(key.checked_add(1)?)))
+}
 
-#[derive(Debug, Clone)]
-pub struct Node {
-    pub id: usize,
-    pub vector: Vec<f32>,
-    pub layer_max: usize,
-    pub connections: Vec<Vec<usize>>, // [layer][neighbor_idx]
+/// `count` consecutive automatic keys starting at `next_key`, `None` if they run past `u64::MAX`.
+pub(crate) fn auto_keys(next_key: Option<u64>, count: usize) -> Option<std::ops::RangeInclusive<u64>> {
+    let first = next_key?;
+    let last = first.checked_add((count as u64).saturating_sub(1))?;
+    Some(first..=last)
 }
 
-#[derive(Debug, Clone, PartialEq)]
-struct Candidate {
-    distance: f32,
-    node_id: usize,
+/// Fixed-capacity neighbor lists packed into one `u32` arena.
+/// List `i` is the record `[count, slot_0, .., slot_{capacity - 1}]`.
+/// Slots are atomics so a parallel build can traverse a list while its owner rewrites it;
+/// a reader may then see a mix of old and new neighbors, which is harmless for routing.
+#[derive(Debug)]
+struct LinkArena {
+    capacity: usize,
+    slots: Vec<AtomicU32>,
 }
 
-impl Eq for Candidate {}
+impl LinkArena {
+    fn new(capacity: usize) -> Self {
+        Self { capacity, slots: Vec::new() }
+    }
+
+    fn stride(&self) -> usize {
+        self.capacity + 1
+    }
+
+    fn len(&self) -> usize {
+        self.slots.len() / self.stride()
+    }
+
+    fn reserve(&mut self, lists: usize) {
+        self.slots.reserve(lists * self.stride());
+    }
+
+    /// Append `lists` empty lists, returning the index of the first one.
+    fn grow(&mut self, lists: usize) -> usize {
+        let first = self.len();
+        let new_len = self.slots.len() + lists * self.stride();
+        self.slots.resize_with(new_len, || AtomicU32::new(0));
+        first
+    }
+
+    fn list(&self, index: usize) -> &[AtomicU32] {
+        let stride = self.stride();
+        &self.slots[index * stride..(index + 1) * stride]
+    }
 
-impl PartialOrd for Candidate {
-    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        // Reverse ordering for MinHeap (smallest distance at top)
-        other.distance.partial_cmp(&self.distance)
+    fn memory_usage(&self) -> usize {
+        self.slots.capacity() * std::mem::size_of::<AtomicU32>()
     }
 }
 
-impl Ord for Candidate {
-    fn cmp(&self, other: &Self) -> Ordering {
-        self.partial_cmp(other).unwrap_or(Ordering::Equal)
+fn norm(vector: &[f32]) -> f32 {
+    vector.iter().map(|x| x * x).sum::<f32>().sqrt()
+}
+
+/// Neighbors currently stored in `list`.
+fn read_links(list: &[AtomicU32]) -> impl Iterator<Item = usize> + '_ {
+    let count = (list[0].load(AtomicOrdering::Acquire) as usize).min(list.len() - 1);
+    list[1..=count].iter().map(|slot| slot.load(AtomicOrdering::Relaxed) as usize)
+}
+
+/// Replace the contents of `list`, truncating to its capacity.
+/// Callers hold the owning node's lock (or `&mut HNSW`).
+fn write_links(list: &[AtomicU32], ids: &[usize]) {
+    let count = ids.len().min(list.len() - 1);
+    for (slot, &id) in list[1..].iter().zip(&ids[..count]) {
+        slot.store(id as u32, AtomicOrdering::Relaxed);
     }
+    list[0].store(count as u32, AtomicOrdering::Release);
+}
+
+// Build and search scratch, one per (rayon) thread.
+thread_local! {
+    static SEARCH_CONTEXT: RefCell<SearchContext> = const { RefCell::new(SearchContext::new()) };
 }
 
-pub struct HNSW {
-    pub layers: usize,
-    pub ef_construction: usize,
-    pub m: usize,
-    pub m0: usize,
-    pub nodes: Vec<Node>,
+/// How a node picks its links from the candidate list found during insertion.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum NeighborSelection {
+    /// Keep the `m` closest candidates.
+    #[default]
+    Simple,
+    /// "Select neighbors heuristic" (Malkov & Yashunin, Algorithm 4):
+    /// a candidate is kept only if it is closer to the base node than to every neighbor
+    /// already selected, which spreads links across directions instead of one cluster.
+    Heuristic {
+        /// Also consider the neighbors of each candidate (new node only, not when pruning).
+        extend_candidates: bool,
+        /// Top up with the closest rejected candidates until `m` links are filled.
+        keep_pruned_connections: bool,
+    },
+}
+
+/// In-memory HNSW graph.
+/// Everything lives in a handful of flat arrays indexed by node ID (vector slab, fixed-size
+/// `u32` link lists, per-node level/key/lock), so build memory tracks the saved file size
+/// instead of paying for a few heap allocations per node.
+/// Generic over the `Distance`; `HNSW::new` uses the built-in `config.metric`.
+pub struct HNSW<D = MetricDistance> {
+    pub config: HnswConfig,
     pub entry_point: Option<usize>,
+    distance: D,
+    /// Node `id` owns `vectors[id * dimension..(id + 1) * dimension]`.
+    vectors: Vec<f32>,
+    /// Top layer of each node.
+    levels: Vec<u8>,
+    /// External key of each node (defaults to the insertion position).
+    keys: Vec<u64>,
+    /// Tombstones, bit `id % 64` of word `id / 64` (same layout as the file bitmap).
+    /// A tombstoned node is hidden from results but still traversed.
+    deleted: Vec<u64>,
+    /// Layer-0 lists (capacity `m0`), list index = node ID.
+    layer0: LinkArena,
+    /// Layer >= 1 lists (capacity `m`): node `id` owns `levels[id]` consecutive lists from `upper_start[id]`.
+    upper: LinkArena,
+    upper_start: Vec<u32>,
+    /// Serializes writers of a node's lists on all layers; readers go through the atomics.
+    locks: Vec<Mutex<()>>,
+    /// External key -> node ID, live nodes only.
+    key_to_id: HashMap<u64, usize>,
+    /// Key handed out by `insert` / `insert_parallel` (one past the largest key seen),
+    /// `None` once `u64::MAX` is in use.
+    next_key: Option<u64>,
+    /// Level generator, seeded from `config.seed` when set.
+    rng: StdRng,
 }
 
 impl HNSW {
-    pub fn new(layers: usize, ef_construction: usize, m: usize, m0: usize) -> Self {
-        Self {
-            layers,
-            ef_construction,
-            m,
-            m0,
-            nodes: Vec::new(),
+    /// The distance kernel for `config.metric` is resolved here, once (`Kernels::detect`).
+    /// Fails if `config` does not pass `HnswConfig::validate`.
+    pub fn new(config: HnswConfig) -> Result<Self, HnswError> {
+        Self::with_distance(config, MetricDistance::new(config.metric))
+    }
+
+    /// `new` with the distance kernel taken from `kernels`, e.g. `Kernels::scalar()` in tests.
+    pub fn with_kernels(config: HnswConfig, kernels: &Kernels) -> Result<Self, HnswError> {
+        Self::with_distance(config, MetricDistance::with_kernels(config.metric, kernels))
+    }
+
+    /// Reopen a saved index for appends: copies the full-precision arena, connections, keys,
+    /// tombstones, entry point and build parameters out of the mapped file.
+    /// Vectors come back exactly as stored (unit length for cosine), so the rebuilt graph
+    /// keeps the geometry the file is searched with.
+    /// Files built with a custom distance need `from_mmap_with_distance`.
+    pub fn from_mmap<E: Distance>(index: &MmapIndex<E>) -> Result<Self, HnswError> {
+        let metric = index.metric().ok_or_else(|| {
+            HnswError::InvalidConfig("index was built with a custom distance, use from_mmap_with_distance".to_string())
+        })?;
+        Self::from_mmap_with_distance(index, MetricDistance::new(metric))
+    }
+}
+
+impl<D: Distance> HNSW<D> {
+    /// Index searched with `distance`. If it is one of the built-in metrics, `config.metric`
+    /// is set to match; otherwise `config.metric` is unused.
+    /// Fails if `config` does not pass `HnswConfig::validate`.
+    pub fn with_distance(mut config: HnswConfig, distance: D) -> Result<Self, HnswError> {
+        config.validate()?;
+        if let Some(metric) = distance.metric() {
+            config.metric = metric;
+        }
+        let rng = match config.seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+        Ok(Self {
             entry_point: None,
+            vectors: Vec::new(),
+            levels: Vec::new(),
+            keys: Vec::new(),
+            deleted: Vec::new(),
+            layer0: LinkArena::new(config.m0),
+            upper: LinkArena::new(config.m),
+            upper_start: Vec::new(),
+            locks: Vec::new(),
+            key_to_id: HashMap::new(),
+            next_key: Some(0),
+            rng,
+            distance,
+            config,
+        })
+    }
+
+    /// `HNSW::from_mmap` with a caller-supplied distance, which must be the one the file was built with.
+    pub fn from_mmap_with_distance<E: Distance>(index: &MmapIndex<E>, distance: D) -> Result<Self, HnswError> {
+        use crate::storage::format::{BUILD_FLAG_EXTEND_CANDIDATES, BUILD_FLAG_HEURISTIC, BUILD_FLAG_KEEP_PRUNED, BUILD_FLAG_SEEDED};
+
+        if !index.has_full_vectors() {
+            return Err(HnswError::InvalidConfig("Index has no full-precision vectors to rebuild from".to_string()));
+        }
+        let header = index.header();
+        let num_nodes = header.num_elements as usize;
+        let on_disk_nodes = index.nodes();
+
+        // Files that predate the persisted config keep at least the depth they already have.
+        let max_layer = header.max_layer as usize;
+        let mut builder = HnswConfig::builder(header.dimension as usize)
+            .m(header.m_max as usize)
+            .m0(header.m_max_0 as usize)
+            .ef_construction(header.ef_construction as usize)
+            .max_layers(if header.max_layers == 0 { (max_layer + 1).max(16) } else { header.max_layers as usize });
+        if header.level_multiplier > 0.0 {
+            builder = builder.level_multiplier(header.level_multiplier);
+        }
+        if header.build_flags & BUILD_FLAG_SEEDED != 0 {
+            builder = builder.seed(header.seed);
+        }
+        if header.build_flags & BUILD_FLAG_HEURISTIC != 0 {
+            builder = builder.neighbor_selection(NeighborSelection::Heuristic {
+                extend_candidates: header.build_flags & BUILD_FLAG_EXTEND_CANDIDATES != 0,
+                keep_pruned_connections: header.build_flags & BUILD_FLAG_KEEP_PRUNED != 0,
+            });
+        }
+        let mut hnsw = Self::with_distance(builder.metric(index.metric().unwrap_or_default()).build()?, distance)?;
+        hnsw.reserve(num_nodes);
+
+        let mut buf = Vec::new();
+        for (id, on_disk_node) in on_disk_nodes.iter().enumerate() {
+            let layer_count = on_disk_node.layer_count as usize;
+            if !(1..=hnsw.config.max_layers).contains(&layer_count) {
+                return Err(HnswError::InvalidConfig(format!(
+                    "Node {} has {} layers, expected 1..={}", id, layer_count, hnsw.config.max_layers
+                )));
+            }
+            let layer_max = layer_count - 1;
+            let key = index.get_key(id);
+            hnsw.push_node(key, index.full_vector_into(id, &mut buf), layer_max);
+
+            for level in 0..=layer_max {
+                let neighbors: Vec<usize> = index.neighbors(id, level).iter().map(|&n| n as usize).collect();
+                write_links(hnsw.links(id, level), &neighbors);
+            }
+
+            if index.is_deleted(id) {
+                hnsw.deleted[id / 64] |= 1 << (id % 64);
+                hnsw.next_key = next_key_after(hnsw.next_key, key);
+            } else {
+                hnsw.register_key(key, id);
+            }
+        }
+
+        if num_nodes > 0 {
+            hnsw.entry_point = Some(header.entry_point_id as usize);
+        }
+
+        Ok(hnsw)
+    }
+
+    /// Number of nodes, tombstones included.
+    pub fn len(&self) -> usize {
+        self.levels.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.levels.is_empty()
+    }
+
+    pub fn vector(&self, id: usize) -> &[f32] {
+        let dim = self.config.dimension;
+        &self.vectors[id * dim..(id + 1) * dim]
+    }
+
+    /// Top layer of node `id`.
+    pub fn level(&self, id: usize) -> usize {
+        self.levels[id] as usize
+    }
+
+    pub fn key(&self, id: usize) -> u64 {
+        self.keys[id]
+    }
+
+    /// Copy of node `id`'s links on `level` (empty above its top layer).
+    pub fn neighbors(&self, id: usize, level: usize) -> Vec<usize> {
+        if level > self.level(id) {
+            return Vec::new();
+        }
+        read_links(self.links(id, level)).collect()
+    }
+
+    /// Heap bytes held by the graph, counted by capacity: vectors, links, per-node bookkeeping
+    /// and an estimate for the key map.
+    pub fn memory_usage(&self) -> usize {
+        use std::mem::size_of;
+
+        self.vectors.capacity() * size_of::<f32>()
+            + self.levels.capacity()
+            + self.keys.capacity() * size_of::<u64>()
+            + self.deleted.capacity() * size_of::<u64>()
+            + self.layer0.memory_usage()
+            + self.upper.memory_usage()
+            + self.upper_start.capacity() * size_of::<u32>()
+            + self.locks.capacity() * size_of::<Mutex<()>>()
+            // One entry plus one control byte per slot.
+            + self.key_to_id.capacity() * (size_of::<(u64, usize)>() + 1)
+    }
+
+    fn links(&self, id: usize, level: usize) -> &[AtomicU32] {
+        if level == 0 {
+            self.layer0.list(id)
+        } else {
+            self.upper.list(self.upper_start[id] as usize + level - 1)
         }
     }
 
-    pub fn insert(&mut self, vector: Vec<f32>) -> usize {
-        use crate::simd::get_euclidean_distance;
-        let dist_func = get_euclidean_distance();
+    fn reserve(&mut self, additional: usize) {
+        self.vectors.reserve(additional * self.config.dimension);
+        self.levels.reserve(additional);
+        self.keys.reserve(additional);
+        self.deleted.reserve(additional.div_ceil(64));
+        self.layer0.reserve(additional);
+        self.upper_start.reserve(additional);
+        self.locks.reserve(additional);
+        self.key_to_id.reserve(additional);
+    }
+
+    /// Append an unlinked node with empty lists. The caller registers its key.
+    fn push_node(&mut self, key: u64, vector: &[f32], layer_max: usize) -> usize {
+        let id = self.len();
+        self.vectors.extend_from_slice(vector);
+        self.levels.push(layer_max as u8);
+        self.keys.push(key);
+        if id.is_multiple_of(64) {
+            self.deleted.push(0);
+        }
+        self.layer0.grow(1);
+        let upper_start = if layer_max > 0 { self.upper.grow(layer_max) } else { 0 };
+        self.upper_start.push(u32::try_from(upper_start).expect("upper layer arena exceeds u32::MAX lists"));
+        self.locks.push(Mutex::new(()));
+        id
+    }
+
+    /// Insert with the next unused key (equal to the node ID unless explicit keys were used).
+    /// Fails with `KeysExhausted` once key `u64::MAX` is in use.
+    pub fn insert(&mut self, vector: Vec<f32>) -> Result<usize, HnswError> {
+        let key = self.next_key.ok_or(HnswError::KeysExhausted)?;
+        self.insert_with_key(key, vector)
+    }
+
+    /// Insert under a caller-supplied key. Search results report this key.
+    pub fn insert_with_key(&mut self, key: u64, mut vector: Vec<f32>) -> Result<usize, HnswError> {
+        self.check_dimension(&vector)?;
+        if self.key_to_id.contains_key(&key) {
+            return Err(HnswError::DuplicateKey(key));
+        }
 
-        let id = self.nodes.len();
         let layer_max = self.random_level();
-        
-        let node = Node {
-            id,
-            vector: vector.clone(),
-            layer_max,
-            connections: vec![Vec::new(); layer_max + 1],
-        };
-        
+        self.distance.prepare(&mut vector);
+
         // Push immediately to allow neighbor pruning logic to access this node
-        self.nodes.push(node);
+        let id = self.push_node(key, &vector, layer_max);
+        self.register_key(key, id);
 
-        if let Some(entry_point) = self.entry_point {
-            let mut curr_obj = entry_point;
-            let mut _curr_dist = unsafe { dist_func(&vector, &self.nodes[curr_obj].vector) };
-
-            let max_layer_global = self.nodes[entry_point].layer_max;
-            
-            if layer_max < max_layer_global {
-                for level in (layer_max + 1..=max_layer_global).rev() {
-                    let (next_obj, next_dist) = self.search_layer(&vector, curr_obj, 1, level, dist_func)[0];
-                    curr_obj = next_obj;
-                    _curr_dist = next_dist;
-                }
-            }
+        let entry = Mutex::new(self.entry_point);
+        self.link(id, &entry);
+        self.entry_point = entry.into_inner().unwrap();
 
-            let start_layer = std::cmp::min(layer_max, max_layer_global);
-            
-            for level in (0..=start_layer).rev() {
-                let candidates = self.search_layer(&vector, curr_obj, self.ef_construction, level, dist_func);
-                
-                let m_level = if level == 0 { self.m0 } else { self.m };
-                let neighbors: Vec<usize> = candidates.iter().take(m_level).map(|(id, _)| *id).collect();
-
-                // Bidirectional connection
-                self.nodes[id].connections[level] = neighbors.clone();
-                
-                for &neighbor_id in &neighbors {
-                    self.nodes[neighbor_id].connections[level].push(id);
-                    let max_links = if level == 0 { self.m0 } else { self.m };
-                    self.prune_connections(neighbor_id, level, max_links, dist_func);
-                }
-                
-                curr_obj = candidates[0].0; 
+        Ok(id)
+    }
+
+    /// Multi-threaded bulk insert.
+    /// Levels are drawn up front, then every node is linked concurrently on the rayon pool.
+    /// Link order follows thread scheduling, so the graph is not reproducible even with a seed;
+    /// use `insert` when the saved file must be byte-identical across runs.
+    /// Returns the range of assigned node IDs.
+    pub fn insert_parallel(&mut self, vectors: Vec<Vec<f32>>) -> Result<Range<usize>, HnswError> {
+        let keys = auto_keys(self.next_key, vectors.len()).ok_or(HnswError::KeysExhausted)?;
+        let items = vectors.into_iter().zip(keys).map(|(v, key)| (key, v)).collect();
+        self.insert_parallel_with_keys(items)
+    }
+
+    /// Multi-threaded bulk insert of `(key, vector)` pairs.
+    /// All keys and lengths are checked before anything is inserted.
+    pub fn insert_parallel_with_keys(&mut self, items: Vec<(u64, Vec<f32>)>) -> Result<Range<usize>, HnswError> {
+        let mut batch_keys = std::collections::HashSet::with_capacity(items.len());
+        for (key, vector) in &items {
+            self.check_dimension(vector)?;
+            if self.key_to_id.contains_key(key) || !batch_keys.insert(*key) {
+                return Err(HnswError::DuplicateKey(*key));
             }
-            
-            if layer_max > max_layer_global {
-                self.entry_point = Some(id);
+        }
+        drop(batch_keys);
+
+        let start = self.len();
+        self.reserve(items.len());
+        // Each input vector is freed as soon as it is copied into the slab.
+        for (key, mut vector) in items {
+            let layer_max = self.random_level();
+            self.distance.prepare(&mut vector);
+            let id = self.push_node(key, &vector, layer_max);
+            self.register_key(key, id);
+        }
+        let end = self.len();
+
+        // Nodes are only reachable once a linked neighbor points at them,
+        // so workers never traverse a node that is still being linked.
+        let entry = Mutex::new(self.entry_point);
+        let this = &*self;
+        (start..end).into_par_iter().for_each(|id| this.link(id, &entry));
+        self.entry_point = entry.into_inner().unwrap();
+
+        Ok(start..end)
+    }
+
+    fn check_dimension(&self, vector: &[f32]) -> Result<(), HnswError> {
+        if vector.len() != self.config.dimension {
+            return Err(HnswError::DimensionMismatch { expected: self.config.dimension, actual: vector.len() });
+        }
+        Ok(())
+    }
+
+    fn register_key(&mut self, key: u64, id: usize) {
+        self.key_to_id.insert(key, id);
+        self.next_key = next_key_after(self.next_key, key);
+    }
+
+    /// Node ID of a live key.
+    pub fn id_for_key(&self, key: u64) -> Option<usize> {
+        self.key_to_id.get(&key).copied()
+    }
+
+    /// The stored vector (unit length for cosine indexes).
+    pub fn get_vector_by_key(&self, key: u64) -> Option<&[f32]> {
+        self.id_for_key(key).map(|id| self.vector(id))
+    }
+
+    /// Connect an already-pushed node into the graph.
+    /// Takes `&self`: all graph mutation goes through the per-node locks.
+    /// At most one node lock is held at a time, so concurrent links cannot deadlock.
+    fn link(&self, id: usize, entry: &Mutex<Option<usize>>) {
+        let vector = self.vector(id);
+        let layer_max = self.level(id);
+
+        let guard = entry.lock().unwrap();
+        let entry_point = match *guard {
+            Some(entry_point) => entry_point,
+            None => {
+                let mut guard = guard;
+                *guard = Some(id);
+                return;
             }
+        };
+        let max_layer_global = self.level(entry_point);
+
+        // A node that will become the new entry point keeps the lock for its whole insertion,
+        // so no other insert starts from a top layer that is not linked yet (rare: O(log N) nodes).
+        let promote = if layer_max > max_layer_global {
+            Some(guard)
         } else {
-            self.entry_point = Some(id);
+            drop(guard);
+            None
+        };
+
+        let mut curr_obj = entry_point;
+
+        if layer_max < max_layer_global {
+            for level in (layer_max + 1..=max_layer_global).rev() {
+                curr_obj = self.greedy_closest(vector, curr_obj, level);
+            }
         }
 
-        id
+        let start_layer = std::cmp::min(layer_max, max_layer_global);
+
+        for level in (0..=start_layer).rev() {
+            let candidates = self.search_layer(vector, curr_obj, self.config.ef_construction, level, false);
+
+            let closest = candidates[0].0;
+
+            let m_level = if level == 0 { self.config.m0 } else { self.config.m };
+            let neighbors = self.select_neighbors(id, vector, candidates, m_level, level, true);
+
+            // Bidirectional connection
+            {
+                let _lock = self.locks[id].lock().unwrap();
+                write_links(self.links(id, level), &neighbors);
+            }
+
+            for &neighbor_id in &neighbors {
+                self.add_link(neighbor_id, id, level, m_level);
+            }
+
+            curr_obj = closest;
+        }
+
+        if let Some(mut guard) = promote {
+            *guard = Some(id);
+        }
     }
 
-    pub fn search(&self, query: &[f32], k: usize) -> Vec<(usize, f32)> {
-        use crate::simd::get_euclidean_distance;
-        let dist_func = get_euclidean_distance();
+    /// Search with the build beam width (`ef_construction`) as `ef`.
+    /// Returns `(key, distance)` pairs, closest first.
+    pub fn search(&self, query: &[f32], k: usize) -> Vec<(u64, f32)> {
+        self.search_with_params(query, k, SearchParams::new(self.config.ef_construction))
+    }
+
+    /// Search with query-time parameters, trading recall for speed via `params.ef`.
+    /// Returns `(key, distance)` pairs, closest first, with distances as defined by `config.metric`.
+    pub fn search_with_params(&self, query: &[f32], k: usize, params: SearchParams) -> Vec<(u64, f32)> {
+        if k == 0 {
+            return Vec::new();
+        }
+        // `prepare` is a no-op for L2 and inner product, so only cosine and custom distances copy
+        let prepared;
+        let query = match self.distance.metric() {
+            Some(Metric::L2 | Metric::InnerProduct) => query,
+            _ => {
+                let mut owned = query.to_vec();
+                self.distance.prepare(&mut owned);
+                prepared = owned;
+                &prepared
+            }
+        };
 
         if let Some(entry_point) = self.entry_point {
             let mut curr_obj = entry_point;
-            let max_layer = self.nodes[entry_point].layer_max;
+            let max_layer = self.level(entry_point);
 
             // 1. Zoom down to layer 0
             for level in (1..=max_layer).rev() {
-                let (next_obj, _) = self.search_layer(query, curr_obj, 1, level, dist_func)[0];
-                curr_obj = next_obj;
+                curr_obj = self.greedy_closest(query, curr_obj, level);
             }
 
-            // 2. Search layer 0
-            let candidates = self.search_layer(query, curr_obj, k.max(self.ef_construction), 0, dist_func);
-            candidates.into_iter().take(k).collect()
+            // 2. Search layer 0 (tombstones are traversed but never returned)
+            let candidates = self.search_layer(query, curr_obj, k.max(params.ef), 0, true);
+            candidates.into_iter().take(k).map(|(id, dist)| (self.keys[id], dist)).collect()
         } else {
             Vec::new()
         }
     }
 
-    fn search_layer(&self, query: &[f32], entry_point: usize, ef: usize, level: usize, dist_func: crate::simd::DistanceFunc) -> Vec<(usize, f32)> {
-        let mut visited = std::collections::HashSet::new();
-        let mut candidates = BinaryHeap::new(); // Min-heap for candidates to explore
+    /// Greedy walk on `level` (the ef = 1 search used above the target layers): move to the
+    /// closest neighbor until no neighbor improves on the current node.
+    fn greedy_closest(&self, query: &[f32], entry_point: usize, level: usize) -> usize {
+        let mut curr_obj = entry_point;
+        let mut curr_dist = self.distance.distance(query, self.vector(curr_obj));
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for neighbor_id in read_links(self.links(curr_obj, level)) {
+                let dist = self.distance.distance(query, self.vector(neighbor_id));
+                if dist < curr_dist {
+                    curr_dist = dist;
+                    curr_obj = neighbor_id;
+                    changed = true;
+                }
+            }
+        }
+        curr_obj
+    }
 
+    /// Beam search on `level` from `entry_point`, returning up to `ef` `(id, distance)` pairs, closest first.
+    /// Uses the calling thread's `SearchContext`; the returned `Vec` is the only allocation.
+    fn search_layer(&self, query: &[f32], entry_point: usize, ef: usize, level: usize, skip_deleted: bool) -> Vec<(usize, f32)> {
+        SEARCH_CONTEXT.with(|ctx| {
+            let mut ctx = ctx.borrow_mut();
+            ctx.reset(self.len());
+            let SearchContext { visited, epoch, candidates, w, .. } = &mut *ctx;
+            let epoch = *epoch;
 
-        // We want a MaxHeap for 'results' to easily pop the furthest element when size > ef
-        // Rust's BinaryHeap is a MaxHeap. So we store (distance, id).
-        // For 'candidates', we want a MinHeap to explore closest first. So we store Reverse(distance).
+            let dist = self.distance.distance(query, self.vector(entry_point));
+            visited[entry_point] = epoch;
+            candidates.push(Reverse(Candidate { distance: dist, node_id: entry_point }));
+            if !(skip_deleted && self.is_deleted(entry_point)) {
+                w.push(Candidate { distance: dist, node_id: entry_point });
+            }
 
-        use std::cmp::Reverse;
-        
-        let dist = unsafe { dist_func(query, &self.nodes[entry_point].vector) };
-        visited.insert(entry_point);
-        candidates.push(Reverse(Candidate { distance: dist, node_id: entry_point }));
-        
-        // We use a simple vector for results and sort it, or a bounded heap. 
-        // For simplicity in this PoC, let's use a sorted vector or just a large heap.
-        // Let's stick to the standard HNSW logic:
-        // W: set of nearest elements found so far (dynamic list)
-        
-        let mut w = vec![Candidate { distance: dist, node_id: entry_point }];
-        
-        while let Some(Reverse(c)) = candidates.pop() {
-            let curr_dist = c.distance;
-            let curr_node = c.node_id;
+            while let Some(Reverse(c)) = candidates.pop() {
+                // If closest candidate is further than the furthest result in W, stop
+                if w.len() >= ef && w.peek().is_some_and(|worst| c.distance > worst.distance) {
+                    break;
+                }
 
-            // If closest candidate is further than the furthest result in W, stop
-            if curr_dist > w.last().unwrap().distance && w.len() >= ef {
-                break;
-            }
+                for neighbor_id in read_links(self.links(c.node_id, level)) {
+                    if std::mem::replace(&mut visited[neighbor_id], epoch) == epoch {
+                        continue;
+                    }
+                    let neighbor_dist = self.distance.distance(query, self.vector(neighbor_id));
 
-            for &neighbor_id in &self.nodes[curr_node].connections[level] {
-                if !visited.contains(&neighbor_id) {
-                    visited.insert(neighbor_id);
-                    let neighbor_dist = unsafe { dist_func(query, &self.nodes[neighbor_id].vector) };
-                    
-                    if w.len() < ef || neighbor_dist < w.last().unwrap().distance {
+                    // `w` can be empty with `ef == 0` (k = 0 from a tombstoned entry point)
+                    if w.len() < ef || w.peek().is_some_and(|worst| neighbor_dist < worst.distance) {
                         let candidate = Candidate { distance: neighbor_dist, node_id: neighbor_id };
                         candidates.push(Reverse(candidate.clone()));
+                        if skip_deleted && self.is_deleted(neighbor_id) {
+                            continue;
+                        }
                         w.push(candidate);
-                        w.sort_by(|a, b| a.distance.partial_cmp(&b.distance).unwrap());
                         if w.len() > ef {
                             w.pop();
                         }
                     }
                 }
             }
-        }
 
-        w.into_iter().map(|c| (c.node_id, c.distance)).collect()
+            let mut results: Vec<(usize, f32)> = w.drain().map(|c| (c.node_id, c.distance)).collect();
+            results.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap());
+            results
+        })
     }
 
-    fn prune_connections(&mut self, node_id: usize, level: usize, max_links: usize, dist_func: crate::simd::DistanceFunc) {
-        // Clone connections to avoid borrowing `self` mutably (via nodes -> connections) 
-        // while we need to read `self` (via nodes -> vector) later.
-        let connection_ids = self.nodes[node_id].connections[level].clone();
-        
-        if connection_ids.len() <= max_links {
+    /// Add the reverse edge `node_id -> new_id`, re-selecting `max_links` links when the list is full.
+    fn add_link(&self, node_id: usize, new_id: usize, level: usize, max_links: usize) {
+        let _lock = self.locks[node_id].lock().unwrap();
+        let list = self.links(node_id, level);
+
+        let count = list[0].load(AtomicOrdering::Relaxed) as usize;
+        if count < max_links {
+            list[count + 1].store(new_id as u32, AtomicOrdering::Relaxed);
+            list[0].store(count as u32 + 1, AtomicOrdering::Release);
             return;
         }
 
-        let node_vector = self.nodes[node_id].vector.clone();
-        
+        let mut connection_ids: Vec<usize> = read_links(list).collect();
+        connection_ids.push(new_id);
+        self.prune_connections(node_id, &mut connection_ids, level, max_links);
+        write_links(list, &connection_ids);
+    }
+
+    fn prune_connections(&self, node_id: usize, connection_ids: &mut Vec<usize>, level: usize, max_links: usize) {
+        let node_vector = self.vector(node_id);
+
         // Calculate distances
-        let mut candidates: Vec<(usize, f32)> = connection_ids.iter().map(|&n_id| {
-            let dist = unsafe { dist_func(&node_vector, &self.nodes[n_id].vector) };
+        let candidates: Vec<(usize, f32)> = connection_ids.iter().map(|&n_id| {
+            let dist = self.distance.distance(node_vector, self.vector(n_id));
             (n_id, dist)
         }).collect();
 
-        // Sort by distance (ascending)
+        // Pruning only ever reshuffles the node's own list, never extends into its neighbors'.
+        *connection_ids = self.select_neighbors(node_id, node_vector, candidates, max_links, level, false);
+    }
+
+    /// Pick up to `m` links for `base_id` out of `candidates` (node, distance to base),
+    /// according to `config.neighbor_selection`. Never returns `base_id` itself or a deleted node.
+    fn select_neighbors(&self, base_id: usize, base: &[f32], mut candidates: Vec<(usize, f32)>, m: usize, level: usize, allow_extend: bool) -> Vec<usize> {
+        candidates.retain(|(n, _)| *n != base_id && !self.is_deleted(*n));
+
+        let (extend_candidates, keep_pruned_connections) = match self.config.neighbor_selection {
+            NeighborSelection::Simple => {
+                candidates.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap());
+                return candidates.into_iter().take(m).map(|(n, _)| n).collect();
+            }
+            NeighborSelection::Heuristic { extend_candidates, keep_pruned_connections } => {
+                (extend_candidates && allow_extend, keep_pruned_connections)
+            }
+        };
+
+        if extend_candidates {
+            let mut seen: std::collections::HashSet<usize> = candidates.iter().map(|(n, _)| *n).collect();
+            seen.insert(base_id);
+            let mut extended = Vec::new();
+            for &(c, _) in &candidates {
+                for e in read_links(self.links(c, level)) {
+                    if seen.insert(e) && !self.is_deleted(e) {
+                        extended.push((e, self.distance.distance(base, self.vector(e))));
+                    }
+                }
+            }
+            candidates.extend(extended);
+        }
+
         candidates.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap());
 
-        // Keep top max_links
-        self.nodes[node_id].connections[level] = candidates.into_iter().take(max_links).map(|(id, _)| id).collect();
+        let mut selected: Vec<usize> = Vec::with_capacity(m);
+        let mut pruned = Vec::new();
+        for (c, dist_to_base) in candidates {
+            if selected.len() >= m {
+                break;
+            }
+            let c_vector = self.vector(c);
+            let diverse = selected.iter().all(|&s| self.distance.distance(c_vector, self.vector(s)) > dist_to_base);
+            if diverse {
+                selected.push(c);
+            } else {
+                pruned.push(c);
+            }
+        }
+
+        if keep_pruned_connections {
+            let missing = m.saturating_sub(selected.len());
+            selected.extend(pruned.into_iter().take(missing));
+        }
+
+        selected
+    }
+
+    /// Tombstone a node.
+    /// It is excluded from `search` results (and from `MmapIndex` results once saved) but keeps
+    /// its own links, so searches can still route through it. Every node that links to it, found by
+    /// a scan since links are directed, is re-linked among the deleted node's other neighbors, so
+    /// no edge into a tombstone survives and connectivity holds up over repeated deletes.
+    /// The scan is O(N) per layer of the deleted node: use `delete_many` for batches.
+    /// Returns `false` if `id` does not exist or is already deleted.
+    pub fn delete(&mut self, id: usize) -> bool {
+        self.delete_many(&[id]) == 1
+    }
+
+    pub fn delete_by_key(&mut self, key: u64) -> bool {
+        match self.id_for_key(key) {
+            Some(id) => self.delete(id),
+            None => false,
+        }
+    }
+
+    /// `delete` for a batch: one in-link scan per layer covers every node in `ids`, so deleting
+    /// D nodes costs O(N) per layer instead of O(D * N). Unknown and already deleted IDs are skipped.
+    /// Returns how many nodes were deleted.
+    pub fn delete_many(&mut self, ids: &[usize]) -> usize {
+        let mut batch: Vec<usize> = ids.iter().copied().filter(|&id| id < self.len() && !self.is_deleted(id)).collect();
+        batch.sort_unstable();
+        batch.dedup();
+        for &id in &batch {
+            self.deleted[id / 64] |= 1 << (id % 64);
+            // The key becomes free again; the tombstone keeps it only for the record.
+            self.key_to_id.remove(&self.keys[id]);
+        }
+
+        let Some(top) = batch.iter().map(|&id| self.level(id)).max() else {
+            return 0;
+        };
+        for level in 0..=top {
+            let max_links = if level == 0 { self.config.m0 } else { self.config.m };
+            let in_batch = |id: usize| batch.binary_search(&id).is_ok();
+            // Taken before any list is rewritten: a batch member can be both an orphan source and an in-neighbor
+            let orphans: HashMap<usize, Vec<usize>> = batch.iter().map(|&id| (id, self.neighbors(id, level))).collect();
+
+            // Links are directed, so in-links can come from anywhere: one scan finds them for the whole batch
+            let this = &*self;
+            let in_links: Vec<usize> = (0..this.len())
+                .into_par_iter()
+                .filter(|&n| this.level(n) >= level && read_links(this.links(n, level)).any(in_batch))
+                .collect();
+
+            for n in in_links {
+                let current = self.neighbors(n, level);
+                let n_vector = self.vector(n);
+                // Deleted neighbors are replaced by their own neighbors, through chains of deleted nodes
+                let mut seen: std::collections::HashSet<usize> = current.iter().copied().collect();
+                let mut pending: Vec<usize> = current.iter().copied().filter(|&c| in_batch(c)).collect();
+                let mut candidates = current;
+                while let Some(dead) = pending.pop() {
+                    for &c in &orphans[&dead] {
+                        if seen.insert(c) {
+                            candidates.push(c);
+                            if in_batch(c) {
+                                pending.push(c);
+                            }
+                        }
+                    }
+                }
+                let candidates = candidates.into_iter().map(|c| (c, self.distance.distance(n_vector, self.vector(c)))).collect();
+
+                // `select_neighbors` drops `n` itself and every tombstone
+                let relinked = self.select_neighbors(n, n_vector, candidates, max_links, level, false);
+                write_links(self.links(n, level), &relinked);
+            }
+        }
+
+        batch.len()
+    }
+
+    /// `delete_many` by key. Returns how many keys were deleted.
+    pub fn delete_many_by_key(&mut self, keys: &[u64]) -> usize {
+        let ids: Vec<usize> = keys.iter().filter_map(|&key| self.id_for_key(key)).collect();
+        self.delete_many(&ids)
+    }
+
+    pub fn is_deleted(&self, id: usize) -> bool {
+        id < self.len() && self.deleted[id / 64] & (1 << (id % 64)) != 0
     }
 
     pub fn save(&self, path: &std::path::Path) -> std::io::Result<()> {
+        self.save_with(path, &SaveOptions::default())
+    }
+
+    /// `save` with a choice of stage-1 representation. `Quantization::Product` trains the
+    /// codebooks on the stored vectors (seeded by `config.seed`) and writes codes instead of the u8 arena.
+    /// `Quantization::TrainedScalar8` learns the u8 ranges from the stored vectors and writes them too.
+    /// `VectorPlacement::Sidecar` also writes `vectors_sidecar_path(path)`.
+    pub fn save_with(&self, path: &std::path::Path, options: &SaveOptions) -> std::io::Result<()> {
         use std::io::{Write, Seek, SeekFrom};
-        use crate::storage::format::{Header, OnDiskNode};
+        use crate::storage::format::{Header, KeyEntry, OnDiskNode};
         use bytemuck::bytes_of;
         use crc32fast::Hasher;
-        use crate::core::quantization::Quantizer;
+        use crate::storage::format::{FORMAT_VERSION, METRIC_CUSTOM, QUANTIZATION_PRODUCT, QUANTIZATION_SCALAR4, QUANTIZATION_SCALAR8, QUANTIZATION_SCALAR8_TRAINED};
+        use crate::storage::format::{ELEMENT_BF16, ELEMENT_F16, ELEMENT_F32, VECTORS_INLINE, VECTORS_OMITTED, VECTORS_SIDECAR};
+        use crate::storage::format::{vectors_sidecar_path, VectorsHeader, VECTORS_FORMAT_VERSION, VECTORS_MAGIC};
+
+        let num_nodes = self.len();
+        let dim = self.config.dimension;
+        if options.vectors == VectorPlacement::Omitted && self.distance.metric().is_none() {
+            // The stage-1 arena cannot rank for a custom distance
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "a custom-distance index needs its full-precision vectors"));
+        }
+        let pq = match options.quantization {
+            Quantization::Scalar8 | Quantization::TrainedScalar8 | Quantization::Scalar4 => None,
+            Quantization::Product { subspaces } => Some(
+                ProductQuantizer::train(dim, subspaces, &self.vectors[..num_nodes * dim], self.config.seed)
+                    .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?,
+            ),
+        };
 
         let mut file = std::fs::File::create(path)?;
-        let num_nodes = self.nodes.len();
-        let dim = if num_nodes > 0 { self.nodes[0].vector.len() } else { 0 };
 
         // 1. Calculate sizes and offsets
         let header_size = 256;
         let nodes_size = num_nodes * std::mem::size_of::<OnDiskNode>();
-        
+
         let nodes_end = header_size + nodes_size;
-        
+
         // Alignment Padding for Quantized Vectors (u8)
-        let pad1 = if nodes_end % 32 != 0 { 32 - (nodes_end % 32) } else { 0 };
+        let pad1 = if !nodes_end.is_multiple_of(32) { 32 - (nodes_end % 32) } else { 0 };
         let quantized_vectors_offset = nodes_end + pad1;
-        let quantized_vectors_size = num_nodes * dim * 1; // u8
-        
+        let code_size = match (&pq, options.quantization) {
+            (Some(pq), _) => pq.subspaces(),
+            (None, Quantization::Scalar4) => dim.div_ceil(2),
+            (None, _) => dim,
+        };
+        let quantized_vectors_size = num_nodes * code_size; // u8, u4 or PQ codes
+
         let quantized_end = quantized_vectors_offset + quantized_vectors_size;
-        
+
+        // Binary Arena (optional): sign bits of each vector against per-dimension thresholds
+        let binary_words = if options.binary { dim.div_ceil(64) } else { 0 };
+        let pad_binary = if options.binary && !quantized_end.is_multiple_of(32) { 32 - (quantized_end % 32) } else { 0 };
+        let binary_offset = if options.binary { quantized_end + pad_binary } else { 0 };
+        let binary_end = quantized_end + pad_binary + num_nodes * binary_words * 8;
+
         // Alignment Padding for Full Vectors (f32)
-        let pad2 = if quantized_end % 32 != 0 { 32 - (quantized_end % 32) } else { 0 };
-        let vectors_offset = quantized_end + pad2;
-        let vectors_size = num_nodes * dim * 4; // f32
-        
+        let pad2 = if !binary_end.is_multiple_of(32) { 32 - (binary_end % 32) } else { 0 };
+        let vectors_offset = binary_end + pad2;
+        let element_size = if options.element_type == ElementType::F32 { 4 } else { 2 };
+        let inline = options.vectors == VectorPlacement::Inline;
+        let vectors_size = if inline { num_nodes * dim * element_size } else { 0 }; // f32, f16 or bf16
+
         // Calculate connection arena
         let mut connections_data = Vec::new();
         let mut node_connection_offsets = Vec::with_capacity(num_nodes);
-        let mut current_connections_byte_offset = 0;
-
-        for node in &self.nodes {
-            node_connection_offsets.push(current_connections_byte_offset as u32);
-            for level in 0..=node.layer_max {
-                let neighbors = &node.connections[level];
-                connections_data.push(neighbors.len() as u32);
-                for &n in neighbors {
-                    connections_data.push(n as u32);
-                }
-                current_connections_byte_offset += 4;
-                current_connections_byte_offset += neighbors.len() * 4;
+
+        for id in 0..num_nodes {
+            node_connection_offsets.push((connections_data.len() * 4) as u32);
+            for level in 0..=self.level(id) {
+                let count_index = connections_data.len();
+                connections_data.push(0);
+                connections_data.extend(read_links(self.links(id, level)).map(|n| n as u32));
+                connections_data[count_index] = (connections_data.len() - count_index - 1) as u32;
             }
         }
-        
-        let connections_offset = vectors_offset + vectors_size;
+
+        // Connections are u32: keep them 4-aligned after a 16-bit arena of odd length
+        let pad_vectors = (vectors_offset + vectors_size).next_multiple_of(4) - (vectors_offset + vectors_size);
+        let connections_offset = vectors_offset + vectors_size + pad_vectors;
+        let connections_size = connections_data.len() * 4;
+        let connections_end = connections_offset + connections_size;
+
+        // Deleted Bitmap (u64 words, bit i = node i), only written if something was deleted
+        let deleted_words: &[u64] = if self.deleted.iter().any(|&w| w != 0) { &self.deleted } else { &[] };
+        let pad3 = if !deleted_words.is_empty() && !connections_end.is_multiple_of(8) { 8 - (connections_end % 8) } else { 0 };
+        let deleted_offset = if deleted_words.is_empty() { 0 } else { connections_end + pad3 };
+        let deleted_end = if deleted_words.is_empty() { connections_end } else { deleted_offset + deleted_words.len() * 8 };
+
+        // ID Mapping: keys (u64 per node) followed by the key index (live keys sorted, for binary search)
+        let keys = &self.keys;
+        let mut key_index: Vec<KeyEntry> = (0..num_nodes)
+            .filter(|&id| !self.is_deleted(id))
+            .map(|id| KeyEntry { key: keys[id], id: id as u64 })
+            .collect();
+        key_index.sort_unstable_by_key(|e| e.key);
+
+        let pad4 = if !deleted_end.is_multiple_of(8) { 8 - (deleted_end % 8) } else { 0 };
+        let keys_offset = deleted_end + pad4;
+        let key_index_offset = keys_offset + keys.len() * 8;
+        let key_index_end = key_index_offset + key_index.len() * std::mem::size_of::<KeyEntry>();
+
+        // Quantization scale: the u8 arena holds x / max_norm, so every component fits [-1, 1].
+        // For inner product this is the MIPS transform into the unit ball: the u8 dot product
+        // with the unit query then ranks by q . x. L2 additionally needs |x / max_norm|^2 per node.
+        // Custom distances never use the u8 arena for ranking; it is scaled the same way so it stays valid.
+        let metric = self.distance.metric();
+        let max_norm = match metric {
+            Some(Metric::Cosine) => 1.0,
+            _ => {
+                let max_norm = (0..num_nodes).map(|id| norm(self.vector(id))).fold(0.0f32, f32::max);
+                if max_norm > f32::EPSILON { max_norm } else { 1.0 }
+            }
+        };
+        let norms: Vec<f32> = if metric == Some(Metric::L2) && pq.is_none() {
+            (0..num_nodes).map(|id| (norm(self.vector(id)) / max_norm).powi(2)).collect()
+        } else {
+            Vec::new()
+        };
+        let norms_offset = if norms.is_empty() { 0 } else { key_index_end };
+        let pq_codebooks_offset = if pq.is_some() { key_index_end + norms.len() * 4 } else { 0 };
+        let pq_codebooks_end = key_index_end + norms.len() * 4 + pq.as_ref().map_or(0, |pq| pq.codebooks().len() * 4);
+
+        // Thresholds are the per-dimension means of the stored vectors, so each bit splits its
+        // dimension roughly in half whatever the data's offset.
+        let thresholds: Vec<f32> = if options.binary {
+            let mut sums = vec![0.0f64; dim];
+            for id in 0..num_nodes {
+                for (s, &x) in sums.iter_mut().zip(self.vector(id)) {
+                    *s += x as f64;
+                }
+            }
+            sums.iter().map(|&s| (s / num_nodes.max(1) as f64) as f32).collect()
+        } else {
+            Vec::new()
+        };
+        let binary_thresholds_offset = if options.binary { pq_codebooks_end } else { 0 };
+        let thresholds_end = pq_codebooks_end + thresholds.len() * 4;
+
+        // Trained ranges are learned on x and rescaled, since the arena holds x / max_norm
+        let sq = (options.quantization == Quantization::TrainedScalar8).then(|| {
+            let trained = TrainedQuantizer::train(dim, &self.vectors[..num_nodes * dim]);
+            let rescale = |v: &[f32]| v.iter().map(|x| x / max_norm).collect();
+            TrainedQuantizer::from_params(rescale(trained.lower()), rescale(trained.step()))
+        });
+        let sq_params_offset = if sq.is_some() { thresholds_end } else { 0 };
 
         // 2. Create Placeholder Header
         // Note: Obfuscation Key is removed/unused in this Zero-Copy version as per Plan
         let mut header = Header {
             magic: *b"HNSWANN1",
-            version: 1,
+            version: FORMAT_VERSION,
             dimension: dim as u32,
             num_elements: num_nodes as u32,
             entry_point_id: self.entry_point.unwrap_or(0) as u32,
-            max_layer: self.nodes.get(self.entry_point.unwrap_or(0)).map_or(0, |n| n.layer_max) as u16,
-            padding_1: 0,
-            m_max: self.m as u32,
-            m_max_0: self.m0 as u32,
-            ef_construction: self.ef_construction as u32,
+            max_layer: self.entry_point.map_or(0, |ep| self.level(ep)) as u16,
+            max_layers: self.config.max_layers as u16,
+            m_max: self.config.m as u32,
+            m_max_0: self.config.m0 as u32,
+            ef_construction: self.config.ef_construction as u32,
             nodes_offset: header_size as u64,
-            quantized_vectors_offset: quantized_vectors_offset as u64,
-            vectors_offset: vectors_offset as u64,
+            quantized_vectors_offset: if pq.is_some() { 0 } else { quantized_vectors_offset as u64 },
+            vectors_offset: if inline { vectors_offset as u64 } else { 0 },
             connections_offset: connections_offset as u64,
             checksum: 0,
-            obfuscation_key: 0, 
-            padding_2: [0; 21],
+            obfuscation_key: 0,
+            connections_size: connections_size as u64,
+            deleted_offset: deleted_offset as u64,
+            keys_offset: keys_offset as u64,
+            key_index_offset: key_index_offset as u64,
+            num_keys: key_index.len() as u64,
+            level_multiplier: self.config.level_multiplier,
+            seed: self.config.seed.unwrap_or(0),
+            metric: metric.map_or(METRIC_CUSTOM, |m| m as u32),
+            build_flags: self.build_flags(),
+            norms_offset: norms_offset as u64,
+            max_norm: max_norm as f64,
+            quantization: match options.quantization {
+                Quantization::Scalar8 => QUANTIZATION_SCALAR8,
+                Quantization::TrainedScalar8 => QUANTIZATION_SCALAR8_TRAINED,
+                Quantization::Scalar4 => QUANTIZATION_SCALAR4,
+                Quantization::Product { .. } => QUANTIZATION_PRODUCT,
+            },
+            pq_subspaces: pq.as_ref().map_or(0, |pq| pq.subspaces() as u32),
+            pq_codebooks_offset: pq_codebooks_offset as u64,
+            pq_codes_offset: if pq.is_some() { quantized_vectors_offset as u64 } else { 0 },
+            binary_offset: binary_offset as u64,
+            binary_thresholds_offset: binary_thresholds_offset as u64,
+            sq_params_offset: sq_params_offset as u64,
+            element_type: match options.element_type {
+                ElementType::F32 => ELEMENT_F32,
+                ElementType::F16 => ELEMENT_F16,
+                ElementType::BF16 => ELEMENT_BF16,
+            },
+            vector_placement: match options.vectors {
+                VectorPlacement::Inline => VECTORS_INLINE,
+                VectorPlacement::Sidecar => VECTORS_SIDECAR,
+                VectorPlacement::Omitted => VECTORS_OMITTED,
+            },
+            padding_2: [0; 4],
         };
 
         file.write_all(bytes_of(&header))?;
         let mut hasher = Hasher::new();
 
         // 3. Write Nodes
-        for (i, node) in self.nodes.iter().enumerate() {
+        for (id, &connections_offset) in node_connection_offsets.iter().enumerate() {
             let on_disk_node = OnDiskNode {
-                layer_count: (node.layer_max + 1) as u8,
+                layer_count: (self.level(id) + 1) as u8,
                 padding: [0; 3],
-                connections_offset: node_connection_offsets[i],
+                connections_offset,
             };
             let bytes = bytes_of(&on_disk_node);
             file.write_all(bytes)?;
@@ -303,29 +1027,76 @@ impl HNSW {
         file.write_all(&pad_zeros)?;
         hasher.update(&pad_zeros);
 
-        // 5. Write Quantized Vectors (u8)
-        // We prefer to iterate once and do both logic, but writing sequentially is easier for disk layout.
-        // We will iterate nodes again.
-        for node in &self.nodes {
-            let mut vec = node.vector.clone();
-            Quantizer::l2_normalize(&mut vec); // Normalize first
-            let q_vec = Quantizer::quantize_u8(&vec);
-            file.write_all(&q_vec)?;
-            hasher.update(&q_vec);
+        // 5. Write Quantized Vectors (u8 or u4) or PQ Codes
+        let inv_max_norm = 1.0 / max_norm;
+        let mut scaled = vec![0.0f32; dim];
+        let mut code = vec![0u8; code_size];
+        for id in 0..num_nodes {
+            match &pq {
+                Some(pq) => pq.encode(self.vector(id), &mut code),
+                None => {
+                    for (s, &x) in scaled.iter_mut().zip(self.vector(id)) {
+                        *s = x * inv_max_norm;
+                    }
+                    code = match (&sq, options.quantization) {
+                        (Some(sq), _) => sq.quantize_u8(&scaled),
+                        (None, Quantization::Scalar4) => Quantizer::quantize_u4(&scaled),
+                        (None, _) => Quantizer::quantize_u8(&scaled),
+                    };
+                }
+            }
+            file.write_all(&code)?;
+            hasher.update(&code);
+        }
+
+        // 6. Write Binary Arena (optional), then Padding 2
+        if options.binary {
+            let pad_zeros = vec![0u8; pad_binary];
+            file.write_all(&pad_zeros)?;
+            hasher.update(&pad_zeros);
+
+            let mut bits = Vec::with_capacity(binary_words);
+            for id in 0..num_nodes {
+                sign_bits_into(self.vector(id), &thresholds, &mut bits);
+                let bytes = bytemuck::cast_slice(&bits);
+                file.write_all(bytes)?;
+                hasher.update(bytes);
+            }
         }
 
-        // 6. Write Padding 2
         let pad_zeros_2 = vec![0u8; pad2];
         file.write_all(&pad_zeros_2)?;
         hasher.update(&pad_zeros_2);
-        
-        // 7. Write Full Precision Vectors (f32) - Normalized
-        for node in &self.nodes {
-            let mut vec = node.vector.clone();
-            Quantizer::l2_normalize(&mut vec);
-            let bytes = bytemuck::cast_slice(&vec);
-            file.write_all(bytes)?;
-            hasher.update(bytes);
+
+        // 7. Write Full Precision Vectors (f32, f16 or bf16) - as stored (unit length for cosine)
+        match options.vectors {
+            VectorPlacement::Inline => {
+                self.write_full_vectors(&mut file, &mut hasher, options.element_type)?;
+                let pad_zeros = vec![0u8; pad_vectors];
+                file.write_all(&pad_zeros)?;
+                hasher.update(&pad_zeros);
+            }
+            VectorPlacement::Sidecar => {
+                let mut sidecar = std::io::BufWriter::new(std::fs::File::create(vectors_sidecar_path(path))?);
+                let mut sidecar_header = VectorsHeader {
+                    magic: VECTORS_MAGIC,
+                    version: VECTORS_FORMAT_VERSION,
+                    element_type: header.element_type,
+                    dimension: dim as u32,
+                    padding_0: 0,
+                    num_elements: num_nodes as u64,
+                    checksum: 0,
+                    padding: [0; 3],
+                };
+                sidecar.write_all(bytes_of(&sidecar_header))?;
+                let mut sidecar_hasher = Hasher::new();
+                self.write_full_vectors(&mut sidecar, &mut sidecar_hasher, options.element_type)?;
+                sidecar_header.checksum = sidecar_hasher.finalize() as u64;
+                let mut sidecar = sidecar.into_inner().map_err(|e| e.into_error())?;
+                sidecar.seek(SeekFrom::Start(0))?;
+                sidecar.write_all(bytes_of(&sidecar_header))?;
+            }
+            VectorPlacement::Omitted => {}
         }
 
         // 8. Write Connections
@@ -333,7 +1104,57 @@ impl HNSW {
         file.write_all(bytes)?;
         hasher.update(bytes);
 
-        // 9. Finalize
+        // 9. Write Deleted Bitmap (optional)
+        if !deleted_words.is_empty() {
+            let pad_zeros_3 = vec![0u8; pad3];
+            file.write_all(&pad_zeros_3)?;
+            hasher.update(&pad_zeros_3);
+
+            let bytes = bytemuck::cast_slice(deleted_words);
+            file.write_all(bytes)?;
+            hasher.update(bytes);
+        }
+
+        // 10. Write ID Mapping
+        let pad_zeros_4 = vec![0u8; pad4];
+        file.write_all(&pad_zeros_4)?;
+        hasher.update(&pad_zeros_4);
+
+        let bytes = bytemuck::cast_slice(keys);
+        file.write_all(bytes)?;
+        hasher.update(bytes);
+
+        let bytes = bytemuck::cast_slice(&key_index);
+        file.write_all(bytes)?;
+        hasher.update(bytes);
+
+        // 11. Write Norms (L2 only)
+        let bytes = bytemuck::cast_slice(&norms);
+        file.write_all(bytes)?;
+        hasher.update(bytes);
+
+        // 12. Write PQ Codebooks
+        if let Some(pq) = &pq {
+            let bytes = bytemuck::cast_slice(pq.codebooks());
+            file.write_all(bytes)?;
+            hasher.update(bytes);
+        }
+
+        // 13. Write Binary Thresholds (optional)
+        let bytes = bytemuck::cast_slice(&thresholds);
+        file.write_all(bytes)?;
+        hasher.update(bytes);
+
+        // 14. Write Trained Scalar Ranges (optional)
+        if let Some(sq) = &sq {
+            for params in [sq.lower(), sq.step()] {
+                let bytes = bytemuck::cast_slice(params);
+                file.write_all(bytes)?;
+                hasher.update(bytes);
+            }
+        }
+
+        // 15. Finalize
         header.checksum = hasher.finalize() as u64;
         file.seek(SeekFrom::Start(0))?;
         file.write_all(bytes_of(&header))?;
@@ -341,28 +1162,119 @@ impl HNSW {
         Ok(())
     }
 
-    fn random_level(&self) -> usize {
-        let mut rng = rand::thread_rng();
-        let mut level = 0;
-        while rng.gen::<f32>() < 0.5 && level < self.layers - 1 {
-            level += 1;
+    /// The stored vectors in `element_type`, node after node.
+    fn write_full_vectors(&self, out: &mut impl std::io::Write, hasher: &mut crc32fast::Hasher, element_type: ElementType) -> std::io::Result<()> {
+        let (num_nodes, dim) = (self.len(), self.config.dimension);
+        if element_type == ElementType::F32 {
+            let bytes = bytemuck::cast_slice(&self.vectors[..num_nodes * dim]);
+            out.write_all(bytes)?;
+            hasher.update(bytes);
+            return Ok(());
         }
-        level
+        let mut half = vec![0u16; dim];
+        for id in 0..num_nodes {
+            match element_type {
+                ElementType::F16 => crate::simd::encode_f16(self.vector(id), &mut half),
+                _ => crate::simd::encode_bf16(self.vector(id), &mut half),
+            }
+            let bytes = bytemuck::cast_slice(&half);
+            out.write_all(bytes)?;
+            hasher.update(bytes);
+        }
+        Ok(())
+    }
+
+    fn build_flags(&self) -> u32 {
+        use crate::storage::format::{BUILD_FLAG_EXTEND_CANDIDATES, BUILD_FLAG_HEURISTIC, BUILD_FLAG_KEEP_PRUNED, BUILD_FLAG_SEEDED};
+
+        let mut flags = 0;
+        if self.config.seed.is_some() {
+            flags |= BUILD_FLAG_SEEDED;
+        }
+        if let NeighborSelection::Heuristic { extend_candidates, keep_pruned_connections } = self.config.neighbor_selection {
+            flags |= BUILD_FLAG_HEURISTIC;
+            if extend_candidates {
+                flags |= BUILD_FLAG_EXTEND_CANDIDATES;
+            }
+            if keep_pruned_connections {
+                flags |= BUILD_FLAG_KEEP_PRUNED;
+            }
+        }
+        flags
+    }
+
+    /// Number of nodes whose top layer is `level`, for `level` in `0..=max_layer`.
+    pub fn layer_histogram(&self) -> Vec<usize> {
+        let mut histogram = Vec::new();
+        for &level in &self.levels {
+            let level = level as usize;
+            if histogram.len() <= level {
+                histogram.resize(level + 1, 0);
+            }
+            histogram[level] += 1;
+        }
+        histogram
+    }
+
+    /// Expected `layer_histogram` for `num_nodes` nodes under this config:
+    /// P(level >= l) = exp(-l / mL), with everything above the cap folded into the top layer.
+    pub fn expected_layer_histogram(&self, num_nodes: usize) -> Vec<f64> {
+        let at_least = |level: usize| (-(level as f64) / self.config.level_multiplier).exp();
+        let top = self.config.max_layers - 1;
+        (0..=top).map(|level| {
+            let p = if level == top { at_least(level) } else { at_least(level) - at_least(level + 1) };
+            p * num_nodes as f64
+        }).collect()
+    }
+
+    /// Level = floor(-ln(U) * mL), U uniform in (0, 1], capped at `max_layers - 1`.
+    fn random_level(&mut self) -> usize {
+        let u = 1.0 - self.rng.gen::<f64>();
+        let level = (-u.ln() * self.config.level_multiplier).floor() as usize;
+        level.min(self.config.max_layers - 1)
     }
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
+    use crate::core::config::HnswConfigBuilder;
+
+    /// Positional shorthand for test builds: (dimension, max_layers, ef_construction, m, m0).
+    pub(crate) fn test_config(dimension: usize, max_layers: usize, ef_construction: usize, m: usize, m0: usize) -> HnswConfig {
+        test_builder(dimension, max_layers, ef_construction, m, m0).build().unwrap()
+    }
+
+    /// `test_config` as a builder, for tests that also set a seed, metric or selection strategy.
+    pub(crate) fn test_builder(dimension: usize, max_layers: usize, ef_construction: usize, m: usize, m0: usize) -> HnswConfigBuilder {
+        HnswConfig::builder(dimension)
+            .max_layers(max_layers)
+            .ef_construction(ef_construction)
+            .m(m)
+            .m0(m0)
+    }
+
+    #[test]
+    fn test_candidate_heap_order() {
+        use std::cmp::Reverse;
+
+        let candidates = [2.0, 0.5, 3.0, 1.0].into_iter().enumerate().map(|(node_id, distance)| Candidate { distance, node_id });
+        // The result set evicts its furthest member
+        let mut results: std::collections::BinaryHeap<Candidate> = candidates.clone().collect();
+        assert_eq!(results.pop().unwrap().distance, 3.0);
+        // The frontier expands its closest member first
+        let mut frontier: std::collections::BinaryHeap<Reverse<Candidate>> = candidates.map(Reverse).collect();
+        assert_eq!(frontier.pop().unwrap().0.distance, 0.5);
+    }
 
     #[test]
     fn test_hnsw_basic() {
-        let mut index = HNSW::new(4, 10, 5, 10);
+        let mut index = HNSW::new(test_config(3, 4, 10, 5, 10)).unwrap();
         
         // Insert 3 vectors
-        index.insert(vec![1.0, 1.0, 1.0]); // ID 0
-        index.insert(vec![2.0, 2.0, 2.0]); // ID 1
-        index.insert(vec![10.0, 10.0, 10.0]); // ID 2
+        index.insert(vec![1.0, 1.0, 1.0]).unwrap(); // ID 0
+        index.insert(vec![2.0, 2.0, 2.0]).unwrap(); // ID 1
+        index.insert(vec![10.0, 10.0, 10.0]).unwrap(); // ID 2
 
         // Search for something close to ID 1
         let query = vec![2.1, 2.1, 2.1];
@@ -375,13 +1287,13 @@ mod tests {
 
     #[test]
     fn test_hnsw_larger() {
-        let mut index = HNSW::new(4, 20, 10, 20);
+        let mut index = HNSW::new(test_config(10, 4, 20, 10, 20)).unwrap();
         let mut rng = rand::thread_rng();
         
         // Insert 100 random vectors
         for _ in 0..100 {
             let vec: Vec<f32> = (0..10).map(|_| rng.gen()).collect();
-            index.insert(vec);
+            index.insert(vec).unwrap();
         }
 
         // Search
@@ -394,4 +1306,368 @@ mod tests {
             assert!(results[i].1 <= results[i+1].1);
         }
     }
+
+    fn random_data(n: usize, dim: usize) -> Vec<Vec<f32>> {
+        let mut rng = rand::thread_rng();
+        (0..n).map(|_| (0..dim).map(|_| rng.gen()).collect()).collect()
+    }
+
+    /// `random_data` that is the same on every run, for tests that assert recall floors.
+    fn seeded_data(n: usize, dim: usize, seed: u64) -> Vec<Vec<f32>> {
+        let mut rng = StdRng::seed_from_u64(seed);
+        (0..n).map(|_| (0..dim).map(|_| rng.gen()).collect()).collect()
+    }
+
+    /// Average recall@k of `index` against an exhaustive scan of `data`.
+    fn recall_at_k(index: &HNSW, data: &[Vec<f32>], queries: &[Vec<f32>], k: usize) -> f32 {
+        let mut hits = 0;
+        for query in queries {
+            let mut exact: Vec<(usize, f32)> = data.iter().enumerate()
+                .map(|(id, v)| (id, crate::simd::distance::euclidean_distance(query, v)))
+                .collect();
+            exact.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap());
+            let truth: Vec<u64> = exact.iter().take(k).map(|(id, _)| *id as u64).collect();
+
+            hits += index.search(query, k).iter().filter(|(id, _)| truth.contains(id)).count();
+        }
+        hits as f32 / (queries.len() * k) as f32
+    }
+
+    #[test]
+    fn test_insert_parallel_matches_serial_recall() {
+        let data = seeded_data(2000, 16, 1);
+        let queries = seeded_data(50, 16, 2);
+        // Same seeded levels for both; only the parallel link order varies between runs
+        let config = test_builder(16, 6, 64, 8, 16).seed(3).build().unwrap();
+
+        let mut serial = HNSW::new(config).unwrap();
+        for v in &data {
+            serial.insert(v.clone()).unwrap();
+        }
+
+        // Force several workers even on single-core CI machines.
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(4).build().unwrap();
+        let mut parallel = HNSW::new(config).unwrap();
+        let ids = pool.install(|| parallel.insert_parallel(data.clone())).unwrap();
+
+        assert_eq!(ids, 0..data.len());
+        assert_eq!(parallel.len(), data.len());
+        for id in 0..parallel.len() {
+            let neighbors = parallel.neighbors(id, 0);
+            assert!(neighbors.len() <= parallel.config.m0);
+            assert!(neighbors.iter().all(|&n| n != id && n < data.len()));
+        }
+
+        let serial_recall = recall_at_k(&serial, &data, &queries, 10);
+        let parallel_recall = recall_at_k(&parallel, &data, &queries, 10);
+        assert!(serial_recall >= 0.9, "serial recall {}", serial_recall);
+        assert!(parallel_recall >= 0.9, "parallel recall {}", parallel_recall);
+    }
+
+    /// Tight, well separated clusters: the case where the simple strategy spends all links inside one cluster.
+    fn clustered_data(n: usize, dim: usize, clusters: usize, seed: u64) -> Vec<Vec<f32>> {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let centers: Vec<Vec<f32>> = (0..clusters).map(|_| (0..dim).map(|_| rng.gen()).collect()).collect();
+        (0..n).map(|i| {
+            centers[i % clusters].iter().map(|c| c * 10.0 + rng.gen::<f32>() * 2.0).collect()
+        }).collect()
+    }
+
+    #[test]
+    fn test_heuristic_selection_recall() {
+        let data = clustered_data(2000, 16, 40, 1);
+        let queries: Vec<Vec<f32>> = clustered_data(200, 16, 40, 2).into_iter().step_by(4).collect();
+
+        let build = |selection: NeighborSelection| {
+            let config = test_builder(16, 6, 16, 6, 12).neighbor_selection(selection).seed(3).build().unwrap();
+            let mut index = HNSW::new(config).unwrap();
+            for v in &data {
+                index.insert(v.clone()).unwrap();
+            }
+            index
+        };
+
+        let simple = build(NeighborSelection::Simple);
+        let heuristic = build(NeighborSelection::Heuristic { extend_candidates: false, keep_pruned_connections: false });
+        let extended = build(NeighborSelection::Heuristic { extend_candidates: true, keep_pruned_connections: true });
+
+        for id in 0..heuristic.len() {
+            let neighbors = heuristic.neighbors(id, 0);
+            assert!(neighbors.len() <= heuristic.config.m0);
+            assert!(!neighbors.contains(&id));
+        }
+
+        let simple_recall = recall_at_k(&simple, &data, &queries, 10);
+        let heuristic_recall = recall_at_k(&heuristic, &data, &queries, 10);
+        let extended_recall = recall_at_k(&extended, &data, &queries, 10);
+        // Seeded data and levels: simple selection stays near 0.33 here
+        assert!(simple_recall >= 0.25, "simple recall {}", simple_recall);
+        assert!(heuristic_recall >= 0.65, "heuristic recall {}", heuristic_recall);
+        assert!(extended_recall >= 0.7, "extended recall {}", extended_recall);
+    }
+
+    #[test]
+    fn test_delete_excludes_and_repairs() {
+        let data = seeded_data(1000, 8, 1);
+        let removed: Vec<usize> = (0..data.len()).step_by(5).collect();
+
+        // One at a time, then as one batch (with a duplicate and an unknown ID)
+        for batched in [false, true] {
+            let mut index = HNSW::new(test_builder(8, 6, 64, 8, 16).seed(2).build().unwrap()).unwrap();
+            for v in &data {
+                index.insert(v.clone()).unwrap();
+            }
+
+            if batched {
+                let ids: Vec<usize> = removed.iter().copied().chain([removed[0], data.len()]).collect();
+                assert_eq!(index.delete_many(&ids), removed.len());
+                assert_eq!(index.delete_many(&removed), 0);
+            } else {
+                for &id in &removed {
+                    assert!(index.delete(id));
+                }
+            }
+            assert!(!index.delete(removed[0]));
+            assert!(!index.delete(data.len()));
+            assert!(index.is_deleted(removed[0]));
+
+            // Repair never strands a live node without links.
+            for id in (0..index.len()).filter(|&id| !index.is_deleted(id)) {
+                assert!(!index.neighbors(id, 0).is_empty());
+            }
+            // Nor leaves an edge into a tombstone, including from nodes it did not link to.
+            for id in 0..index.len() {
+                for level in 0..=index.level(id) {
+                    assert!(index.neighbors(id, level).iter().all(|&n| !index.is_deleted(n)), "node {} level {}", id, level);
+                }
+            }
+
+            // An exact match of a deleted vector must not be returned; its live neighbors still are.
+            for &id in &removed {
+                let results = index.search(&data[id], 5);
+                assert_eq!(results.len(), 5);
+                assert!(results.iter().all(|(r, _)| !index.is_deleted(*r as usize)));
+            }
+
+            let live: Vec<usize> = (0..data.len()).filter(|id| !index.is_deleted(*id)).collect();
+            let mut found = 0;
+            for &id in live.iter().take(100) {
+                if index.search(&data[id], 1)[0].0 == id as u64 {
+                    found += 1;
+                }
+            }
+            assert!(found >= 95, "batched {}: only {} of 100 live vectors found themselves", batched, found);
+        }
+    }
+
+    #[test]
+    fn test_external_keys() {
+        let mut index = HNSW::new(test_config(3, 4, 10, 5, 10)).unwrap();
+        index.insert_with_key(1000, vec![1.0, 0.0, 0.0]).unwrap();
+        index.insert_with_key(42, vec![0.0, 1.0, 0.0]).unwrap();
+        assert_eq!(index.insert_with_key(42, vec![0.0, 0.0, 1.0]), Err(HnswError::DuplicateKey(42)));
+
+        // Auto keys continue after the largest explicit key
+        let id = index.insert(vec![0.0, 0.0, 1.0]).unwrap();
+        assert_eq!(index.key(id), 1001);
+
+        let results = index.search(&[0.1, 0.9, 0.0], 1);
+        assert_eq!(results[0].0, 42);
+        assert_eq!(index.get_vector_by_key(1000), Some(&[1.0, 0.0, 0.0][..]));
+        assert_eq!(index.get_vector_by_key(7), None);
+
+        let ids = index.insert_parallel_with_keys(vec![(7, vec![1.0, 1.0, 0.0]), (8, vec![0.0, 1.0, 1.0])]).unwrap();
+        assert_eq!(ids, 3..5);
+        assert_eq!(index.insert_parallel_with_keys(vec![(9, vec![0.0; 3]), (9, vec![1.0; 3])]), Err(HnswError::DuplicateKey(9)));
+        assert_eq!(index.len(), 5);
+
+        // Deleting frees the key for reuse
+        assert!(index.delete_by_key(42));
+        assert_eq!(index.get_vector_by_key(42), None);
+        assert!(index.insert_with_key(42, vec![0.0, 0.9, 0.1]).is_ok());
+        assert_eq!(index.delete_many_by_key(&[7, 8, 8, 12345]), 2);
+        assert_eq!(index.get_vector_by_key(8), None);
+
+        // Once the largest key is taken there are no automatic keys left, explicit ones still work
+        index.insert_with_key(u64::MAX, vec![1.0, 0.0, 1.0]).unwrap();
+        assert_eq!(index.insert(vec![0.0; 3]), Err(HnswError::KeysExhausted));
+        assert_eq!(index.insert_parallel(vec![vec![0.0; 3]]), Err(HnswError::KeysExhausted));
+        assert!(index.insert_with_key(9, vec![0.0, 0.5, 0.5]).is_ok());
+
+        let mut index = HNSW::new(test_config(3, 4, 10, 5, 10)).unwrap();
+        index.insert_with_key(u64::MAX - 1, vec![1.0, 0.0, 0.0]).unwrap();
+        assert_eq!(index.insert_parallel(vec![vec![0.0; 3], vec![1.0; 3]]), Err(HnswError::KeysExhausted));
+        assert_eq!(index.insert_parallel(vec![vec![0.0; 3]]), Ok(1..2));
+        assert_eq!(index.key(1), u64::MAX);
+    }
+
+    #[test]
+    fn test_search_with_params_ef() {
+        let data = seeded_data(2000, 16, 1);
+        let queries = seeded_data(50, 16, 2);
+        // Serial and seeded, so the graph is the same on every run
+        let mut index = HNSW::new(test_builder(16, 6, 100, 8, 16).seed(3).build().unwrap()).unwrap();
+        for v in &data {
+            index.insert(v.clone()).unwrap();
+        }
+
+        let recall = |ef: usize| {
+            let mut hits = 0;
+            for query in &queries {
+                let mut exact: Vec<(usize, f32)> = data.iter().enumerate()
+                    .map(|(id, v)| (id, crate::simd::distance::euclidean_distance(query, v)))
+                    .collect();
+                exact.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap());
+                let results = index.search_with_params(query, 10, SearchParams::new(ef));
+                assert_eq!(results.len(), 10);
+                hits += results.iter().filter(|(key, _)| exact.iter().take(10).any(|(id, _)| *id as u64 == *key)).count();
+            }
+            hits as f32 / (queries.len() * 10) as f32
+        };
+
+        // ef below k is raised to k; the wider beam finds nearly everything
+        let narrow = recall(1);
+        let wide = recall(200);
+        assert!(narrow >= 0.75, "recall at ef=k {}", narrow);
+        assert!(wide >= 0.95, "recall at ef=200 {}", wide);
+    }
+
+    #[test]
+    fn test_zero_k_with_deleted_entry_point() {
+        let mut index = HNSW::new(test_config(3, 4, 10, 5, 10)).unwrap();
+        for i in 0..20 {
+            index.insert(vec![i as f32, 1.0, 0.0]).unwrap();
+        }
+        assert!(index.delete(index.entry_point.unwrap()));
+
+        let query = [1.0, 1.0, 0.0];
+        assert!(index.search_with_params(&query, 0, SearchParams::new(0)).is_empty());
+        assert!(index.search_layer(&query, index.entry_point.unwrap(), 0, 0, true).is_empty());
+        assert_eq!(index.search_with_params(&query, 3, SearchParams::new(0)).len(), 3);
+
+        let file = tempfile::NamedTempFile::new().unwrap();
+        index.save(file.path()).unwrap();
+        let mmap_index = MmapIndex::load(file.path()).unwrap();
+        assert!(mmap_index.search_with_params(&query, 0, SearchParams::new(0)).is_empty());
+        assert_eq!(mmap_index.search_with_params(&query, 3, SearchParams::new(0)).len(), 3);
+    }
+
+    #[test]
+    fn test_rejects_wrong_dimension() {
+        let mut index = HNSW::new(test_config(3, 4, 10, 5, 10)).unwrap();
+        assert_eq!(index.insert(vec![1.0, 2.0]), Err(HnswError::DimensionMismatch { expected: 3, actual: 2 }));
+        assert_eq!(
+            index.insert_parallel(vec![vec![1.0; 3], vec![1.0; 4]]),
+            Err(HnswError::DimensionMismatch { expected: 3, actual: 4 })
+        );
+        assert!(index.is_empty());
+        assert_eq!(index.insert(vec![1.0, 2.0, 3.0]), Ok(0));
+    }
+
+    #[test]
+    fn test_new_validates_config() {
+        // Struct updates bypass the builder, so `new` checks again
+        let valid = test_config(3, 4, 10, 5, 10);
+        for config in [
+            HnswConfig { max_layers: 0, ..valid },
+            HnswConfig { m0: 4, ..valid },
+            HnswConfig { ef_construction: 0, ..valid },
+            HnswConfig { level_multiplier: 0.0, ..valid },
+        ] {
+            assert!(matches!(HNSW::new(config), Err(HnswError::InvalidConfig(_))), "{:?}", config);
+            assert!(matches!(HNSW::with_distance(config, MetricDistance::new(Metric::L2)), Err(HnswError::InvalidConfig(_))));
+        }
+    }
+
+    #[test]
+    fn test_level_distribution_matches_theory() {
+        let config = test_builder(4, 8, 10, 16, 32).seed(7).build().unwrap();
+        let mut index = HNSW::new(config).unwrap();
+
+        let n = 200_000;
+        let mut histogram = vec![0usize; config.max_layers];
+        for _ in 0..n {
+            histogram[index.random_level()] += 1;
+        }
+        let expected = index.expected_layer_histogram(n);
+
+        // mL = 1/ln(16): each layer holds ~1/16 of the one below
+        for level in 0..3 {
+            let relative = (histogram[level] as f64 - expected[level]).abs() / expected[level];
+            assert!(relative < 0.05, "layer {}: got {}, expected {:.0}", level, histogram[level], expected[level]);
+        }
+
+        // Same seed, same levels
+        let mut a = HNSW::new(config).unwrap();
+        let mut b = HNSW::new(config).unwrap();
+        let levels_a: Vec<usize> = (0..1000).map(|_| a.random_level()).collect();
+        let levels_b: Vec<usize> = (0..1000).map(|_| b.random_level()).collect();
+        assert_eq!(levels_a, levels_b);
+
+        // Cap is respected
+        let mut flat = HNSW::new(test_builder(4, 3, 10, 16, 32).level_multiplier(50.0).build().unwrap()).unwrap();
+        assert!((0..1000).all(|_| flat.random_level() <= 2));
+    }
+
+    #[test]
+    fn test_layer_histogram() {
+        let mut index = HNSW::new(test_config(4, 6, 16, 4, 8)).unwrap();
+        index.insert_parallel(random_data(3000, 4)).unwrap();
+
+        let histogram = index.layer_histogram();
+        assert_eq!(histogram.iter().sum::<usize>(), 3000);
+        assert_eq!(histogram.len(), index.level(index.entry_point.unwrap()) + 1);
+        assert!(histogram[0] > histogram[1]);
+    }
+
+    #[test]
+    fn test_build_memory_tracks_file_size() {
+        let mut index = HNSW::new(test_config(32, 6, 32, 8, 16)).unwrap();
+        index.insert_parallel(random_data(5000, 32)).unwrap();
+        for id in 0..index.len() {
+            for level in 0..=index.level(id) {
+                assert!(index.neighbors(id, level).len() <= if level == 0 { 16 } else { 8 });
+            }
+        }
+
+        let file = tempfile::NamedTempFile::new().unwrap();
+        index.save(file.path()).unwrap();
+        let file_size = std::fs::metadata(file.path()).unwrap().len() as usize;
+        let memory = index.memory_usage();
+        assert!(memory >= 5000 * 32 * 4);
+        assert!(memory < file_size * 3 / 2, "build memory {} vs file {}", memory, file_size);
+    }
+
+    #[test]
+    fn test_seeded_builds_are_byte_identical() {
+        use rand::rngs::StdRng;
+
+        let build = |seed: u64| -> (u64, Vec<u8>) {
+            let mut data_rng = StdRng::seed_from_u64(99);
+            let config = test_builder(8, 8, 32, 6, 12)
+                .seed(seed)
+                .neighbor_selection(NeighborSelection::Heuristic { extend_candidates: true, keep_pruned_connections: true })
+                .build()
+                .unwrap();
+            let mut index = HNSW::new(config).unwrap();
+            for _ in 0..500 {
+                index.insert((0..8).map(|_| data_rng.gen::<f32>()).collect()).unwrap();
+            }
+            index.delete(17);
+
+            let file = tempfile::NamedTempFile::new().unwrap();
+            index.save(file.path()).unwrap();
+            let bytes = std::fs::read(file.path()).unwrap();
+            let header: &crate::storage::format::Header = bytemuck::from_bytes(&bytes[..256]);
+            (header.checksum, bytes)
+        };
+
+        let (checksum_a, bytes_a) = build(42);
+        let (checksum_b, bytes_b) = build(42);
+        assert_eq!(checksum_a, checksum_b);
+        assert!(bytes_a == bytes_b, "same seed produced different files");
+
+        let (checksum_c, _) = build(43);
+        assert_ne!(checksum_a, checksum_c);
+    }
 }
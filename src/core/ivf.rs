@@ -0,0 +1,403 @@
+use crate::core::config::{IvfConfig, Metric};
+use crate::core::distance::{Distance, MetricDistance};
+use crate::core::hnsw::{auto_keys, next_key_after};
+use crate::core::kmeans::{kmeans, nearest};
+use crate::core::quantization::Quantizer;
+use crate::core::search::{push_bounded, Candidate};
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use rayon::prelude::*;
+use std::collections::{BinaryHeap, HashMap};
+use thiserror::Error;
+
+#[derive(Error, Debug, PartialEq)]
+pub enum IvfError {
+    #[error("Duplicate key: {0}")]
+    DuplicateKey(u64),
+    #[error("Invalid config: {0}")]
+    InvalidConfig(String),
+    #[error("Dimension mismatch: expected {expected}, got {actual}")]
+    DimensionMismatch { expected: usize, actual: usize },
+    #[error("Index must be trained first")]
+    NotTrained,
+    #[error("No automatic keys left: key {} is in use", u64::MAX)]
+    KeysExhausted,
+}
+
+/// Inverted-file index: k-means centroids partition the vectors into `nlist` lists and a query
+/// scans only the `nprobe` lists whose centroids are closest. Scales to collections where a
+/// graph does not fit in memory; `save` writes a file for `MmapIvfIndex`.
+pub struct IvfIndex {
+    pub config: IvfConfig,
+    distance: MetricDistance,
+    /// `nlist * dimension` floats, empty until trained.
+    centroids: Vec<f32>,
+    /// Vector IDs per list, in insertion order.
+    lists: Vec<Vec<u32>>,
+    /// Vector slab, `dimension` floats per ID (prepared, i.e. unit length for cosine).
+    vectors: Vec<f32>,
+    keys: Vec<u64>,
+    key_to_id: HashMap<u64, usize>,
+    /// `None` once `u64::MAX` is in use, like `HNSW`.
+    next_key: Option<u64>,
+}
+
+impl IvfIndex {
+    pub fn new(config: IvfConfig) -> Result<Self, IvfError> {
+        config.validate()?;
+        Ok(Self {
+            distance: MetricDistance::new(config.metric),
+            centroids: Vec::new(),
+            lists: vec![Vec::new(); config.nlist],
+            vectors: Vec::new(),
+            keys: Vec::new(),
+            key_to_id: HashMap::new(),
+            next_key: Some(0),
+            config,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    pub fn is_trained(&self) -> bool {
+        !self.centroids.is_empty()
+    }
+
+    pub fn centroid(&self, list: usize) -> &[f32] {
+        let dim = self.config.dimension;
+        &self.centroids[list * dim..(list + 1) * dim]
+    }
+
+    /// Number of vectors in each list.
+    pub fn list_sizes(&self) -> Vec<usize> {
+        self.lists.iter().map(Vec::len).collect()
+    }
+
+    pub fn vector(&self, id: usize) -> &[f32] {
+        let dim = self.config.dimension;
+        &self.vectors[id * dim..(id + 1) * dim]
+    }
+
+    pub fn key(&self, id: usize) -> u64 {
+        self.keys[id]
+    }
+
+    /// Learn the centroids with k-means (seeded by `config.seed`) on `samples`, at least `nlist`
+    /// of them. Only an empty index can be (re)trained, since existing lists would no longer
+    /// match their centroids.
+    pub fn train(&mut self, samples: &[Vec<f32>]) -> Result<(), IvfError> {
+        let (dim, nlist) = (self.config.dimension, self.config.nlist);
+        if !self.is_empty() {
+            return Err(IvfError::InvalidConfig("cannot retrain a non-empty index".to_string()));
+        }
+        if samples.len() < nlist {
+            return Err(IvfError::InvalidConfig(format!("need at least nlist = {} training vectors, got {}", nlist, samples.len())));
+        }
+
+        let mut data = Vec::with_capacity(samples.len() * dim);
+        for sample in samples {
+            self.check_dimension(sample)?;
+            let start = data.len();
+            data.extend_from_slice(sample);
+            self.distance.prepare(&mut data[start..]);
+        }
+
+        let mut rng = match self.config.seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+        self.centroids = kmeans(&data, dim, nlist, self.config.train_iters, &mut rng, &self.distance);
+        Ok(())
+    }
+
+    /// Insert under the next free key and return the vector ID.
+    pub fn insert(&mut self, vector: Vec<f32>) -> Result<usize, IvfError> {
+        let key = self.next_key.ok_or(IvfError::KeysExhausted)?;
+        self.insert_with_key(key, vector)
+    }
+
+    pub fn insert_with_key(&mut self, key: u64, mut vector: Vec<f32>) -> Result<usize, IvfError> {
+        if !self.is_trained() {
+            return Err(IvfError::NotTrained);
+        }
+        self.check_dimension(&vector)?;
+        if self.key_to_id.contains_key(&key) {
+            return Err(IvfError::DuplicateKey(key));
+        }
+        self.distance.prepare(&mut vector);
+        let list = self.nearest_list(&vector);
+        Ok(self.push(key, &vector, list))
+    }
+
+    /// Bulk insert under consecutive keys; list assignment runs on the rayon pool.
+    pub fn insert_parallel(&mut self, mut vectors: Vec<Vec<f32>>) -> Result<(), IvfError> {
+        if !self.is_trained() {
+            return Err(IvfError::NotTrained);
+        }
+        for vector in &vectors {
+            self.check_dimension(vector)?;
+        }
+        let keys = auto_keys(self.next_key, vectors.len()).ok_or(IvfError::KeysExhausted)?;
+        let lists: Vec<usize> = vectors
+            .par_iter_mut()
+            .map(|vector| {
+                self.distance.prepare(vector);
+                self.nearest_list(vector)
+            })
+            .collect();
+        self.vectors.reserve(vectors.len() * self.config.dimension);
+        for ((vector, list), key) in vectors.iter().zip(lists).zip(keys) {
+            self.push(key, vector, list);
+        }
+        Ok(())
+    }
+
+    fn check_dimension(&self, vector: &[f32]) -> Result<(), IvfError> {
+        if vector.len() != self.config.dimension {
+            return Err(IvfError::DimensionMismatch { expected: self.config.dimension, actual: vector.len() });
+        }
+        Ok(())
+    }
+
+    fn push(&mut self, key: u64, vector: &[f32], list: usize) -> usize {
+        let id = self.keys.len();
+        self.vectors.extend_from_slice(vector);
+        self.keys.push(key);
+        self.lists[list].push(id as u32);
+        self.key_to_id.insert(key, id);
+        self.next_key = next_key_after(self.next_key, key);
+        id
+    }
+
+    fn nearest_list(&self, vector: &[f32]) -> usize {
+        nearest(&self.centroids, vector, &self.distance)
+    }
+
+    /// Exact search within the `nprobe` closest lists.
+    /// Returns `(key, distance)` pairs, closest first.
+    pub fn search(&self, query: &[f32], k: usize, nprobe: usize) -> Vec<(u64, f32)> {
+        if !self.is_trained() || k == 0 {
+            return Vec::new();
+        }
+        let mut query = query.to_vec();
+        self.distance.prepare(&mut query);
+
+        let mut probes = Vec::new();
+        probe_lists(&self.distance, &self.centroids, &query, nprobe, &mut probes);
+        let mut heap = BinaryHeap::with_capacity(k + 1);
+        for &(list, _) in &probes {
+            for &id in &self.lists[list] {
+                let id = id as usize;
+                push_bounded(&mut heap, Candidate { distance: self.distance.distance(&query, self.vector(id)), node_id: id }, k);
+            }
+        }
+        heap.into_sorted_vec().into_iter().map(|c| (self.keys[c.node_id], c.distance)).collect()
+    }
+
+    /// Write the index for `MmapIvfIndex`: centroids, list offsets, then the u8 and f32 arenas,
+    /// keys and (L2) norms, all in list order. Quantization matches `HNSW::save`.
+    pub fn save(&self, path: &std::path::Path) -> std::io::Result<()> {
+        use crate::storage::format::{IvfHeader, IVF_FORMAT_VERSION, IVF_MAGIC};
+        use bytemuck::{bytes_of, Zeroable};
+        use std::io::{Seek, SeekFrom, Write};
+
+        if !self.is_trained() {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, IvfError::NotTrained));
+        }
+        let dim = self.config.dimension;
+        let order: Vec<usize> = self.lists.iter().flatten().map(|&id| id as usize).collect();
+        let mut list_offsets = Vec::with_capacity(self.lists.len() + 1);
+        list_offsets.push(0u64);
+        for list in &self.lists {
+            list_offsets.push(list_offsets.last().unwrap() + list.len() as u64);
+        }
+
+        let norm = |v: &[f32]| v.iter().map(|x| x * x).sum::<f32>().sqrt();
+        let max_norm = match self.config.metric {
+            Metric::Cosine => 1.0,
+            _ => {
+                let max_norm = order.iter().map(|&id| norm(self.vector(id))).fold(0.0f32, f32::max);
+                if max_norm > f32::EPSILON { max_norm } else { 1.0 }
+            }
+        };
+
+        let mut header = IvfHeader::zeroed();
+        header.magic = IVF_MAGIC;
+        header.version = IVF_FORMAT_VERSION;
+        header.metric = self.config.metric as u32;
+        header.dimension = dim as u32;
+        header.nlist = self.config.nlist as u32;
+        header.num_elements = order.len() as u64;
+        header.max_norm = max_norm as f64;
+
+        let mut file = std::fs::File::create(path)?;
+        file.write_all(bytes_of(&header))?;
+        let mut out = SectionWriter {
+            out: std::io::BufWriter::new(file),
+            hasher: crc32fast::Hasher::new(),
+            pos: std::mem::size_of::<IvfHeader>(),
+        };
+
+        header.centroids_offset = out.align(32)?;
+        out.write(bytemuck::cast_slice(&self.centroids))?;
+
+        header.list_offsets_offset = out.align(8)?;
+        out.write(bytemuck::cast_slice(&list_offsets))?;
+
+        header.quantized_vectors_offset = out.align(32)?;
+        let inv_max_norm = 1.0 / max_norm;
+        let mut scaled = vec![0.0f32; dim];
+        for &id in &order {
+            for (s, &x) in scaled.iter_mut().zip(self.vector(id)) {
+                *s = x * inv_max_norm;
+            }
+            out.write(&Quantizer::quantize_u8(&scaled))?;
+        }
+
+        header.vectors_offset = out.align(32)?;
+        for &id in &order {
+            out.write(bytemuck::cast_slice(self.vector(id)))?;
+        }
+
+        header.keys_offset = out.align(8)?;
+        for &id in &order {
+            out.write(bytes_of(&self.keys[id]))?;
+        }
+
+        if self.config.metric == Metric::L2 {
+            header.norms_offset = out.align(4)?;
+            for &id in &order {
+                out.write(bytes_of(&(norm(self.vector(id)) / max_norm).powi(2)))?;
+            }
+        }
+
+        header.checksum = out.hasher.finalize() as u64;
+        let mut file = out.out.into_inner()?;
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(bytes_of(&header))?;
+        Ok(())
+    }
+}
+
+/// Sequential writer that checksums everything it writes and zero-pads sections into alignment.
+struct SectionWriter<W> {
+    out: W,
+    hasher: crc32fast::Hasher,
+    pos: usize,
+}
+
+impl<W: std::io::Write> SectionWriter<W> {
+    fn write(&mut self, bytes: &[u8]) -> std::io::Result<()> {
+        self.out.write_all(bytes)?;
+        self.hasher.update(bytes);
+        self.pos += bytes.len();
+        Ok(())
+    }
+
+    /// Pad to `alignment` and return the offset of the next section.
+    fn align(&mut self, alignment: usize) -> std::io::Result<u64> {
+        let pad = self.pos.next_multiple_of(alignment) - self.pos;
+        self.write(&[0u8; 64][..pad])?;
+        Ok(self.pos as u64)
+    }
+}
+
+/// The `nprobe` lists whose centroids are closest to the (prepared) query, closest first,
+/// as `(list, distance)` pairs in `out` (cleared first).
+pub(crate) fn probe_lists(distance: &impl Distance, centroids: &[f32], query: &[f32], nprobe: usize, out: &mut Vec<(usize, f32)>) {
+    out.clear();
+    out.extend(centroids.chunks_exact(query.len()).map(|centroid| distance.distance(query, centroid)).enumerate());
+    let nprobe = nprobe.clamp(1, out.len().max(1));
+    if nprobe < out.len() {
+        out.select_nth_unstable_by(nprobe - 1, |a, b| a.1.partial_cmp(&b.1).unwrap());
+        out.truncate(nprobe);
+    }
+    out.sort_unstable_by(|a, b| a.1.partial_cmp(&b.1).unwrap());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::flat::FlatIndex;
+
+    fn random_data(n: usize, dim: usize) -> Vec<Vec<f32>> {
+        use rand::Rng;
+        let mut rng = rand::thread_rng();
+        (0..n).map(|_| (0..dim).map(|_| rng.gen_range(-1.0f32..1.0)).collect()).collect()
+    }
+
+    #[test]
+    fn test_train_and_assign() -> Result<(), Box<dyn std::error::Error>> {
+        let config = IvfConfig::builder(8).nlist(16).seed(7).build()?;
+        let data = random_data(2000, 8);
+
+        assert!(matches!(IvfIndex::new(IvfConfig { nlist: 0, ..config }), Err(IvfError::InvalidConfig(_))));
+        assert!(matches!(IvfIndex::new(IvfConfig { train_iters: 0, ..config }), Err(IvfError::InvalidConfig(_))));
+        let mut index = IvfIndex::new(config)?;
+        assert!(matches!(index.insert(data[0].clone()), Err(IvfError::NotTrained)));
+        assert!(matches!(index.train(&data[..8]), Err(IvfError::InvalidConfig(_))));
+        index.train(&data)?;
+        index.insert_parallel(data.clone())?;
+        assert!(matches!(index.train(&data), Err(IvfError::InvalidConfig(_))));
+
+        // Every vector sits in exactly one list: the one with the nearest centroid
+        let sizes = index.list_sizes();
+        assert_eq!(sizes.iter().sum::<usize>(), data.len());
+        assert!(sizes.iter().all(|&size| size > 0), "{:?}", sizes);
+        for (list, ids) in index.lists.iter().enumerate() {
+            for &id in ids {
+                assert_eq!(index.nearest_list(index.vector(id as usize)), list);
+            }
+        }
+
+        // Same seed, same centroids
+        let mut again = IvfIndex::new(config)?;
+        again.train(&data)?;
+        assert_eq!(again.centroids, index.centroids);
+
+        // No automatic keys past u64::MAX
+        again.insert_with_key(u64::MAX - 1, data[0].clone())?;
+        assert!(matches!(again.insert_parallel(data[1..3].to_vec()), Err(IvfError::KeysExhausted)));
+        again.insert(data[1].clone())?;
+        assert!(matches!(again.insert(data[2].clone()), Err(IvfError::KeysExhausted)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_search_probes_lists() -> Result<(), Box<dyn std::error::Error>> {
+        let data = random_data(3000, 16);
+        let queries = random_data(20, 16);
+
+        for metric in [Metric::L2, Metric::InnerProduct, Metric::Cosine] {
+            let mut index = IvfIndex::new(IvfConfig::builder(16).nlist(32).metric(metric).build()?)?;
+            index.train(&data)?;
+            index.insert_parallel(data.clone())?;
+            let mut flat = FlatIndex::new(16, metric);
+            for v in &data {
+                flat.insert(v.clone())?;
+            }
+
+            let mut hits = 0;
+            for query in &queries {
+                // Probing every list is exhaustive
+                let exact = flat.search(query, 10);
+                let all = index.search(query, 10, 32);
+                assert_eq!(all.iter().map(|r| r.0).collect::<Vec<_>>(), exact.iter().map(|r| r.0).collect::<Vec<_>>(), "{:?}", metric);
+
+                let probed = index.search(query, 10, 8);
+                hits += probed.iter().filter(|r| exact.contains(r)).count();
+            }
+            let recall = hits as f32 / (queries.len() * 10) as f32;
+            assert!(recall >= 0.6, "{:?} recall at nprobe 8: {}", metric, recall);
+        }
+
+        Ok(())
+    }
+}
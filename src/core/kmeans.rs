@@ -0,0 +1,54 @@
+use crate::core::distance::Distance;
+use rand::rngs::StdRng;
+use rand::Rng;
+use rayon::prelude::*;
+
+/// Lloyd's k-means over `data` (rows of `dim` floats, at least `k` of them).
+/// Returns `k * dim` centroids. Initial centroids are distinct rows drawn from `rng`; a cluster
+/// that empties is re-seeded from a random row. Every centroid goes through `distance.prepare`,
+/// so cosine runs spherical k-means.
+pub(crate) fn kmeans(data: &[f32], dim: usize, k: usize, iters: usize, rng: &mut StdRng, distance: &impl Distance) -> Vec<f32> {
+    let n = data.len() / dim;
+    let row = |i: usize| &data[i * dim..(i + 1) * dim];
+
+    let mut centroids = Vec::with_capacity(k * dim);
+    for i in rand::seq::index::sample(rng, n, k) {
+        centroids.extend_from_slice(row(i));
+    }
+
+    let mut assignment = vec![0usize; n];
+    for _ in 0..iters {
+        assignment.par_iter_mut().enumerate().for_each(|(i, cluster)| *cluster = nearest(&centroids, row(i), distance));
+
+        let mut sums = vec![0.0f32; k * dim];
+        let mut counts = vec![0usize; k];
+        for (i, &cluster) in assignment.iter().enumerate() {
+            counts[cluster] += 1;
+            for (s, &x) in sums[cluster * dim..(cluster + 1) * dim].iter_mut().zip(row(i)) {
+                *s += x;
+            }
+        }
+        for (cluster, centroid) in centroids.chunks_exact_mut(dim).enumerate() {
+            if counts[cluster] == 0 {
+                centroid.copy_from_slice(row(rng.gen_range(0..n)));
+                continue;
+            }
+            let inv = 1.0 / counts[cluster] as f32;
+            for (c, &s) in centroid.iter_mut().zip(&sums[cluster * dim..(cluster + 1) * dim]) {
+                *c = s * inv;
+            }
+            distance.prepare(centroid);
+        }
+    }
+    centroids
+}
+
+/// Index of the centroid closest to `vector`.
+pub(crate) fn nearest(centroids: &[f32], vector: &[f32], distance: &impl Distance) -> usize {
+    centroids
+        .chunks_exact(vector.len())
+        .map(|centroid| distance.distance(vector, centroid))
+        .enumerate()
+        .min_by(|a, b| a.1.partial_cmp(&b.1).unwrap())
+        .map_or(0, |(cluster, _)| cluster)
+}
@@ -1,4 +1,12 @@
 pub mod hnsw;
+pub mod config;
+pub mod search;
 pub mod quantization;
 pub mod hardware;
 pub mod runtime;
+pub mod diagnostics;
+pub mod distance;
+pub mod flat;
+pub mod ivf;
+pub mod kmeans;
+pub mod pq;
@@ -0,0 +1,177 @@
+use crate::core::config::Metric;
+use crate::core::distance::MetricDistance;
+use crate::core::hnsw::HnswError;
+use crate::core::kmeans::{kmeans, nearest};
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+
+/// Centroids per sub-codebook: one code byte per subspace.
+pub const PQ_CENTROIDS: usize = 256;
+/// Vectors sampled to train the codebooks; more adds training time, not accuracy.
+pub const PQ_TRAIN_SAMPLES: usize = 32_768;
+/// Lloyd iterations per sub-codebook.
+pub const PQ_TRAIN_ITERS: usize = 10;
+
+/// Product quantizer: the vector is split into `subspaces` equal slices and each slice is
+/// replaced by the index of its nearest centroid in that slice's codebook, so a vector costs
+/// `subspaces` bytes. Queries are scored asymmetrically (ADC): the query stays in f32 and a
+/// per-query table holds its distance to every centroid, so a code scores in `subspaces` lookups.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProductQuantizer {
+    dimension: usize,
+    subspaces: usize,
+    /// `[subspace][centroid][sub_dimension]`.
+    codebooks: Vec<f32>,
+}
+
+impl ProductQuantizer {
+    /// Train on `samples` (rows of `dimension` floats) with k-means under L2 per subspace.
+    /// With fewer than `PQ_CENTROIDS` samples the codebooks repeat centroids.
+    pub fn train(dimension: usize, subspaces: usize, samples: &[f32], seed: Option<u64>) -> Result<Self, HnswError> {
+        if subspaces == 0 || !dimension.is_multiple_of(subspaces) {
+            return Err(HnswError::InvalidConfig(format!("PQ subspaces ({}) must divide the dimension ({})", subspaces, dimension)));
+        }
+        let n = samples.len() / dimension;
+        if n == 0 {
+            return Err(HnswError::InvalidConfig("PQ needs at least one training vector".to_string()));
+        }
+
+        let mut rng = match seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+        let rows: Vec<usize> = if n > PQ_TRAIN_SAMPLES {
+            rand::seq::index::sample(&mut rng, n, PQ_TRAIN_SAMPLES).into_vec()
+        } else {
+            (0..n).collect()
+        };
+
+        let dsub = dimension / subspaces;
+        let k = PQ_CENTROIDS.min(rows.len());
+        let l2 = MetricDistance::new(Metric::L2);
+        let mut codebooks = Vec::with_capacity(subspaces * PQ_CENTROIDS * dsub);
+        let mut slab = Vec::with_capacity(rows.len() * dsub);
+        for s in 0..subspaces {
+            slab.clear();
+            for &row in &rows {
+                slab.extend_from_slice(&samples[row * dimension + s * dsub..row * dimension + (s + 1) * dsub]);
+            }
+            let centroids = kmeans(&slab, dsub, k, PQ_TRAIN_ITERS, &mut rng, &l2);
+            codebooks.extend(centroids.iter().cycle().take(PQ_CENTROIDS * dsub));
+        }
+        Ok(Self { dimension, subspaces, codebooks })
+    }
+
+    /// Rebuild from codebooks laid out like `codebooks()`, e.g. read back from a file.
+    pub fn from_codebooks(dimension: usize, subspaces: usize, codebooks: Vec<f32>) -> Self {
+        assert_eq!(codebooks.len(), dimension * PQ_CENTROIDS);
+        Self { dimension, subspaces, codebooks }
+    }
+
+    pub fn subspaces(&self) -> usize {
+        self.subspaces
+    }
+
+    pub fn codebooks(&self) -> &[f32] {
+        &self.codebooks
+    }
+
+    fn centroid(&self, subspace: usize, code: u8) -> &[f32] {
+        let dsub = self.dimension / self.subspaces;
+        let start = (subspace * PQ_CENTROIDS + code as usize) * dsub;
+        &self.codebooks[start..start + dsub]
+    }
+
+    /// `subspaces` code bytes for `vector`.
+    pub fn encode(&self, vector: &[f32], code: &mut [u8]) {
+        let l2 = MetricDistance::new(Metric::L2);
+        let dsub = self.dimension / self.subspaces;
+        for (s, (slice, c)) in vector.chunks_exact(dsub).zip(code.iter_mut()).enumerate() {
+            let codebook = &self.codebooks[s * PQ_CENTROIDS * dsub..(s + 1) * PQ_CENTROIDS * dsub];
+            *c = nearest(codebook, slice, &l2) as u8;
+        }
+    }
+
+    /// Reconstruction of a code (the concatenated centroids).
+    pub fn decode(&self, code: &[u8], vector: &mut [f32]) {
+        let dsub = self.dimension / self.subspaces;
+        for (s, (slice, &c)) in vector.chunks_exact_mut(dsub).zip(code).enumerate() {
+            slice.copy_from_slice(self.centroid(s, c));
+        }
+    }
+
+    /// Per-query ADC table (cleared first): entry `[subspace * 256 + code]` is the query slice's
+    /// partial distance to that centroid, squared L2 for `Metric::L2` and the negated dot product
+    /// otherwise. `adc` sums them, which ranks codes like the metric ranks their reconstructions.
+    pub fn distance_table(&self, query: &[f32], metric: Metric, table: &mut Vec<f32>) {
+        let dsub = self.dimension / self.subspaces;
+        table.clear();
+        for (s, slice) in query.chunks_exact(dsub).enumerate() {
+            for centroid in self.codebooks[s * PQ_CENTROIDS * dsub..(s + 1) * PQ_CENTROIDS * dsub].chunks_exact(dsub) {
+                let partial = match metric {
+                    Metric::L2 => slice.iter().zip(centroid).map(|(q, c)| (q - c) * (q - c)).sum::<f32>(),
+                    Metric::InnerProduct | Metric::Cosine => -slice.iter().zip(centroid).map(|(q, c)| q * c).sum::<f32>(),
+                };
+                table.push(partial);
+            }
+        }
+    }
+
+    /// Stage-1 score of a code from a `distance_table`, lower is closer.
+    #[inline]
+    pub fn adc(&self, table: &[f32], code: &[u8]) -> f32 {
+        code.iter().enumerate().map(|(s, &c)| table[s * PQ_CENTROIDS + c as usize]).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::distance::Distance;
+    use rand::Rng;
+
+    #[test]
+    fn test_pq_roundtrip_and_adc() -> Result<(), Box<dyn std::error::Error>> {
+        let mut rng = rand::thread_rng();
+        let data: Vec<f32> = (0..1000 * 16).map(|_| rng.gen_range(-1.0f32..1.0)).collect();
+        assert!(ProductQuantizer::train(16, 3, &data, Some(1)).is_err());
+
+        let pq = ProductQuantizer::train(16, 8, &data, Some(1))?;
+        assert_eq!(pq, ProductQuantizer::train(16, 8, &data, Some(1))?);
+
+        // Reconstruction is far closer than a random vector from the same distribution
+        let (mut code, mut decoded) = (vec![0u8; 8], vec![0.0f32; 16]);
+        let (mut error, mut baseline) = (0.0, 0.0);
+        for (v, other) in data.chunks_exact(16).zip(data.chunks_exact(16).skip(1)).take(200) {
+            pq.encode(v, &mut code);
+            pq.decode(&code, &mut decoded);
+            error += v.iter().zip(&decoded).map(|(a, b)| (a - b) * (a - b)).sum::<f32>();
+            baseline += v.iter().zip(other).map(|(a, b)| (a - b) * (a - b)).sum::<f32>();
+        }
+        assert!(error < baseline * 0.2, "error {} vs baseline {}", error, baseline);
+
+        // ADC equals the metric against the reconstruction
+        let query: Vec<f32> = (0..16).map(|_| rng.gen_range(-1.0f32..1.0)).collect();
+        let mut table = Vec::new();
+        for metric in [Metric::L2, Metric::InnerProduct] {
+            pq.distance_table(&query, metric, &mut table);
+            for v in data.chunks_exact(16).take(50) {
+                pq.encode(v, &mut code);
+                pq.decode(&code, &mut decoded);
+                let expected = match metric {
+                    Metric::L2 => MetricDistance::new(Metric::L2).distance(&query, &decoded).powi(2),
+                    _ => MetricDistance::new(Metric::InnerProduct).distance(&query, &decoded),
+                };
+                assert!((pq.adc(&table, &code) - expected).abs() < 1e-4, "{:?}", metric);
+            }
+        }
+
+        // Too few samples for a full codebook still yields valid codes
+        let small = ProductQuantizer::train(16, 4, &data[..10 * 16], Some(2))?;
+        small.encode(&data[..16], &mut code[..4]);
+        small.decode(&code[..4], &mut decoded);
+        assert_eq!(decoded, &data[..16]);
+
+        Ok(())
+    }
+}
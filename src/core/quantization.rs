@@ -1,3 +1,5 @@
+use crate::simd::{Int8DotFunc, Kernels};
+
 /// Quantization Logic for Vector Engine
 ///
 /// Implements:
@@ -19,7 +21,7 @@ impl Quantizer {
             sum_sq += val * val;
         }
         
-        if sum_sq > std::f32::EPSILON {
+        if sum_sq > f32::EPSILON {
             let inv_norm = 1.0 / sum_sq.sqrt();
             for val in vector.iter_mut() {
                 *val *= inv_norm;
@@ -35,7 +37,7 @@ impl Quantizer {
         let mut quantized = Vec::with_capacity(vector.len());
         for &val in vector {
             // Clamp to -1.0..1.0 just in case
-            let clamped = val.max(-1.0).min(1.0);
+            let clamped = val.clamp(-1.0, 1.0);
             // Map to 0..255
             let scaled = (clamped + 1.0) * 127.5;
             quantized.push(scaled as u8);
@@ -43,20 +45,220 @@ impl Quantizer {
         quantized
     }
 
+    /// Quantize a vector with components in [-1.0, 1.0] to 4 bits, two codes per byte
+    /// (dimension 2j in the low nibble of byte j). We map [-1.0, 1.0] -> [0, 15], rounding.
+    /// Formula: u4 = round((val + 1.0) * 7.5)
+    pub fn quantize_u4(vector: &[f32]) -> Vec<u8> {
+        let mut packed = vec![0u8; vector.len().div_ceil(2)];
+        for (i, &val) in vector.iter().enumerate() {
+            let code = ((val.clamp(-1.0, 1.0) + 1.0) * 7.5).round() as u8;
+            packed[i / 2] |= code << ((i % 2) * 4);
+        }
+        packed
+    }
+
     /// Prepare a query vector: Normalize -> I8 Quantize
     /// We map [-1.0, 1.0] -> [-127, 127]
-    /// This is needed for `maddubs` (u8 * i8)
     /// Formula: i8 = val * 127.0
     pub fn quantize_query(vector: &[f32]) -> Vec<i8> {
-        let mut normalized = vector.to_vec();
-        Self::l2_normalize(&mut normalized);
-        
-        let mut quantized = Vec::with_capacity(normalized.len());
-        for val in normalized {
-             let clamped = val.max(-1.0).min(1.0);
-             let scaled = clamped * 127.0;
-             quantized.push(scaled as i8);
-        }
+        let mut quantized = Vec::with_capacity(vector.len());
+        Self::quantize_query_into(vector, &mut quantized);
         quantized
     }
+
+    /// `quantize_query` into a reused buffer (cleared first), for allocation-free search loops.
+    pub fn quantize_query_into(vector: &[f32], out: &mut Vec<i8>) {
+        let mut sum_sq = 0.0;
+        for &val in vector {
+            sum_sq += val * val;
+        }
+        let inv_norm = if sum_sq > f32::EPSILON { 1.0 / sum_sq.sqrt() } else { 1.0 };
+
+        out.clear();
+        out.extend(vector.iter().map(|&val| ((val * inv_norm).clamp(-1.0, 1.0) * 127.0) as i8));
+    }
+}
+
+/// Rows sampled to train a `TrainedQuantizer`.
+pub const SQ_TRAIN_SAMPLES: usize = 32_768;
+/// Fraction of each dimension's values clipped at either end, so outliers do not stretch the range.
+pub const SQ_CLIP_QUANTILE: f32 = 0.001;
+
+/// Scalar quantizer with a learned range per dimension: component i maps
+/// [lower_i, lower_i + 255 * step_i] onto 0..255 instead of the fixed [-1, 1] of `Quantizer::quantize_u8`,
+/// so narrow embedding components (e.g. +-0.1) still use all 256 levels.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TrainedQuantizer {
+    lower: Vec<f32>,
+    step: Vec<f32>,
+}
+
+impl TrainedQuantizer {
+    /// Learn the per-dimension range from `samples` (rows of `dimension` floats), between the
+    /// `SQ_CLIP_QUANTILE` and `1 - SQ_CLIP_QUANTILE` quantiles of each dimension.
+    pub fn train(dimension: usize, samples: &[f32]) -> Self {
+        let n = samples.len() / dimension;
+        let stride = n.div_ceil(SQ_TRAIN_SAMPLES).max(1);
+        let rows: Vec<&[f32]> = samples.chunks_exact(dimension).step_by(stride).collect();
+
+        let mut lower = Vec::with_capacity(dimension);
+        let mut step = Vec::with_capacity(dimension);
+        let mut column = Vec::with_capacity(rows.len());
+        for d in 0..dimension {
+            column.clear();
+            column.extend(rows.iter().map(|row| row[d]));
+            let (lo, hi) = if column.is_empty() {
+                (-1.0, 1.0)
+            } else {
+                let last = column.len() - 1;
+                let lo_rank = (last as f32 * SQ_CLIP_QUANTILE) as usize;
+                let hi_rank = last - lo_rank;
+                let lo = *column.select_nth_unstable_by(lo_rank, f32::total_cmp).1;
+                let hi = *column.select_nth_unstable_by(hi_rank, f32::total_cmp).1;
+                (lo, hi)
+            };
+            lower.push(lo);
+            step.push(((hi - lo) / 255.0).max(f32::EPSILON));
+        }
+        Self { lower, step }
+    }
+
+    /// Rebuild from parameters laid out like `lower()` and `step()`, e.g. read back from a file.
+    pub fn from_params(lower: Vec<f32>, step: Vec<f32>) -> Self {
+        assert_eq!(lower.len(), step.len());
+        Self { lower, step }
+    }
+
+    pub fn lower(&self) -> &[f32] {
+        &self.lower
+    }
+
+    pub fn step(&self) -> &[f32] {
+        &self.step
+    }
+
+    /// u8 code per component: round((x - lower) / step), clamped to 0..255.
+    pub fn quantize_u8(&self, vector: &[f32]) -> Vec<u8> {
+        vector
+            .iter()
+            .zip(self.lower.iter().zip(&self.step))
+            .map(|(&x, (&lo, &step))| ((x - lo) / step).round().clamp(0.0, 255.0) as u8)
+            .collect()
+    }
+
+    /// Query side of `quantize_u8`, into a reused buffer (cleared first).
+    /// Since x_i ~ lower_i + step_i * u_i, q . x ~ offset + factor * sum(q8_i * u_i) with the
+    /// returned `(factor, offset)`. The i8 query is q_i * step_i scaled to at most 127 in magnitude.
+    pub fn quantize_query_into(&self, query: &[f32], out: &mut Vec<i8>) -> (f32, f32) {
+        let offset = query.iter().zip(&self.lower).map(|(q, lo)| q * lo).sum();
+        let max = query.iter().zip(&self.step).map(|(q, step)| (q * step).abs()).fold(0.0f32, f32::max);
+        let scale = if max > 0.0 { 127.0 / max } else { 0.0 };
+
+        out.clear();
+        out.extend(query.iter().zip(&self.step).map(|(q, step)| (q * step * scale).round() as i8));
+        (if max > 0.0 { max / 127.0 } else { 0.0 }, offset)
+    }
+}
+
+/// Stage-1 score of a u8 (or packed u4) arena vector against a query from `Quantizer::quantize_query`,
+/// lower is closer. The arena holds x' = x / max_norm, the query is the unit q^ = q / |q|.
+/// Cosine and inner product rank by -(q^ . x') straight from the kernel.
+/// L2 ranks by max_norm * |x'|^2 - 2 |q| (q^ . x'), i.e. |q - x|^2 / max_norm up to a per-query constant.
+/// With a `TrainedQuantizer` arena the query is not normalized: q . x' comes from its
+/// `(factor, offset)` and the same two forms apply.
+#[derive(Clone, Copy)]
+pub(crate) struct QuantizedScorer {
+    kernel: Int8DotFunc,
+    form: ScoreForm,
+}
+
+#[derive(Clone, Copy)]
+enum ScoreForm {
+    Dot,
+    /// `(max_norm, |q|, sum of the i8 query, half the code range)`.
+    L2(f32, f32, f32, f32),
+    /// `(factor, offset)` from `TrainedQuantizer::quantize_query_into`, and `max_norm` for L2.
+    Trained(f32, f32, Option<f32>),
+}
+
+impl QuantizedScorer {
+    /// `l2` selects the L2 form, which needs the squared norms written next to an L2 arena.
+    pub(crate) fn new(kernels: &Kernels, l2: bool, max_norm: f32, query: &[f32], query_i8: &[i8]) -> Self {
+        Self::fixed_range(kernels.int8_dot, 127.5, l2, max_norm, query, query_i8)
+    }
+
+    /// `new` for an arena of `Quantizer::quantize_u4` codes.
+    pub(crate) fn int4(kernels: &Kernels, l2: bool, max_norm: f32, query: &[f32], query_i8: &[i8]) -> Self {
+        Self::fixed_range(kernels.int4_dot, 7.5, l2, max_norm, query, query_i8)
+    }
+
+    fn fixed_range(kernel: Int8DotFunc, half_range: f32, l2: bool, max_norm: f32, query: &[f32], query_i8: &[i8]) -> Self {
+        let form = if l2 {
+            let q_norm = query.iter().map(|x| x * x).sum::<f32>().sqrt();
+            let q_sum = query_i8.iter().map(|&q| q as i32).sum::<i32>() as f32;
+            ScoreForm::L2(max_norm, q_norm, q_sum, half_range)
+        } else {
+            ScoreForm::Dot
+        };
+        Self { kernel, form }
+    }
+
+    /// Scorer for a `TrainedQuantizer` arena; `(factor, offset)` is what quantizing the query returned.
+    pub(crate) fn trained(kernels: &Kernels, l2: bool, max_norm: f32, (factor, offset): (f32, f32)) -> Self {
+        Self { kernel: kernels.int8_dot, form: ScoreForm::Trained(factor, offset, l2.then_some(max_norm)) }
+    }
+
+    /// `norm` is |x'|^2 from the norms section, ignored unless L2.
+    #[inline]
+    pub(crate) fn score(&self, query_i8: &[i8], vector: &[u8], norm: f32) -> f32 {
+        // The kernel came from a `Kernels` resolved for this CPU
+        let raw = unsafe { (self.kernel)(query_i8, vector) };
+        match self.form {
+            ScoreForm::Dot => raw,
+            ScoreForm::L2(max_norm, q_norm, q_sum, half_range) => {
+                // Kernel output is -sum(u_i * q_i) with u_i = (x'_i + 1) * half_range and q_i = 127 * q^_i
+                let dot = (-raw / half_range - q_sum) / 127.0;
+                max_norm * norm - 2.0 * q_norm * dot
+            }
+            ScoreForm::Trained(factor, offset, l2) => {
+                let dot = offset - factor * raw;
+                match l2 {
+                    Some(max_norm) => max_norm * norm - 2.0 * dot,
+                    None => -dot,
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::Rng;
+
+    #[test]
+    fn test_trained_quantizer_transform() {
+        let mut rng = rand::thread_rng();
+        // Narrow, off-center components like normalized high-dimensional embeddings
+        let data: Vec<f32> = (0..500 * 32).map(|i| 0.05 * (i % 32) as f32 / 32.0 + rng.gen_range(-0.1f32..0.1)).collect();
+        let sq = TrainedQuantizer::train(32, &data);
+
+        let (mut codes, mut q8) = (Vec::new(), Vec::new());
+        for v in data.chunks_exact(32).take(50) {
+            codes.clear();
+            codes.extend(sq.quantize_u8(v));
+            // Reconstruction error is within half a step (clipped tails aside)
+            let reconstructed: Vec<f32> = codes.iter().zip(sq.lower().iter().zip(sq.step())).map(|(&u, (lo, step))| lo + step * u as f32).collect();
+            let error = v.iter().zip(&reconstructed).map(|(a, b)| (a - b).abs()).fold(0.0f32, f32::max);
+            assert!(error < 0.005, "error {}", error);
+
+            // The kernel dot product plus the returned transform approximates q . x
+            let query: Vec<f32> = (0..32).map(|_| rng.gen_range(-1.0f32..1.0)).collect();
+            let (factor, offset) = sq.quantize_query_into(&query, &mut q8);
+            assert!(q8.iter().all(|&q| q != i8::MIN));
+            let approx = offset - factor * crate::simd::int8::dot_product_u8_scalar(&q8, &codes);
+            let exact: f32 = query.iter().zip(v).map(|(a, b)| a * b).sum();
+            assert!((approx - exact).abs() < 0.02, "{} vs {}", approx, exact);
+        }
+    }
 }
@@ -25,7 +25,7 @@ impl RuntimeConfig {
 
     /// Configure Rayon Thread Pool with Pinning
     pub fn init_rayon_pool() -> Result<(), rayon::ThreadPoolBuildError> {
-         let core_ids = core_affinity::get_core_ids().unwrap_or_else(Vec::new);
+         let core_ids = core_affinity::get_core_ids().unwrap_or_default();
          if core_ids.is_empty() {
              return Ok(());
          }
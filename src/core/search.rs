@@ -0,0 +1,193 @@
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+
+/// Query-time parameters shared by the in-memory `HNSW` and the mmap search paths.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SearchParams {
+    /// Beam width of the layer-0 search (candidate list size), or for `MmapIvfIndex` the number
+    /// of quantized candidates its scan keeps. Raised to `k` if smaller.
+    /// Higher = better recall, lower = faster.
+    pub ef: usize,
+    /// Which arenas an `MmapIndex` search scores with. Ignored by the in-memory `HNSW` and `MmapIvfIndex`.
+    pub cascade: Cascade,
+    /// How many candidates an `MmapIndex` or `MmapIvfIndex` search rescores in full precision.
+    /// Ignored by the in-memory `HNSW`.
+    pub rerank: Rerank,
+}
+
+impl SearchParams {
+    pub fn new(ef: usize) -> Self {
+        Self { ef, cascade: Cascade::default(), rerank: Rerank::default() }
+    }
+
+    pub fn with_cascade(self, cascade: Cascade) -> Self {
+        Self { cascade, ..self }
+    }
+
+    pub fn with_rerank(self, rerank: Rerank) -> Self {
+        Self { rerank, ..self }
+    }
+}
+
+impl Default for SearchParams {
+    fn default() -> Self {
+        Self::new(64)
+    }
+}
+
+/// Scoring stages of an `MmapIndex` search, cheapest first. Every cascade ends with an
+/// f32 rerank of its survivors (unless the index is searched exactly with a custom distance).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Cascade {
+    /// Traverse on the stage-1 arena (u8 or PQ codes), rerank every candidate in f32.
+    #[default]
+    Quantized,
+    /// Traverse on the binary arena with Hamming distance, rescore the `ef` candidates on the
+    /// stage-1 arena, rerank the best `keep` of them in f32. Indexes saved without a binary
+    /// arena run `Quantized` instead.
+    Binary { keep: usize },
+}
+
+/// Full-precision rerank depth of an `MmapIndex` or `MmapIvfIndex` search.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Rerank {
+    /// Every candidate the cascade keeps (`ef`, or `keep` for `Cascade::Binary`).
+    #[default]
+    All,
+    /// Only the best `n` by stage-1 score (at least `k`), e.g. `4 * k`.
+    Top(usize),
+    /// None: results carry stage-1 scores, which rank like the metric but are not its distances.
+    /// Indexes without a full-precision arena always search this way.
+    Skip,
+}
+
+/// A node scored against the query.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Candidate {
+    pub(crate) distance: f32,
+    pub(crate) node_id: usize,
+}
+
+impl Eq for Candidate {}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Candidate {
+    // Natural ordering by distance: BinaryHeap<Candidate> is a MaxHeap (furthest on top),
+    // BinaryHeap<Reverse<Candidate>> is a MinHeap (closest on top).
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.distance.partial_cmp(&other.distance).unwrap_or(Ordering::Equal)
+    }
+}
+
+/// Push into a max-heap that keeps the `k` closest candidates, evicting the furthest.
+pub(crate) fn push_bounded(heap: &mut BinaryHeap<Candidate>, candidate: Candidate, k: usize) {
+    if heap.len() < k {
+        heap.push(candidate);
+    } else if heap.peek().is_some_and(|worst| candidate.distance < worst.distance) {
+        heap.pop();
+        heap.push(candidate);
+    }
+}
+
+/// Reusable buffers for graph searches: visited tags, both heaps, the prepared queries and the results.
+/// Create one per worker and pass it to `MmapIndex::search_two_stage_with`; once the buffers have
+/// grown to the working size a query does no heap allocation. Everything is freed when the
+/// context is dropped. A context can be used with any index.
+#[derive(Debug, Default)]
+pub struct SearchContext {
+    /// `visited[id] == epoch` marks a node seen by the current search.
+    pub(crate) visited: Vec<u32>,
+    pub(crate) epoch: u32,
+    /// Nodes to expand, closest first.
+    pub(crate) candidates: BinaryHeap<Reverse<Candidate>>,
+    /// Best `ef` results so far, furthest on top.
+    pub(crate) w: BinaryHeap<Candidate>,
+    /// Query after `Distance::prepare`.
+    pub(crate) query: Vec<f32>,
+    pub(crate) query_i8: Vec<i8>,
+    /// Sign bits of the query, for the binary arena.
+    pub(crate) query_bits: Vec<u64>,
+    /// A full-precision vector widened from a 16-bit arena.
+    pub(crate) decoded: Vec<f32>,
+    /// Per-query PQ distance table.
+    pub(crate) pq_table: Vec<f32>,
+    /// Reranked `(node, distance)` pairs.
+    pub(crate) scored: Vec<(usize, f32)>,
+    /// `(key, distance)` pairs handed back to the caller.
+    pub(crate) results: Vec<(u64, f32)>,
+}
+
+impl SearchContext {
+    pub const fn new() -> Self {
+        Self {
+            visited: Vec::new(),
+            epoch: 0,
+            candidates: BinaryHeap::new(),
+            w: BinaryHeap::new(),
+            query: Vec::new(),
+            query_i8: Vec::new(),
+            query_bits: Vec::new(),
+            decoded: Vec::new(),
+            pq_table: Vec::new(),
+            scored: Vec::new(),
+            results: Vec::new(),
+        }
+    }
+
+    /// Pre-size for an index of `num_nodes` nodes searched with beam width `ef`,
+    /// so even the first query does not allocate.
+    pub fn with_capacity(num_nodes: usize, ef: usize) -> Self {
+        Self {
+            visited: vec![0; num_nodes],
+            candidates: BinaryHeap::with_capacity(ef * 4),
+            w: BinaryHeap::with_capacity(ef + 1),
+            scored: Vec::with_capacity(ef),
+            results: Vec::with_capacity(ef),
+            ..Self::new()
+        }
+    }
+
+    /// Start a search over `num_nodes` nodes: a new epoch invalidates every visited tag at once
+    /// (tags are only cleared for real when the counter wraps).
+    pub(crate) fn reset(&mut self, num_nodes: usize) {
+        if self.visited.len() < num_nodes {
+            self.visited.resize(num_nodes, 0);
+        }
+        self.epoch = self.epoch.wrapping_add(1);
+        if self.epoch == 0 {
+            self.visited.fill(0);
+            self.epoch = 1;
+        }
+        self.candidates.clear();
+        self.w.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_context_epoch_wraparound() {
+        let mut ctx = SearchContext::new();
+        ctx.reset(4);
+        ctx.visited[2] = ctx.epoch;
+
+        // A new epoch invalidates old tags without touching them
+        ctx.reset(8);
+        assert_eq!(ctx.visited.len(), 8);
+        assert_ne!(ctx.visited[2], ctx.epoch);
+
+        // On wrap-around the tags are cleared so stale ones cannot collide with the restarted counter
+        ctx.epoch = u32::MAX;
+        ctx.visited[3] = 1;
+        ctx.reset(8);
+        assert_eq!(ctx.epoch, 1);
+        assert!(ctx.visited.iter().all(|&tag| tag == 0));
+    }
+}
@@ -1,6 +1,10 @@
 #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
 use std::arch::x86_64::*;
 
+/// Euclidean (L2) distance, 8 lanes at a time with FMA.
+///
+/// # Safety
+/// The CPU must support AVX2 and FMA, and `b` must be at least as long as `a`.
 #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
 #[target_feature(enable = "avx2", enable = "fma")]
 pub unsafe fn euclidean_distance_avx2(a: &[f32], b: &[f32]) -> f32 {
@@ -43,3 +47,35 @@ pub unsafe fn euclidean_distance_avx2(a: &[f32], b: &[f32]) -> f32 {
 
     sum.sqrt()
 }
+
+/// Dot product, 8 lanes at a time with FMA.
+///
+/// # Safety
+/// The CPU must support AVX2 and FMA, and `b` must be at least as long as `a`.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[target_feature(enable = "avx2", enable = "fma")]
+pub unsafe fn dot_product_avx2(a: &[f32], b: &[f32]) -> f32 {
+    let n = a.len();
+    let mut sum256 = _mm256_setzero_ps();
+    let mut i = 0;
+
+    while i + 8 <= n {
+        let a_vec = _mm256_loadu_ps(a.as_ptr().add(i));
+        let b_vec = _mm256_loadu_ps(b.as_ptr().add(i));
+        sum256 = _mm256_fmadd_ps(a_vec, b_vec, sum256);
+        i += 8;
+    }
+
+    // Same horizontal reduction as euclidean_distance_avx2
+    let sum128 = _mm_add_ps(_mm256_castps256_ps128(sum256), _mm256_extractf128_ps(sum256, 1));
+    let sum128 = _mm_hadd_ps(sum128, sum128);
+    let sum128 = _mm_hadd_ps(sum128, sum128);
+    let mut sum = _mm_cvtss_f32(sum128);
+
+    while i < n {
+        sum += a[i] * b[i];
+        i += 1;
+    }
+
+    sum
+}
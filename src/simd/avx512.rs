@@ -0,0 +1,146 @@
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use std::arch::x86_64::*;
+
+/// Euclidean (L2) distance, 16 lanes at a time with FMA. The tail is a masked load, so
+/// there is no scalar loop.
+///
+/// # Safety
+/// The CPU must support AVX-512F, and `b` must be at least as long as `a`.
+#[target_feature(enable = "avx512f")]
+pub unsafe fn euclidean_distance_avx512(a: &[f32], b: &[f32]) -> f32 {
+    let n = a.len();
+    let mut sum0 = _mm512_setzero_ps();
+    let mut sum1 = _mm512_setzero_ps();
+    let mut i = 0;
+
+    // Two accumulators hide the FMA latency
+    while i + 32 <= n {
+        let d0 = _mm512_sub_ps(_mm512_loadu_ps(a.as_ptr().add(i)), _mm512_loadu_ps(b.as_ptr().add(i)));
+        let d1 = _mm512_sub_ps(_mm512_loadu_ps(a.as_ptr().add(i + 16)), _mm512_loadu_ps(b.as_ptr().add(i + 16)));
+        sum0 = _mm512_fmadd_ps(d0, d0, sum0);
+        sum1 = _mm512_fmadd_ps(d1, d1, sum1);
+        i += 32;
+    }
+    while i < n {
+        let mask = tail_mask(n - i);
+        let d = _mm512_sub_ps(_mm512_maskz_loadu_ps(mask, a.as_ptr().add(i)), _mm512_maskz_loadu_ps(mask, b.as_ptr().add(i)));
+        sum0 = _mm512_fmadd_ps(d, d, sum0);
+        i += 16;
+    }
+
+    _mm512_reduce_add_ps(_mm512_add_ps(sum0, sum1)).sqrt()
+}
+
+/// Dot product, 16 lanes at a time with FMA and a masked tail.
+///
+/// # Safety
+/// The CPU must support AVX-512F, and `b` must be at least as long as `a`.
+#[target_feature(enable = "avx512f")]
+pub unsafe fn dot_product_avx512(a: &[f32], b: &[f32]) -> f32 {
+    let n = a.len();
+    let mut sum0 = _mm512_setzero_ps();
+    let mut sum1 = _mm512_setzero_ps();
+    let mut i = 0;
+
+    while i + 32 <= n {
+        sum0 = _mm512_fmadd_ps(_mm512_loadu_ps(a.as_ptr().add(i)), _mm512_loadu_ps(b.as_ptr().add(i)), sum0);
+        sum1 = _mm512_fmadd_ps(_mm512_loadu_ps(a.as_ptr().add(i + 16)), _mm512_loadu_ps(b.as_ptr().add(i + 16)), sum1);
+        i += 32;
+    }
+    while i < n {
+        let mask = tail_mask(n - i);
+        sum0 = _mm512_fmadd_ps(_mm512_maskz_loadu_ps(mask, a.as_ptr().add(i)), _mm512_maskz_loadu_ps(mask, b.as_ptr().add(i)), sum0);
+        i += 16;
+    }
+
+    _mm512_reduce_add_ps(_mm512_add_ps(sum0, sum1))
+}
+
+/// Integer Dot Product (AVX-512 VNNI)
+/// Input: Query (i8), Vector (u8)
+/// Logic: `vpdpbusd` multiplies 4 adjacent u8 * i8 pairs and adds them straight into an i32
+/// lane, so unlike `maddubs` there is no i16 intermediate to saturate. The tail is a masked
+/// byte load (AVX-512BW).
+///
+/// Returns: Negative Dot Product, like `dot_product_u8_avx2`.
+///
+/// # Safety
+/// The CPU must support AVX-512F, AVX-512BW and AVX-512 VNNI.
+#[target_feature(enable = "avx512f", enable = "avx512bw", enable = "avx512vnni")]
+pub unsafe fn dot_product_u8_vnni(q: &[i8], v: &[u8]) -> f32 {
+    let n = q.len();
+    assert_eq!(n, v.len());
+
+    let mut sum0 = _mm512_setzero_si512();
+    let mut sum1 = _mm512_setzero_si512();
+    let mut i = 0;
+    let ptr_q = q.as_ptr();
+    let ptr_v = v.as_ptr();
+
+    // Process 128 bytes (64 * 2 unroll) at a time
+    while i + 128 <= n {
+        let q0 = _mm512_loadu_si512(ptr_q.add(i) as *const _);
+        let v0 = _mm512_loadu_si512(ptr_v.add(i) as *const _);
+        let q1 = _mm512_loadu_si512(ptr_q.add(i + 64) as *const _);
+        let v1 = _mm512_loadu_si512(ptr_v.add(i + 64) as *const _);
+        sum0 = _mm512_dpbusd_epi32(sum0, v0, q0);
+        sum1 = _mm512_dpbusd_epi32(sum1, v1, q1);
+        i += 128;
+    }
+    while i < n {
+        let remaining = n - i;
+        let mask: __mmask64 = if remaining >= 64 { !0 } else { (1u64 << remaining) - 1 };
+        let q0 = _mm512_maskz_loadu_epi8(mask, ptr_q.add(i));
+        let v0 = _mm512_maskz_loadu_epi8(mask, ptr_v.add(i) as *const i8);
+        sum0 = _mm512_dpbusd_epi32(sum0, v0, q0);
+        i += 64;
+    }
+
+    -(_mm512_reduce_add_epi32(_mm512_add_epi32(sum0, sum1)) as f32)
+}
+
+/// Mask of the first `min(remaining, 16)` lanes.
+#[inline]
+fn tail_mask(remaining: usize) -> __mmask16 {
+    if remaining >= 16 { !0 } else { (1u16 << remaining) - 1 }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simd::distance;
+    use crate::simd::int8::dot_product_u8_scalar;
+    use rand::Rng;
+
+    #[test]
+    fn test_avx512_kernels_match_scalar() {
+        let mut rng = rand::thread_rng();
+        let f32_kernels = is_x86_feature_detected!("avx512f");
+        let vnni = f32_kernels && is_x86_feature_detected!("avx512bw") && is_x86_feature_detected!("avx512vnni");
+
+        // Lengths around the 16/32-lane and 64/128-byte blocks, including pure tails
+        for n in [0usize, 1, 15, 16, 17, 31, 32, 33, 63, 64, 65, 127, 128, 129, 200, 768] {
+            let a: Vec<f32> = (0..n).map(|_| rng.gen_range(-1.0f32..1.0)).collect();
+            let b: Vec<f32> = (0..n).map(|_| rng.gen_range(-1.0f32..1.0)).collect();
+            let q: Vec<i8> = (0..n).map(|_| rng.gen()).collect();
+            let v: Vec<u8> = (0..n).map(|_| rng.gen()).collect();
+
+            if f32_kernels {
+                let (l2, dot) = unsafe { (euclidean_distance_avx512(&a, &b), dot_product_avx512(&a, &b)) };
+                assert!((l2 - distance::euclidean_distance(&a, &b)).abs() < 1e-4, "n = {}", n);
+                assert!((dot - distance::dot_product(&a, &b)).abs() < 1e-4, "n = {}", n);
+            }
+            // Exact, saturating inputs included
+            if vnni {
+                assert_eq!(unsafe { dot_product_u8_vnni(&q, &v) }, dot_product_u8_scalar(&q, &v), "n = {}", n);
+                let (q, v) = (vec![i8::MIN; n], vec![u8::MAX; n]);
+                assert_eq!(unsafe { dot_product_u8_vnni(&q, &v) }, dot_product_u8_scalar(&q, &v));
+            }
+
+            // Whatever the dispatcher picks agrees with the scalar paths
+            let l2 = unsafe { crate::simd::get_euclidean_distance()(&a, &b) };
+            assert!((l2 - distance::euclidean_distance(&a, &b)).abs() < 1e-4);
+            assert_eq!(unsafe { crate::simd::get_int8_dot()(&q, &v) }, dot_product_u8_scalar(&q, &v));
+        }
+    }
+}
@@ -0,0 +1,99 @@
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use std::arch::x86_64::*;
+
+/// Pack the signs of `values - thresholds` into bits (bit i of word i / 64 = component i > threshold).
+/// `out` is cleared first and gets `values.len().div_ceil(64)` words.
+pub fn sign_bits_into(values: &[f32], thresholds: &[f32], out: &mut Vec<u64>) {
+    out.clear();
+    out.resize(values.len().div_ceil(64), 0);
+    for (i, (&v, &t)) in values.iter().zip(thresholds).enumerate() {
+        if v > t {
+            out[i / 64] |= 1 << (i % 64);
+        }
+    }
+}
+
+/// Hamming distance between two bit strings of equal length.
+pub fn hamming_scalar(a: &[u64], b: &[u64]) -> u32 {
+    assert_eq!(a.len(), b.len());
+    a.iter().zip(b).map(|(x, y)| (x ^ y).count_ones()).sum()
+}
+
+/// Hamming distance with the POPCNT instruction (one per word).
+///
+/// # Safety
+/// The CPU must support POPCNT.
+#[target_feature(enable = "popcnt")]
+pub unsafe fn hamming_popcnt(a: &[u64], b: &[u64]) -> u32 {
+    hamming_scalar(a, b)
+}
+
+/// Hamming distance (AVX2)
+/// Logic: XOR 256 bits at a time, popcount each byte with two `pshufb` nibble lookups,
+/// then `_mm256_sad_epu8` sums the byte counts into four u64 lanes. Tail words use POPCNT.
+///
+/// # Safety
+/// The CPU must support AVX2 and POPCNT.
+#[target_feature(enable = "avx2", enable = "popcnt")]
+pub unsafe fn hamming_avx2(a: &[u64], b: &[u64]) -> u32 {
+    let n = a.len();
+    assert_eq!(n, b.len());
+
+    let lookup = _mm256_setr_epi8(
+        0, 1, 1, 2, 1, 2, 2, 3, 1, 2, 2, 3, 2, 3, 3, 4,
+        0, 1, 1, 2, 1, 2, 2, 3, 1, 2, 2, 3, 2, 3, 3, 4,
+    );
+    let low_mask = _mm256_set1_epi8(0x0f);
+    let mut acc = _mm256_setzero_si256();
+
+    let mut i = 0;
+    let ptr_a = a.as_ptr();
+    let ptr_b = b.as_ptr();
+    while i + 4 <= n {
+        let x = _mm256_xor_si256(
+            _mm256_loadu_si256(ptr_a.add(i) as *const _),
+            _mm256_loadu_si256(ptr_b.add(i) as *const _),
+        );
+        let lo = _mm256_shuffle_epi8(lookup, _mm256_and_si256(x, low_mask));
+        let hi = _mm256_shuffle_epi8(lookup, _mm256_and_si256(_mm256_srli_epi16(x, 4), low_mask));
+        acc = _mm256_add_epi64(acc, _mm256_sad_epu8(_mm256_add_epi8(lo, hi), _mm256_setzero_si256()));
+        i += 4;
+    }
+
+    let mut lanes = [0u64; 4];
+    _mm256_storeu_si256(lanes.as_mut_ptr() as *mut _, acc);
+    let mut sum = lanes.iter().sum::<u64>() as u32;
+    while i < n {
+        sum += (a[i] ^ b[i]).count_ones();
+        i += 1;
+    }
+    sum
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::Rng;
+
+    #[test]
+    fn test_hamming_kernels_agree() {
+        let mut rng = rand::thread_rng();
+        // Lengths around the 4-word AVX2 block, including pure tails
+        for words in [0, 1, 3, 4, 5, 16, 17] {
+            let a: Vec<u64> = (0..words).map(|_| rng.gen()).collect();
+            let b: Vec<u64> = (0..words).map(|_| rng.gen()).collect();
+            let expected = hamming_scalar(&a, &b);
+            assert_eq!(unsafe { crate::simd::get_hamming()(&a, &b) }, expected, "{} words", words);
+            if is_x86_feature_detected!("popcnt") {
+                assert_eq!(unsafe { hamming_popcnt(&a, &b) }, expected);
+            }
+            if is_x86_feature_detected!("avx2") && is_x86_feature_detected!("popcnt") {
+                assert_eq!(unsafe { hamming_avx2(&a, &b) }, expected);
+            }
+        }
+
+        let mut bits = Vec::new();
+        sign_bits_into(&[1.0, -1.0, 0.5, 0.2], &[0.0, 0.0, 0.0, 0.3], &mut bits);
+        assert_eq!(bits, vec![0b101]);
+    }
+}
@@ -14,3 +14,8 @@ pub fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
     let norm_b: f32 = b.iter().map(|x| x.powi(2)).sum::<f32>().sqrt();
     dot_product / (norm_a * norm_b)
 }
+
+pub fn dot_product(a: &[f32], b: &[f32]) -> f32 {
+    assert_eq!(a.len(), b.len());
+    a.iter().zip(b.iter()).map(|(x, y)| x * y).sum()
+}
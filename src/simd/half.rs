@@ -0,0 +1,194 @@
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use std::arch::x86_64::*;
+
+/// IEEE 754 half precision -> f32 (exact).
+pub fn f16_to_f32(h: u16) -> f32 {
+    let sign = ((h & 0x8000) as u32) << 16;
+    let exp = ((h >> 10) & 0x1f) as u32;
+    let mant = (h & 0x03ff) as u32;
+    let bits = match (exp, mant) {
+        (0, 0) => sign,
+        // Subnormal: mant * 2^-24, exactly representable in f32
+        (0, _) => {
+            let magnitude = mant as f32 / 16_777_216.0;
+            return if sign != 0 { -magnitude } else { magnitude };
+        }
+        (0x1f, 0) => sign | 0x7f80_0000,
+        (0x1f, _) => sign | 0x7fc0_0000 | (mant << 13),
+        _ => sign | ((exp + 112) << 23) | (mant << 13),
+    };
+    f32::from_bits(bits)
+}
+
+/// f32 -> IEEE 754 half precision, rounding to nearest even. Out-of-range values become infinity.
+pub fn f32_to_f16(x: f32) -> u16 {
+    let bits = x.to_bits();
+    let sign = ((bits >> 16) & 0x8000) as u16;
+    let exp = ((bits >> 23) & 0xff) as i32;
+    let mant = bits & 0x007f_ffff;
+
+    if exp == 0xff {
+        return sign | 0x7c00 | if mant != 0 { 0x0200 } else { 0 };
+    }
+    let half_exp = exp - 127 + 15;
+    if half_exp >= 0x1f {
+        return sign | 0x7c00;
+    }
+    if half_exp <= 0 {
+        // Subnormal (or zero): shift the implicit-one mantissa into place, round to nearest even
+        if half_exp < -10 {
+            return sign;
+        }
+        let full = mant | 0x0080_0000;
+        let shift = (14 - half_exp) as u32;
+        let mut half_mant = full >> shift;
+        let rem = full & ((1 << shift) - 1);
+        let halfway = 1 << (shift - 1);
+        if rem > halfway || (rem == halfway && half_mant & 1 == 1) {
+            half_mant += 1;
+        }
+        return sign | half_mant as u16;
+    }
+    let mut half = ((half_exp as u32) << 10) | (mant >> 13);
+    let rem = mant & 0x1fff;
+    if rem > 0x1000 || (rem == 0x1000 && half & 1 == 1) {
+        // A carry out of the mantissa bumps the exponent, up to infinity
+        half += 1;
+    }
+    sign | half as u16
+}
+
+/// bfloat16 -> f32 (exact: bf16 is the top half of an f32).
+pub fn bf16_to_f32(h: u16) -> f32 {
+    f32::from_bits((h as u32) << 16)
+}
+
+/// f32 -> bfloat16, rounding to nearest even. NaN stays NaN.
+pub fn f32_to_bf16(x: f32) -> u16 {
+    let bits = x.to_bits();
+    if x.is_nan() {
+        return ((bits >> 16) | 0x0040) as u16;
+    }
+    let round = 0x7fff + ((bits >> 16) & 1);
+    (bits.wrapping_add(round) >> 16) as u16
+}
+
+pub fn f16_to_f32_scalar(src: &[u16], dst: &mut [f32]) {
+    for (d, &h) in dst.iter_mut().zip(src) {
+        *d = f16_to_f32(h);
+    }
+}
+
+pub fn bf16_to_f32_scalar(src: &[u16], dst: &mut [f32]) {
+    for (d, &h) in dst.iter_mut().zip(src) {
+        *d = bf16_to_f32(h);
+    }
+}
+
+/// f16 -> f32, 8 lanes at a time with `vcvtph2ps`.
+///
+/// # Safety
+/// The CPU must support F16C and AVX.
+#[target_feature(enable = "f16c", enable = "avx")]
+pub unsafe fn f16_to_f32_f16c(src: &[u16], dst: &mut [f32]) {
+    let n = src.len();
+    assert_eq!(n, dst.len());
+    let mut i = 0;
+    while i + 8 <= n {
+        let h = _mm_loadu_si128(src.as_ptr().add(i) as *const _);
+        _mm256_storeu_ps(dst.as_mut_ptr().add(i), _mm256_cvtph_ps(h));
+        i += 8;
+    }
+    f16_to_f32_scalar(&src[i..], &mut dst[i..]);
+}
+
+/// f32 -> f16, 8 lanes at a time with `vcvtps2ph` (round to nearest even).
+///
+/// # Safety
+/// The CPU must support F16C and AVX.
+#[target_feature(enable = "f16c", enable = "avx")]
+pub unsafe fn f32_to_f16_f16c(src: &[f32], dst: &mut [u16]) {
+    let n = src.len();
+    assert_eq!(n, dst.len());
+    let mut i = 0;
+    while i + 8 <= n {
+        let x = _mm256_loadu_ps(src.as_ptr().add(i));
+        _mm_storeu_si128(dst.as_mut_ptr().add(i) as *mut _, _mm256_cvtps_ph(x, _MM_FROUND_TO_NEAREST_INT));
+        i += 8;
+    }
+    for (d, &x) in dst[i..].iter_mut().zip(&src[i..]) {
+        *d = f32_to_f16(x);
+    }
+}
+
+/// bf16 -> f32 (AVX2): zero-extend 8 u16 lanes to u32 and shift them into the high half.
+///
+/// # Safety
+/// The CPU must support AVX2.
+#[target_feature(enable = "avx2")]
+pub unsafe fn bf16_to_f32_avx2(src: &[u16], dst: &mut [f32]) {
+    let n = src.len();
+    assert_eq!(n, dst.len());
+    let mut i = 0;
+    while i + 8 <= n {
+        let h = _mm_loadu_si128(src.as_ptr().add(i) as *const _);
+        let widened = _mm256_slli_epi32(_mm256_cvtepu16_epi32(h), 16);
+        _mm256_storeu_ps(dst.as_mut_ptr().add(i), _mm256_castsi256_ps(widened));
+        i += 8;
+    }
+    bf16_to_f32_scalar(&src[i..], &mut dst[i..]);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::Rng;
+
+    #[test]
+    fn test_half_conversions() {
+        // Exact values, rounding ties to even, overflow, subnormals and specials
+        assert_eq!(f32_to_f16(1.0), 0x3c00);
+        assert_eq!(f32_to_f16(-2.5), 0xc100);
+        assert_eq!(f32_to_f16(1.0 + 1.0 / 2048.0), 0x3c00); // halfway, rounds down to even
+        assert_eq!(f32_to_f16(1.0 + 3.0 / 2048.0), 0x3c02); // halfway, rounds up to even
+        assert_eq!(f32_to_f16(65520.0), 0x7c00);
+        assert_eq!(f32_to_f16(5.960_464_5e-8), 0x0001);
+        assert!(f16_to_f32(f32_to_f16(f32::NAN)).is_nan());
+        assert_eq!(f16_to_f32(0x0001), 5.960_464_5e-8);
+        assert_eq!(f16_to_f32(0x8001), -5.960_464_5e-8);
+        assert_eq!(f16_to_f32(0xfc00), f32::NEG_INFINITY);
+        assert_eq!(f32_to_bf16(1.0), 0x3f80);
+        assert_eq!(f32_to_bf16(1.0 + 1.0 / 256.0), 0x3f80);
+        assert_eq!(bf16_to_f32(f32_to_bf16(-3.0)), -3.0);
+
+        // Every f16 round-trips through f32
+        for h in 0..=u16::MAX {
+            let x = f16_to_f32(h);
+            if !x.is_nan() {
+                assert_eq!(f32_to_f16(x), h, "{:#06x}", h);
+            }
+        }
+
+        let mut rng = rand::thread_rng();
+        let values: Vec<f32> = (0..37).map(|_| rng.gen_range(-4.0f32..4.0)).collect();
+        let f16: Vec<u16> = values.iter().map(|&x| f32_to_f16(x)).collect();
+        let bf16: Vec<u16> = values.iter().map(|&x| f32_to_bf16(x)).collect();
+        let (mut expected, mut out) = (vec![0.0f32; 37], vec![0.0f32; 37]);
+        f16_to_f32_scalar(&f16, &mut expected);
+        for (x, y) in values.iter().zip(&expected) {
+            assert!((x - y).abs() <= x.abs() / 1024.0);
+        }
+        if is_x86_feature_detected!("f16c") && is_x86_feature_detected!("avx") {
+            unsafe { f16_to_f32_f16c(&f16, &mut out) };
+            assert_eq!(out, expected);
+            let mut encoded = vec![0u16; 37];
+            unsafe { f32_to_f16_f16c(&values, &mut encoded) };
+            assert_eq!(encoded, f16);
+        }
+        bf16_to_f32_scalar(&bf16, &mut expected);
+        if is_x86_feature_detected!("avx2") {
+            unsafe { bf16_to_f32_avx2(&bf16, &mut out) };
+            assert_eq!(out, expected);
+        }
+    }
+}
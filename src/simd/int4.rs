@@ -0,0 +1,98 @@
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use std::arch::x86_64::*;
+
+/// Integer Dot Product over nibble-packed codes (AVX2)
+/// Input: Query (i8, one per dimension), Vector (u4, two per byte: dimension 2j in the low
+/// nibble of byte j, 2j + 1 in the high nibble)
+/// Logic:
+/// 1. Split 32 bytes into low/high nibbles and interleave them back into dimension order
+///    (unpack within 128-bit lanes, then permute the lanes) -> 64 u8 codes in 0..15
+/// 2. _mm256_maddubs_epi16 (u8 * i8 -> i16), which cannot saturate: 2 * 15 * 128 < 32767
+/// 3. _mm256_madd_epi16 (i16 * 1 + i16 * 1 -> i32), then accumulate i32
+///
+/// Returns: Negative Dot Product, like `dot_product_u8_avx2`.
+///
+/// # Safety
+/// The CPU must support AVX2.
+#[target_feature(enable = "avx2")]
+pub unsafe fn dot_product_u4_avx2(q: &[i8], v: &[u8]) -> f32 {
+    let n = q.len();
+    assert_eq!(n.div_ceil(2), v.len());
+
+    let mut sum0 = _mm256_setzero_si256();
+    let mut sum1 = _mm256_setzero_si256();
+    let ones = _mm256_set1_epi16(1);
+    let low_mask = _mm256_set1_epi8(0x0f);
+
+    let mut i = 0; // dimension
+    let ptr_q = q.as_ptr();
+    let ptr_v = v.as_ptr();
+
+    // Process 64 dimensions (32 packed bytes) at a time
+    while i + 64 <= n {
+        let packed = _mm256_loadu_si256(ptr_v.add(i / 2) as *const _);
+        let lo = _mm256_and_si256(packed, low_mask);
+        let hi = _mm256_and_si256(_mm256_srli_epi16(packed, 4), low_mask);
+
+        // [lo0 hi0 .. lo7 hi7 | lo16 hi16 ..] and [lo8 hi8 .. | lo24 hi24 ..]
+        let a = _mm256_unpacklo_epi8(lo, hi);
+        let b = _mm256_unpackhi_epi8(lo, hi);
+        let codes0 = _mm256_permute2x128_si256(a, b, 0x20); // dimensions i .. i + 32
+        let codes1 = _mm256_permute2x128_si256(a, b, 0x31); // dimensions i + 32 .. i + 64
+
+        let q0 = _mm256_loadu_si256(ptr_q.add(i) as *const _);
+        let q1 = _mm256_loadu_si256(ptr_q.add(i + 32) as *const _);
+        sum0 = _mm256_add_epi32(sum0, _mm256_madd_epi16(_mm256_maddubs_epi16(codes0, q0), ones));
+        sum1 = _mm256_add_epi32(sum1, _mm256_madd_epi16(_mm256_maddubs_epi16(codes1, q1), ones));
+
+        i += 64;
+    }
+
+    sum0 = _mm256_add_epi32(sum0, sum1);
+    let sum128 = _mm_add_epi32(_mm256_castsi256_si128(sum0), _mm256_extracti128_si256(sum0, 1));
+    let sum64 = _mm_hadd_epi32(sum128, sum128);
+    let sum32 = _mm_hadd_epi32(sum64, sum64);
+
+    // Scalar tail
+    let mut dot = _mm_cvtsi128_si32(sum32);
+    while i < n {
+        dot += q[i] as i32 * unpack_u4(v, i) as i32;
+        i += 1;
+    }
+    -(dot as f32)
+}
+
+pub fn dot_product_u4_scalar(q: &[i8], v: &[u8]) -> f32 {
+    assert_eq!(q.len().div_ceil(2), v.len());
+    let dot: i32 = q.iter().enumerate().map(|(i, &qi)| qi as i32 * unpack_u4(v, i) as i32).sum();
+    -(dot as f32)
+}
+
+/// Code of dimension `i` in a nibble-packed vector.
+#[inline]
+pub fn unpack_u4(v: &[u8], i: usize) -> u8 {
+    (v[i / 2] >> ((i % 2) * 4)) & 0x0f
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::Rng;
+
+    #[test]
+    fn test_dot_product_u4_matches_scalar() {
+        let mut rng = rand::thread_rng();
+        // Lengths around the 64-dimension block, including odd ones with a lone last nibble
+        for n in [1usize, 7, 63, 64, 65, 128, 200] {
+            let q: Vec<i8> = (0..n).map(|_| rng.gen()).collect();
+            let v: Vec<u8> = (0..n.div_ceil(2)).map(|_| rng.gen()).collect();
+
+            let expected: i32 = (0..n).map(|i| q[i] as i32 * unpack_u4(&v, i) as i32).sum();
+            assert_eq!(dot_product_u4_scalar(&q, &v), -(expected as f32));
+
+            if is_x86_feature_detected!("avx2") {
+                assert_eq!(unsafe { dot_product_u4_avx2(&q, &v) }, -(expected as f32), "n = {}", n);
+            }
+        }
+    }
+}
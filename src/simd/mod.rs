@@ -1,17 +1,255 @@
 pub mod distance;
 pub mod avx2;
+pub mod avx512;
 pub mod int8;
+pub mod int4;
+pub mod binary;
+pub mod half;
+
+use crate::core::config::Metric;
+use crate::core::hardware::CpuFeatures;
 
 pub type DistanceFunc = unsafe fn(&[f32], &[f32]) -> f32;
 
+/// Negated dot product of an i8 query and a u8 (or nibble-packed u4) code (`simd::int8`, `simd::int4`).
+pub type Int8DotFunc = unsafe fn(&[i8], &[u8]) -> f32;
+
+/// Hamming distance between packed bit strings (`simd::binary`).
+pub type HammingFunc = unsafe fn(&[u64], &[u64]) -> u32;
+
+/// Widen 16-bit floats (`simd::half`) into an f32 slice of the same length.
+pub type DecodeFunc = unsafe fn(&[u16], &mut [f32]);
+
+/// Environment variable that makes `Kernels::detect` return `Kernels::scalar()` (any value but "0"),
+/// e.g. to run the test suite on the portable paths.
+pub const FORCE_SCALAR_ENV: &str = "VECTOR_ENGINE_FORCE_SCALAR";
+
+/// Every kernel a search needs, resolved for one CPU. Indexes copy it in when they are built or
+/// loaded so the hot loops make one indirect call instead of re-checking CPU features.
+#[derive(Clone, Copy)]
+pub struct Kernels {
+    pub euclidean: DistanceFunc,
+    /// Dot product (a similarity).
+    pub dot: DistanceFunc,
+    /// `-(a . b)`, the `Metric::InnerProduct` distance.
+    pub inner_product: DistanceFunc,
+    /// `1 - (a . b)`, the `Metric::Cosine` distance for unit-length inputs.
+    pub cosine: DistanceFunc,
+    /// u8 arena codes, for the quantized stage.
+    pub int8_dot: Int8DotFunc,
+    /// Nibble-packed u4 arena codes.
+    pub int4_dot: Int8DotFunc,
+    pub hamming: HammingFunc,
+    pub f16_decode: DecodeFunc,
+    pub bf16_decode: DecodeFunc,
+    scalar: bool,
+}
+
+impl Kernels {
+    /// The best kernels for this CPU, detected once per process. Honors `FORCE_SCALAR_ENV`.
+    pub fn detect() -> Self {
+        static DETECTED: std::sync::OnceLock<Kernels> = std::sync::OnceLock::new();
+        *DETECTED.get_or_init(|| {
+            let forced = std::env::var(FORCE_SCALAR_ENV).is_ok_and(|value| value != "0");
+            if forced {
+                return Self::scalar();
+            }
+            Self {
+                euclidean: get_euclidean_distance(),
+                dot: get_dot_product(),
+                inner_product: get_distance(Metric::InnerProduct),
+                cosine: get_distance(Metric::Cosine),
+                int8_dot: get_int8_dot(),
+                int4_dot: get_int4_dot(),
+                hamming: get_hamming(),
+                f16_decode: get_f16_decoder(),
+                bf16_decode: get_bf16_decoder(),
+                scalar: false,
+            }
+        })
+    }
+
+    /// Portable kernels only, whatever the CPU supports. Results match `detect()` up to f32
+    /// summation order; the integer kernels are bit-identical.
+    pub fn scalar() -> Self {
+        Self {
+            euclidean: fallback_euclidean,
+            dot: fallback_dot_product,
+            inner_product: fallback_inner_product_distance,
+            cosine: fallback_cosine_distance,
+            int8_dot: int8::dot_product_u8_scalar,
+            int4_dot: int4::dot_product_u4_scalar,
+            hamming: fallback_hamming,
+            f16_decode: fallback_f16_decode,
+            bf16_decode: fallback_bf16_decode,
+            scalar: true,
+        }
+    }
+
+    /// Whether these are the `scalar()` kernels.
+    pub fn is_scalar(&self) -> bool {
+        self.scalar
+    }
+
+    /// Distance kernel for `metric`, lower is closer (see `get_distance`).
+    pub fn distance(&self, metric: Metric) -> DistanceFunc {
+        match metric {
+            Metric::L2 => self.euclidean,
+            Metric::InnerProduct => self.inner_product,
+            Metric::Cosine => self.cosine,
+        }
+    }
+}
+
+impl std::fmt::Debug for Kernels {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Kernels").field("scalar", &self.scalar).finish()
+    }
+}
+
 pub fn get_euclidean_distance() -> DistanceFunc {
-    if is_x86_feature_detected!("avx2") {
+    if is_x86_feature_detected!("avx512f") {
+        avx512::euclidean_distance_avx512
+    } else if is_x86_feature_detected!("avx2") && is_x86_feature_detected!("fma") {
         avx2::euclidean_distance_avx2
     } else {
         fallback_euclidean
     }
 }
 
+/// Dot product (a similarity: higher is closer).
+pub fn get_dot_product() -> DistanceFunc {
+    if is_x86_feature_detected!("avx512f") {
+        avx512::dot_product_avx512
+    } else if is_x86_feature_detected!("avx2") && is_x86_feature_detected!("fma") {
+        avx2::dot_product_avx2
+    } else {
+        fallback_dot_product
+    }
+}
+
+/// Distance for `metric`, lower is closer:
+/// L2 = Euclidean, InnerProduct = -(a . b), Cosine = 1 - (a . b) for unit-length inputs.
+pub fn get_distance(metric: Metric) -> DistanceFunc {
+    let avx512 = is_x86_feature_detected!("avx512f");
+    let avx2 = is_x86_feature_detected!("avx2") && is_x86_feature_detected!("fma");
+    match (metric, avx512, avx2) {
+        (Metric::L2, _, _) => get_euclidean_distance(),
+        (Metric::InnerProduct, true, _) => inner_product_distance_avx512,
+        (Metric::InnerProduct, false, true) => inner_product_distance_avx2,
+        (Metric::InnerProduct, false, false) => fallback_inner_product_distance,
+        (Metric::Cosine, true, _) => cosine_distance_avx512,
+        (Metric::Cosine, false, true) => cosine_distance_avx2,
+        (Metric::Cosine, false, false) => fallback_cosine_distance,
+    }
+}
+
+/// i8 x u8 kernel for the quantized stage: AVX-512 VNNI, then AVX2, then scalar. All are exact.
+pub fn get_int8_dot() -> Int8DotFunc {
+    let cpu = CpuFeatures::detect();
+    if cpu.avx512vnni {
+        avx512::dot_product_u8_vnni
+    } else if cpu.avx2 {
+        int8::dot_product_u8_avx2
+    } else {
+        int8::dot_product_u8_scalar
+    }
+}
+
+/// i8 x nibble-packed u4 kernel for `Quantization::Scalar4`.
+pub fn get_int4_dot() -> Int8DotFunc {
+    if is_x86_feature_detected!("avx2") {
+        int4::dot_product_u4_avx2
+    } else {
+        int4::dot_product_u4_scalar
+    }
+}
+
+pub fn get_hamming() -> HammingFunc {
+    let popcnt = is_x86_feature_detected!("popcnt");
+    if is_x86_feature_detected!("avx2") && popcnt {
+        binary::hamming_avx2
+    } else if popcnt {
+        binary::hamming_popcnt
+    } else {
+        fallback_hamming
+    }
+}
+
+pub fn get_f16_decoder() -> DecodeFunc {
+    if is_x86_feature_detected!("f16c") && is_x86_feature_detected!("avx") {
+        half::f16_to_f32_f16c
+    } else {
+        fallback_f16_decode
+    }
+}
+
+pub fn get_bf16_decoder() -> DecodeFunc {
+    if is_x86_feature_detected!("avx2") {
+        half::bf16_to_f32_avx2
+    } else {
+        fallback_bf16_decode
+    }
+}
+
+/// f32 -> f16 (round to nearest even), with F16C when available. For writing indexes.
+pub fn encode_f16(src: &[f32], dst: &mut [u16]) {
+    if is_x86_feature_detected!("f16c") && is_x86_feature_detected!("avx") {
+        unsafe { half::f32_to_f16_f16c(src, dst) }
+    } else {
+        for (d, &x) in dst.iter_mut().zip(src) {
+            *d = half::f32_to_f16(x);
+        }
+    }
+}
+
+/// f32 -> bf16 (round to nearest even). For writing indexes.
+pub fn encode_bf16(src: &[f32], dst: &mut [u16]) {
+    for (d, &x) in dst.iter_mut().zip(src) {
+        *d = half::f32_to_bf16(x);
+    }
+}
+
+unsafe fn fallback_f16_decode(src: &[u16], dst: &mut [f32]) {
+    half::f16_to_f32_scalar(src, dst)
+}
+
+unsafe fn fallback_bf16_decode(src: &[u16], dst: &mut [f32]) {
+    half::bf16_to_f32_scalar(src, dst)
+}
+
+unsafe fn fallback_hamming(a: &[u64], b: &[u64]) -> u32 {
+    binary::hamming_scalar(a, b)
+}
+
 unsafe fn fallback_euclidean(a: &[f32], b: &[f32]) -> f32 {
     distance::euclidean_distance(a, b)
 }
+
+unsafe fn fallback_dot_product(a: &[f32], b: &[f32]) -> f32 {
+    distance::dot_product(a, b)
+}
+
+unsafe fn inner_product_distance_avx512(a: &[f32], b: &[f32]) -> f32 {
+    -avx512::dot_product_avx512(a, b)
+}
+
+unsafe fn cosine_distance_avx512(a: &[f32], b: &[f32]) -> f32 {
+    1.0 - avx512::dot_product_avx512(a, b)
+}
+
+unsafe fn inner_product_distance_avx2(a: &[f32], b: &[f32]) -> f32 {
+    -avx2::dot_product_avx2(a, b)
+}
+
+unsafe fn fallback_inner_product_distance(a: &[f32], b: &[f32]) -> f32 {
+    -distance::dot_product(a, b)
+}
+
+unsafe fn cosine_distance_avx2(a: &[f32], b: &[f32]) -> f32 {
+    1.0 - avx2::dot_product_avx2(a, b)
+}
+
+unsafe fn fallback_cosine_distance(a: &[f32], b: &[f32]) -> f32 {
+    1.0 - distance::dot_product(a, b)
+}
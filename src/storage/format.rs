@@ -9,7 +9,7 @@ pub struct Header {
     pub num_elements: u32,
     pub entry_point_id: u32,
     pub max_layer: u16,
-    pub padding_1: u16, // Alignment
+    pub max_layers: u16, // Level cap used to build (0 = not recorded)
     pub m_max: u32,
     pub m_max_0: u32,
     pub ef_construction: u32,
@@ -19,9 +19,101 @@ pub struct Header {
     pub checksum: u64,
     pub obfuscation_key: u64,
     pub quantized_vectors_offset: u64, // Offset to u8 vector arena
-    pub padding_2: [u64; 21], // Reduced by 1 u64
+    pub connections_size: u64, // Bytes in the connections arena (0 = runs to end of file)
+    pub deleted_offset: u64, // Offset to deleted bitmap (u64 words), 0 = no deletions
+    pub keys_offset: u64, // Offset to external keys (u64 per node), 0 = key is the node ID
+    pub key_index_offset: u64, // Offset to KeyEntry array sorted by key (live nodes only)
+    pub num_keys: u64, // Entries in the key index
+    pub level_multiplier: f64, // mL used for level assignment (0 = not recorded)
+    pub seed: u64, // Level RNG seed, valid if BUILD_FLAG_SEEDED
+    pub metric: u32, // Metric discriminant
+    pub build_flags: u32, // BUILD_FLAG_* bits
+    pub norms_offset: u64, // Offset to squared norms of the quantized vectors (f32 per node), L2 only
+    pub max_norm: f64, // Quantized vectors are stored divided by this (1.0 for cosine)
+    pub quantization: u32, // QUANTIZATION_* kind of the stage-1 arena
+    pub pq_subspaces: u32, // PQ code bytes per node
+    pub pq_codebooks_offset: u64, // f32 codebooks, [subspace][256][dimension / pq_subspaces]
+    pub pq_codes_offset: u64, // PQ codes (pq_subspaces bytes per node), replaces the u8 arena
+    pub binary_offset: u64, // Sign bits (dimension.div_ceil(64) u64 words per node), 0 = no binary arena
+    pub binary_thresholds_offset: u64, // f32 per dimension the sign bits are taken against
+    pub sq_params_offset: u64, // Trained scalar ranges: f32 lower bounds, then f32 steps, per dimension
+    pub element_type: u32, // ELEMENT_* type of the full-precision arena at `vectors_offset`
+    pub vector_placement: u32, // VECTORS_* location of the full-precision arena
+    pub padding_2: [u64; 4], // Reduced by 18 u64
 }
 
+/// Version 3: the stage-1 arena is `quantization` (scalar u8, trained u8, u4 or PQ codes), optionally followed
+/// by a binary arena at `binary_offset` (zero in files written without one); the full-precision
+/// arena is `element_type`, inline or not per `vector_placement`.
+/// Version 2: vectors are stored per `metric` (only cosine normalizes).
+/// Version 1 files were always normalized and are read as cosine.
+pub const FORMAT_VERSION: u32 = 3;
+
+/// `Header::quantization`: one u8 per dimension at `quantized_vectors_offset` (all versions < 3).
+pub const QUANTIZATION_SCALAR8: u32 = 0;
+/// `Header::quantization`: product-quantized codes at `pq_codes_offset`.
+pub const QUANTIZATION_PRODUCT: u32 = 1;
+/// `Header::quantization`: one u8 per dimension at `quantized_vectors_offset`, over the
+/// per-dimension ranges at `sq_params_offset`.
+pub const QUANTIZATION_SCALAR8_TRAINED: u32 = 2;
+/// `Header::quantization`: nibble-packed u4 codes (`dimension.div_ceil(2)` bytes per node) at
+/// `quantized_vectors_offset`.
+pub const QUANTIZATION_SCALAR4: u32 = 3;
+
+/// `Header::element_type`: f32 (all versions < 3).
+pub const ELEMENT_F32: u32 = 0;
+/// `Header::element_type`: IEEE half precision (u16 bits).
+pub const ELEMENT_F16: u32 = 1;
+/// `Header::element_type`: bfloat16 (u16 bits).
+pub const ELEMENT_BF16: u32 = 2;
+
+/// `Header::vector_placement`: at `vectors_offset` (all versions < 3).
+pub const VECTORS_INLINE: u32 = 0;
+/// `Header::vector_placement`: in the sidecar file, `vectors_offset` is 0.
+pub const VECTORS_SIDECAR: u32 = 1;
+/// `Header::vector_placement`: not stored, `vectors_offset` is 0.
+pub const VECTORS_OMITTED: u32 = 2;
+
+/// Header of a full-precision sidecar file: the arena follows it, `element_type` as in `Header`.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Pod, Zeroable)]
+pub struct VectorsHeader {
+    pub magic: [u8; 8],
+    pub version: u32,
+    pub element_type: u32,
+    pub dimension: u32,
+    pub padding_0: u32,
+    pub num_elements: u64,
+    pub checksum: u64, // crc32 of the arena
+    pub padding: [u64; 3],
+}
+
+pub const VECTORS_MAGIC: [u8; 8] = *b"HNSWVEC1";
+pub const VECTORS_FORMAT_VERSION: u32 = 1;
+
+/// Sidecar of the index at `path`: the same name with `.vectors` appended.
+pub fn vectors_sidecar_path(path: &std::path::Path) -> std::path::PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".vectors");
+    name.into()
+}
+
+/// `Header::metric` of an index built with a custom `Distance` (not a `Metric` discriminant).
+pub const METRIC_CUSTOM: u32 = u32::MAX;
+
+/// Built-in metric recorded in `header`, `None` for a custom `Distance`. Version 1 files are cosine.
+pub fn header_metric(header: &Header) -> Option<crate::core::config::Metric> {
+    if header.version < 2 {
+        return Some(crate::core::config::Metric::Cosine);
+    }
+    crate::core::config::Metric::from_u32(header.metric)
+}
+
+pub const BUILD_FLAG_SEEDED: u32 = 1 << 0;
+pub const BUILD_FLAG_HEURISTIC: u32 = 1 << 1;
+pub const BUILD_FLAG_EXTEND_CANDIDATES: u32 = 1 << 2;
+pub const BUILD_FLAG_KEEP_PRUNED: u32 = 1 << 3;
+
 #[repr(C)]
 #[derive(Debug, Copy, Clone, Pod, Zeroable)]
 pub struct OnDiskNode {
@@ -30,7 +122,42 @@ pub struct OnDiskNode {
     pub connections_offset: u32,
 }
 
+/// One entry of the key index: external key -> node ID.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Pod, Zeroable)]
+pub struct KeyEntry {
+    pub key: u64,
+    pub id: u64,
+}
+
+/// Header of an IVF index file (`IvfIndex::save`). Every section is laid out in inverted-list
+/// order: list `l` owns positions `list_offsets[l]..list_offsets[l + 1]` of the per-vector sections.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Pod, Zeroable)]
+pub struct IvfHeader {
+    pub magic: [u8; 8],
+    pub version: u32,
+    pub metric: u32, // Metric discriminant
+    pub dimension: u32,
+    pub nlist: u32,
+    pub num_elements: u64,
+    pub max_norm: f64, // Quantized vectors are stored divided by this (1.0 for cosine)
+    pub centroids_offset: u64, // f32 centroids, nlist * dimension
+    pub list_offsets_offset: u64, // u64 prefix sums, nlist + 1 entries
+    pub quantized_vectors_offset: u64, // u8 arena, same scaling as `Header`
+    pub vectors_offset: u64, // f32 arena, as stored (unit length for cosine)
+    pub keys_offset: u64, // u64 external key per position
+    pub norms_offset: u64, // Squared norms of the quantized vectors (f32 per position), L2 only
+    pub checksum: u64, // crc32 of everything after the header
+    pub padding: [u64; 20],
+}
+
+pub const IVF_MAGIC: [u8; 8] = *b"IVFANN01";
+pub const IVF_FORMAT_VERSION: u32 = 1;
+
 // Ensure Header is 256 bytes
 const _: () = assert!(std::mem::size_of::<Header>() == 256);
 // Ensure OnDiskNode is 8 bytes
 const _: () = assert!(std::mem::size_of::<OnDiskNode>() == 8);
+const _: () = assert!(std::mem::size_of::<IvfHeader>() == 256);
+const _: () = assert!(std::mem::size_of::<VectorsHeader>() == 64);
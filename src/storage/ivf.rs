@@ -0,0 +1,323 @@
+use crate::core::config::Metric;
+use crate::core::distance::{Distance, MetricDistance};
+use crate::core::ivf::probe_lists;
+use crate::core::quantization::{QuantizedScorer, Quantizer};
+use crate::core::search::{push_bounded, Candidate, Rerank, SearchContext, SearchParams};
+use crate::simd::Kernels;
+use crate::storage::format::{IvfHeader, IVF_FORMAT_VERSION, IVF_MAGIC};
+use crate::storage::mmap::StorageError;
+use memmap2::Mmap;
+use std::fs::File;
+use std::path::Path;
+
+/// Quantized candidates kept per requested result by `search`, which takes no `SearchParams`.
+const DEFAULT_RERANK_FACTOR: usize = 4;
+
+/// Read-only view of a file written by `IvfIndex::save`, the IVF counterpart of `MmapIndex`.
+/// Search probes the `nprobe` closest lists with the int8 kernel, keeps the best `ef`
+/// candidates and reranks them (as deep as `SearchParams::rerank` allows) on the f32 arena.
+pub struct MmapIvfIndex {
+    mmap: Mmap,
+    distance: MetricDistance,
+    kernels: Kernels,
+}
+
+impl MmapIvfIndex {
+    pub fn load(path: &Path) -> Result<Self, StorageError> {
+        let file = File::open(path)?;
+        let mmap = unsafe { Mmap::map(&file)? };
+
+        let header_size = std::mem::size_of::<IvfHeader>();
+        if mmap.len() < header_size {
+            return Err(StorageError::FileTooSmall);
+        }
+        let header = bytemuck::from_bytes::<IvfHeader>(&mmap[0..header_size]);
+        if header.magic != IVF_MAGIC {
+            return Err(StorageError::InvalidMagic);
+        }
+        if header.version != IVF_FORMAT_VERSION {
+            return Err(StorageError::InvalidHeader(format!("unsupported IVF version {}", header.version)));
+        }
+        let metric = Metric::from_u32(header.metric)
+            .ok_or_else(|| StorageError::InvalidHeader(format!("unknown metric {}", header.metric)))?;
+        if header.max_norm <= 0.0 || !header.max_norm.is_finite() {
+            return Err(StorageError::InvalidHeader(format!("invalid max_norm {}", header.max_norm)));
+        }
+        if header.dimension == 0 || header.nlist == 0 {
+            return Err(StorageError::InvalidHeader("empty dimension or nlist".to_string()));
+        }
+        if metric == Metric::L2 && header.norms_offset == 0 {
+            return Err(StorageError::InvalidHeader("L2 index without norms section".to_string()));
+        }
+
+        let (dim, n, nlist) = (header.dimension as u64, header.num_elements, header.nlist as u64);
+        let total_size = mmap.len() as u64;
+        let mut sections = vec![
+            (header.centroids_offset, nlist * dim * 4, 4),
+            (header.list_offsets_offset, (nlist + 1) * 8, 8),
+            (header.quantized_vectors_offset, n * dim, 1),
+            (header.vectors_offset, n * dim * 4, 4),
+            (header.keys_offset, n * 8, 8),
+        ];
+        if header.norms_offset != 0 {
+            sections.push((header.norms_offset, n * 4, 4));
+        }
+        for (offset, size, alignment) in sections {
+            if offset < header_size as u64 || !offset.is_multiple_of(alignment) || offset + size > total_size {
+                return Err(StorageError::FileTooSmall);
+            }
+        }
+
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&mmap[header_size..]);
+        if hasher.finalize() as u64 != header.checksum {
+            return Err(StorageError::ChecksumMismatch);
+        }
+
+        let kernels = Kernels::detect();
+        let index = Self { mmap, distance: MetricDistance::with_kernels(metric, &kernels), kernels };
+        let offsets = index.list_offsets();
+        if offsets[0] != 0 || offsets[nlist as usize] != n || offsets.windows(2).any(|w| w[0] > w[1]) {
+            return Err(StorageError::InvalidHeader("list offsets are not a partition of the vectors".to_string()));
+        }
+        Ok(index)
+    }
+
+    pub fn header(&self) -> &IvfHeader {
+        bytemuck::from_bytes::<IvfHeader>(&self.mmap[0..std::mem::size_of::<IvfHeader>()])
+    }
+
+    pub fn metric(&self) -> Metric {
+        Metric::from_u32(self.header().metric).unwrap_or_default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.header().num_elements as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn nlist(&self) -> usize {
+        self.header().nlist as usize
+    }
+
+    fn section<T: bytemuck::Pod>(&self, offset: u64, count: usize) -> &[T] {
+        let start = offset as usize;
+        bytemuck::cast_slice(&self.mmap[start..start + count * std::mem::size_of::<T>()])
+    }
+
+    pub fn centroids(&self) -> &[f32] {
+        let header = self.header();
+        self.section(header.centroids_offset, header.nlist as usize * header.dimension as usize)
+    }
+
+    /// Prefix sums: list `l` holds positions `list_offsets()[l]..list_offsets()[l + 1]`.
+    pub fn list_offsets(&self) -> &[u64] {
+        let header = self.header();
+        self.section(header.list_offsets_offset, header.nlist as usize + 1)
+    }
+
+    pub fn get_quantized_vector(&self, pos: usize) -> &[u8] {
+        let header = self.header();
+        let dim = header.dimension as usize;
+        &self.section(header.quantized_vectors_offset, self.len() * dim)[pos * dim..(pos + 1) * dim]
+    }
+
+    pub fn get_full_vector(&self, pos: usize) -> &[f32] {
+        let header = self.header();
+        let dim = header.dimension as usize;
+        &self.section(header.vectors_offset, self.len() * dim)[pos * dim..(pos + 1) * dim]
+    }
+
+    pub fn get_key(&self, pos: usize) -> u64 {
+        self.section::<u64>(self.header().keys_offset, self.len())[pos]
+    }
+
+    /// Squared norm of each quantized-arena vector, L2 indexes only.
+    pub fn norms(&self) -> Option<&[f32]> {
+        let header = self.header();
+        (header.norms_offset != 0).then(|| self.section(header.norms_offset, self.len()))
+    }
+
+    /// Search the `nprobe` closest lists, reranking the best `4 * k` candidates.
+    /// Returns `(key, distance)` pairs, closest first.
+    /// Scratch lives in a `SearchContext` dropped on return; hot loops should own one and call `search_with`.
+    pub fn search(&self, query: &[f32], k: usize, nprobe: usize) -> Vec<(u64, f32)> {
+        self.search_with(&mut SearchContext::new(), query, k, nprobe).to_vec()
+    }
+
+    /// `search` with caller-owned buffers. The returned slice lives in `ctx`.
+    pub fn search_with<'c>(&self, ctx: &'c mut SearchContext, query: &[f32], k: usize, nprobe: usize) -> &'c [(u64, f32)] {
+        self.search_with_context(ctx, query, k, nprobe, SearchParams::new(DEFAULT_RERANK_FACTOR * k))
+    }
+
+    /// `search` driven by `SearchParams`: `ef` is how many quantized candidates the scan keeps,
+    /// `rerank` how many of them are rescored in f32. `cascade` is ignored.
+    pub fn search_with_params(&self, query: &[f32], k: usize, nprobe: usize, params: SearchParams) -> Vec<(u64, f32)> {
+        self.search_with_context(&mut SearchContext::new(), query, k, nprobe, params).to_vec()
+    }
+
+    /// `search_with_params` with caller-owned buffers (see `search_with`).
+    pub fn search_with_context<'c>(&self, ctx: &'c mut SearchContext, query: &[f32], k: usize, nprobe: usize, params: SearchParams) -> &'c [(u64, f32)] {
+        let SearchContext { w, query: q, query_i8, scored, results, .. } = ctx;
+        results.clear();
+        if k == 0 || self.is_empty() {
+            return results;
+        }
+
+        // 1. Prepare Query
+        q.clear();
+        q.extend_from_slice(query);
+        self.distance.prepare(q);
+        Quantizer::quantize_query_into(query, query_i8);
+
+        // 2. Probe: closest centroids in full precision
+        probe_lists(&self.distance, self.centroids(), q, nprobe, scored);
+
+        // 3. Scan the probed lists (Coarse)
+        let norms = self.norms().filter(|_| self.metric() == Metric::L2);
+        let scorer = QuantizedScorer::new(&self.kernels, norms.is_some(), self.header().max_norm as f32, query, query_i8);
+        let offsets = self.list_offsets();
+        let ef = k.max(params.ef);
+        w.clear();
+        for &(list, _) in scored.iter() {
+            for pos in offsets[list] as usize..offsets[list + 1] as usize {
+                let distance = scorer.score(query_i8, self.get_quantized_vector(pos), norms.map_or(0.0, |norms| norms[pos]));
+                push_bounded(w, Candidate { distance, node_id: pos }, ef);
+            }
+        }
+
+        // 4. Rerank (Fine)
+        // params.rerank picks how many of the `ef` survivors, best stage-1 score first, get
+        // their f32 distance; `Skip` returns the stage-1 scores.
+        let rerank = match params.rerank {
+            Rerank::All => Some(ef),
+            Rerank::Top(n) => Some(ef.min(n.max(k))),
+            Rerank::Skip => None,
+        };
+        scored.clear();
+        scored.extend(w.drain().map(|c| (c.node_id, c.distance)));
+        if let Some(keep) = rerank {
+            if keep < scored.len() {
+                scored.select_nth_unstable_by(keep, |a, b| a.1.partial_cmp(&b.1).unwrap());
+                scored.truncate(keep);
+            }
+            for (pos, dist) in scored.iter_mut() {
+                *dist = self.distance.distance(q, self.get_full_vector(*pos));
+            }
+        }
+        scored.sort_unstable_by(|a, b| a.1.partial_cmp(&b.1).unwrap());
+        scored.truncate(k);
+
+        results.extend(scored.iter().map(|&(pos, dist)| (self.get_key(pos), dist)));
+        results
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::config::IvfConfig;
+    use crate::core::ivf::IvfIndex;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+    use tempfile::NamedTempFile;
+
+    #[test]
+    fn test_ivf_save_load_search() -> Result<(), Box<dyn std::error::Error>> {
+        let mut rng = StdRng::seed_from_u64(2);
+        // Norms spread over 0.5..5 so the metrics disagree
+        let data: Vec<Vec<f32>> = (0..3000).map(|_| {
+            let scale = rng.gen_range(0.5..5.0);
+            (0..16).map(|_| rng.gen_range(-1.0f32..1.0) * scale).collect()
+        }).collect();
+        let queries: Vec<Vec<f32>> = (0..20).map(|_| (0..16).map(|_| rng.gen_range(-2.0f32..2.0)).collect()).collect();
+
+        for metric in [Metric::L2, Metric::InnerProduct, Metric::Cosine] {
+            let distance = MetricDistance::new(metric);
+            let mut index = IvfIndex::new(IvfConfig::builder(16).nlist(24).metric(metric).seed(3).build()?)?;
+            index.train(&data)?;
+            for (i, v) in data.iter().enumerate() {
+                index.insert_with_key(1000 + i as u64, v.clone())?;
+            }
+            let temp_file = NamedTempFile::new()?;
+            index.save(temp_file.path())?;
+            let mmap_index = MmapIvfIndex::load(temp_file.path())?;
+            assert_eq!((mmap_index.metric(), mmap_index.len(), mmap_index.nlist()), (metric, 3000, 24));
+            assert_eq!(mmap_index.list_offsets().windows(2).map(|w| (w[1] - w[0]) as usize).collect::<Vec<_>>(), index.list_sizes());
+
+            let mut hits = 0;
+            for query in &queries {
+                let from_mmap = mmap_index.search(query, 10, 24);
+                let exact = index.search(query, 10, 24);
+                hits += from_mmap.iter().filter(|(key, _)| exact.iter().any(|e| e.0 == *key)).count();
+                // Reported distances are exact
+                let (key, dist) = from_mmap[0];
+                let mut q = query.clone();
+                distance.prepare(&mut q);
+                let exact_dist = distance.distance(&q, index.vector((key - 1000) as usize));
+                assert!((dist - exact_dist).abs() < 1e-3, "{:?}: {} vs {}", metric, dist, exact_dist);
+            }
+            let recall = hits as f32 / (queries.len() * 10) as f32;
+            assert!(recall >= 0.9, "{:?} recall {}", metric, recall);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_ivf_rerank_depth() -> Result<(), Box<dyn std::error::Error>> {
+        let mut rng = StdRng::seed_from_u64(4);
+        let data: Vec<Vec<f32>> = (0..2000).map(|_| (0..16).map(|_| rng.gen_range(-1.0f32..1.0)).collect()).collect();
+        let queries: Vec<Vec<f32>> = (0..20).map(|_| (0..16).map(|_| rng.gen_range(-1.0f32..1.0)).collect()).collect();
+        let mut index = IvfIndex::new(IvfConfig::builder(16).nlist(16).seed(5).build()?)?;
+        index.train(&data)?;
+        index.insert_parallel(data)?;
+        let temp_file = NamedTempFile::new()?;
+        index.save(temp_file.path())?;
+        let mmap_index = MmapIvfIndex::load(temp_file.path())?;
+
+        // `search` reranks 4k candidates; a rerank at least as deep as `ef` changes nothing, a
+        // shallower one still reranks the top of the list, and skipping returns stage-1 scores
+        let all = SearchParams::new(40);
+        let (mut top_overlap, mut skip_overlap) = (0, 0);
+        for query in &queries {
+            let expected = mmap_index.search(query, 10, 4);
+            assert_eq!(mmap_index.search_with_params(query, 10, 4, all), expected);
+            assert_eq!(mmap_index.search_with_params(query, 10, 4, all.with_rerank(Rerank::Top(1000))), expected);
+            let top = mmap_index.search_with_params(query, 10, 4, all.with_rerank(Rerank::Top(20)));
+            top_overlap += top.iter().filter(|(key, _)| expected.iter().any(|e| e.0 == *key)).count();
+            let skipped = mmap_index.search_with_params(query, 10, 4, all.with_rerank(Rerank::Skip));
+            assert_eq!(skipped.len(), 10);
+            skip_overlap += skipped.iter().filter(|(key, _)| expected.iter().any(|e| e.0 == *key)).count();
+        }
+        assert!(top_overlap >= 180, "top-20 overlap {}", top_overlap);
+        assert!(skip_overlap >= 120, "quantized-only overlap {}", skip_overlap);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_ivf_load_rejects_corruption() -> Result<(), Box<dyn std::error::Error>> {
+        let data: Vec<Vec<f32>> = (0..64).map(|i| vec![i as f32, 1.0, -1.0, 0.5]).collect();
+        let mut index = IvfIndex::new(IvfConfig::builder(4).nlist(4).seed(1).build()?)?;
+        index.train(&data)?;
+        index.insert_parallel(data)?;
+        let temp_file = NamedTempFile::new()?;
+        index.save(temp_file.path())?;
+
+        let mut bytes = std::fs::read(temp_file.path())?;
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xff;
+        std::fs::write(temp_file.path(), &bytes)?;
+        assert!(matches!(MmapIvfIndex::load(temp_file.path()), Err(StorageError::ChecksumMismatch)));
+
+        bytes[0] = b'X';
+        std::fs::write(temp_file.path(), &bytes)?;
+        assert!(matches!(MmapIvfIndex::load(temp_file.path()), Err(StorageError::InvalidMagic)));
+
+        Ok(())
+    }
+}
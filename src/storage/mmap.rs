@@ -1,4 +1,13 @@
-use crate::storage::format::{Header, OnDiskNode};
+use crate::core::config::{ElementType, Metric, Quantization};
+use crate::core::distance::{Distance, MetricDistance};
+use crate::core::pq::{ProductQuantizer, PQ_CENTROIDS};
+use crate::core::quantization::TrainedQuantizer;
+use crate::core::search::{Candidate, Cascade, Rerank, SearchContext, SearchParams};
+use crate::simd::binary::sign_bits_into;
+use crate::simd::{DecodeFunc, Kernels};
+use crate::storage::format::{Header, KeyEntry, OnDiskNode, ELEMENT_BF16, ELEMENT_F16, ELEMENT_F32, FORMAT_VERSION, METRIC_CUSTOM};
+use crate::storage::format::{header_metric, vectors_sidecar_path, VectorsHeader, VECTORS_FORMAT_VERSION, VECTORS_INLINE, VECTORS_MAGIC, VECTORS_OMITTED, VECTORS_SIDECAR};
+use crate::storage::format::{QUANTIZATION_PRODUCT, QUANTIZATION_SCALAR4, QUANTIZATION_SCALAR8, QUANTIZATION_SCALAR8_TRAINED};
 use memmap2::Mmap;
 use std::fs::File;
 use std::path::Path;
@@ -14,14 +23,99 @@ pub enum StorageError {
     FileTooSmall,
     #[error("Checksum mismatch")]
     ChecksumMismatch,
+    #[error("Invalid header: {0}")]
+    InvalidHeader(String),
 }
 
-pub struct MmapIndex {
+/// Read-only view of a saved index, generic over its `Distance` like `HNSW`.
+pub struct MmapIndex<D = MetricDistance> {
     mmap: Mmap,
+    distance: D,
+    /// Codebooks copied out of the file, for `Quantization::Product` indexes.
+    pq: Option<ProductQuantizer>,
+    /// Ranges copied out of the file, for `Quantization::TrainedScalar8` indexes.
+    sq: Option<TrainedQuantizer>,
+    /// Kernels for the quantized stage, resolved once at load.
+    kernels: Kernels,
+    /// Widening kernel for a 16-bit full-precision arena, `None` for f32.
+    decode: Option<DecodeFunc>,
+    /// Mapped `vectors_sidecar_path` of an index saved with `VectorPlacement::Sidecar`, if present.
+    sidecar: Option<Mmap>,
+}
+
+/// A full-precision vector as stored in the file, see `MmapIndex::full_vector`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FullVector<'a> {
+    F32(&'a [f32]),
+    /// IEEE half precision bits.
+    F16(&'a [u16]),
+    /// bfloat16 bits.
+    BF16(&'a [u16]),
+}
+
+impl FullVector<'_> {
+    pub fn len(&self) -> usize {
+        match self {
+            FullVector::F32(v) => v.len(),
+            FullVector::F16(v) | FullVector::BF16(v) => v.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Widened copy (a plain copy for f32).
+    pub fn to_vec(&self) -> Vec<f32> {
+        use crate::simd::half::{bf16_to_f32, f16_to_f32};
+
+        match self {
+            FullVector::F32(v) => v.to_vec(),
+            FullVector::F16(v) => v.iter().map(|&h| f16_to_f32(h)).collect(),
+            FullVector::BF16(v) => v.iter().map(|&h| bf16_to_f32(h)).collect(),
+        }
+    }
 }
 
 impl MmapIndex {
+    /// Load an index built with one of the built-in metrics (read from the header).
     pub fn load(path: &Path) -> Result<Self, StorageError> {
+        Self::load_with_kernels(path, Kernels::detect())
+    }
+
+    /// `load` with explicit kernels for every stage, e.g. `Kernels::scalar()` to test the portable paths.
+    pub fn load_with_kernels(path: &Path, kernels: Kernels) -> Result<Self, StorageError> {
+        let index = Self::open(path, kernels, |header| {
+            let metric = header_metric(header).ok_or_else(|| {
+                StorageError::InvalidHeader("index was built with a custom distance, use load_with_distance".to_string())
+            })?;
+            Ok(MetricDistance::with_kernels(metric, &kernels))
+        })?;
+        index.check_searchable()?;
+        Ok(index)
+    }
+}
+
+impl<D: Distance> MmapIndex<D> {
+    /// Load an index to be searched with `distance`, which must be the one it was built with.
+    /// If `distance` is the built-in metric recorded in the header the search runs on the
+    /// quantized arena and reranks; any other distance is searched exactly on the f32 arena.
+    pub fn load_with_distance(path: &Path, distance: D) -> Result<Self, StorageError> {
+        let index = Self::open(path, Kernels::detect(), |_| Ok(distance))?;
+        index.check_searchable()?;
+        Ok(index)
+    }
+
+    /// Without full-precision vectors only the quantized path of the header's own metric works.
+    fn check_searchable(&self) -> Result<(), StorageError> {
+        if !self.has_full_vectors() && (self.metric().is_none() || self.distance.metric() != self.metric()) {
+            return Err(StorageError::InvalidHeader("searching with this distance needs the full-precision vectors".to_string()));
+        }
+        Ok(())
+    }
+
+    /// Map, validate and warm up the file. `distance` is built from the validated header.
+    fn open(path: &Path, kernels: Kernels, distance: impl FnOnce(&Header) -> Result<D, StorageError>) -> Result<Self, StorageError> {
         let file = File::open(path)?;
         let mmap = unsafe { Mmap::map(&file)? };
 
@@ -38,9 +132,94 @@ impl MmapIndex {
         let total_size = mmap.len() as u64;
         if header.nodes_offset >= total_size || 
            header.vectors_offset >= total_size || 
-           header.connections_offset >= total_size {
+           header.connections_offset >= total_size ||
+           header.connections_offset + header.connections_size > total_size {
             return Err(StorageError::FileTooSmall);
         }
+        if header.deleted_offset != 0 {
+            let deleted_size = (header.num_elements as u64).div_ceil(64) * 8;
+            if !header.deleted_offset.is_multiple_of(8) || header.deleted_offset + deleted_size > total_size {
+                return Err(StorageError::FileTooSmall);
+            }
+        }
+        if header.keys_offset != 0 {
+            let keys_end = header.keys_offset + header.num_elements as u64 * 8;
+            let key_index_end = header.key_index_offset + header.num_keys * std::mem::size_of::<KeyEntry>() as u64;
+            if !header.keys_offset.is_multiple_of(8) || keys_end > total_size || key_index_end > total_size {
+                return Err(StorageError::FileTooSmall);
+            }
+        }
+
+        if header.version > FORMAT_VERSION {
+            return Err(StorageError::InvalidHeader(format!("unsupported version {}", header.version)));
+        }
+        if header.version >= 2 {
+            let metric = Metric::from_u32(header.metric);
+            if metric.is_none() && header.metric != METRIC_CUSTOM {
+                return Err(StorageError::InvalidHeader(format!("unknown metric {}", header.metric)));
+            }
+            if header.max_norm <= 0.0 || !header.max_norm.is_finite() {
+                return Err(StorageError::InvalidHeader(format!("invalid max_norm {}", header.max_norm)));
+            }
+            let scalar = header.quantization != QUANTIZATION_PRODUCT;
+            if metric == Some(Metric::L2) && scalar && header.num_elements > 0 && header.norms_offset == 0 {
+                return Err(StorageError::InvalidHeader("L2 index without norms section".to_string()));
+            }
+        }
+        if header.norms_offset != 0 {
+            let norms_end = header.norms_offset + header.num_elements as u64 * 4;
+            if !header.norms_offset.is_multiple_of(4) || norms_end > total_size {
+                return Err(StorageError::FileTooSmall);
+            }
+        }
+
+        let mut sq = None;
+        let pq = match header.quantization {
+            QUANTIZATION_SCALAR8 | QUANTIZATION_SCALAR4 => None,
+            QUANTIZATION_SCALAR8_TRAINED => {
+                let dim = header.dimension as usize;
+                let start = header.sq_params_offset as usize;
+                if start == 0 || !start.is_multiple_of(4) || (start + dim * 8) as u64 > total_size {
+                    return Err(StorageError::FileTooSmall);
+                }
+                let params: &[f32] = bytemuck::cast_slice(&mmap[start..start + dim * 8]);
+                sq = Some(TrainedQuantizer::from_params(params[..dim].to_vec(), params[dim..].to_vec()));
+                None
+            }
+            QUANTIZATION_PRODUCT => {
+                let (dim, subspaces) = (header.dimension as u64, header.pq_subspaces as u64);
+                if subspaces == 0 || !dim.is_multiple_of(subspaces) {
+                    return Err(StorageError::InvalidHeader(format!("{} PQ subspaces for dimension {}", subspaces, dim)));
+                }
+                let codebooks_size = dim * PQ_CENTROIDS as u64 * 4;
+                if header.pq_codes_offset + header.num_elements as u64 * subspaces > total_size
+                    || !header.pq_codebooks_offset.is_multiple_of(4)
+                    || header.pq_codebooks_offset + codebooks_size > total_size {
+                    return Err(StorageError::FileTooSmall);
+                }
+                let start = header.pq_codebooks_offset as usize;
+                let codebooks: &[f32] = bytemuck::cast_slice(&mmap[start..start + codebooks_size as usize]);
+                Some(ProductQuantizer::from_codebooks(dim as usize, subspaces as usize, codebooks.to_vec()))
+            }
+            other => return Err(StorageError::InvalidHeader(format!("unknown quantization {}", other))),
+        };
+
+        let decode = match header.element_type {
+            ELEMENT_F32 => None,
+            ELEMENT_F16 => Some(kernels.f16_decode),
+            ELEMENT_BF16 => Some(kernels.bf16_decode),
+            other => return Err(StorageError::InvalidHeader(format!("unknown element type {}", other))),
+        };
+
+        if header.binary_offset != 0 {
+            let binary_size = header.num_elements as u64 * (header.dimension as u64).div_ceil(64) * 8;
+            let thresholds_end = header.binary_thresholds_offset + header.dimension as u64 * 4;
+            if !header.binary_offset.is_multiple_of(8) || header.binary_offset + binary_size > total_size
+                || header.binary_thresholds_offset == 0 || !header.binary_thresholds_offset.is_multiple_of(4)
+                || thresholds_end > total_size {
+                return Err(StorageError::FileTooSmall);
+            }
+        }
 
         // Verify Checksum
         let header_size = std::mem::size_of::<Header>();
@@ -54,17 +233,60 @@ impl MmapIndex {
             }
         }
 
-        let index = Self { mmap };
+        let sidecar = match header.vector_placement {
+            VECTORS_INLINE | VECTORS_OMITTED => None,
+            VECTORS_SIDECAR => Self::map_sidecar(&vectors_sidecar_path(path), header)?,
+            other => return Err(StorageError::InvalidHeader(format!("unknown vector placement {}", other))),
+        };
+
+        let distance = distance(header)?;
+        let index = Self { mmap, distance, pq, sq, kernels, decode, sidecar };
         // Warmup & Optimization
-        index.warmup()?;
+        Self::warmup(&index.mmap)?;
+        if let Some(sidecar) = &index.sidecar {
+            Self::warmup(sidecar)?;
+        }
 
         Ok(index)
     }
 
-    fn warmup(&self) -> Result<(), StorageError> {
+    /// Map and validate a full-precision sidecar; `None` if the file does not exist.
+    fn map_sidecar(path: &Path, header: &Header) -> Result<Option<Mmap>, StorageError> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let mmap = unsafe { Mmap::map(&file)? };
+
+        let header_size = std::mem::size_of::<VectorsHeader>();
+        if mmap.len() < header_size {
+            return Err(StorageError::FileTooSmall);
+        }
+        let sidecar = bytemuck::from_bytes::<VectorsHeader>(&mmap[..header_size]);
+        if sidecar.magic != VECTORS_MAGIC {
+            return Err(StorageError::InvalidMagic);
+        }
+        if sidecar.version > VECTORS_FORMAT_VERSION
+            || sidecar.element_type != header.element_type
+            || sidecar.dimension != header.dimension
+            || sidecar.num_elements != header.num_elements as u64 {
+            return Err(StorageError::InvalidHeader("sidecar does not match the index".to_string()));
+        }
+        let element_size = if header.element_type == ELEMENT_F32 { 4 } else { 2 };
+        if mmap.len() < header_size + header.num_elements as usize * header.dimension as usize * element_size {
+            return Err(StorageError::FileTooSmall);
+        }
+        if crc32fast::hash(&mmap[header_size..]) as u64 != sidecar.checksum {
+            return Err(StorageError::ChecksumMismatch);
+        }
+        Ok(Some(mmap))
+    }
+
+    fn warmup(mmap: &Mmap) -> Result<(), StorageError> {
         unsafe {
-            let ptr = self.mmap.as_ptr();
-            let len = self.mmap.len();
+            let ptr = mmap.as_ptr();
+            let len = mmap.len();
             
             // 1. Transparent Huge Pages
             // MADV_HUGEPAGE = 14
@@ -76,7 +298,7 @@ impl MmapIndex {
         }
 
         // 3. User-Land Prefault (Touch every page)
-        let sum: u64 = self.mmap.iter().step_by(4096).map(|&b| b as u64).sum();
+        let sum: u64 = mmap.iter().step_by(4096).map(|&b| b as u64).sum();
         // Prevent compiler optimization
         std::hint::black_box(sum);
 
@@ -98,26 +320,209 @@ impl MmapIndex {
     pub fn connections(&self) -> &[u32] {
         let header = self.header();
         let start = header.connections_offset as usize;
-        let end = self.mmap.len();
+        // Older files have no size and no trailing sections: the arena runs to the end.
+        let end = if header.connections_size == 0 { self.mmap.len() } else { start + header.connections_size as usize };
         bytemuck::cast_slice(&self.mmap[start..end])
     }
+
+    /// Neighbor IDs of a node on one layer, straight from the connections arena.
+    /// Layout per node: [L0 count, L0 neighbors..., L1 count, L1 neighbors..., ...]
+    pub fn neighbors(&self, id: usize, level: usize) -> &[u32] {
+        let connections_arena = self.connections();
+        let mut offset = (self.nodes()[id].connections_offset as usize) / 4;
+        for _ in 0..level {
+            offset += 1 + connections_arena[offset] as usize;
+        }
+        let count = connections_arena[offset] as usize;
+        &connections_arena[offset + 1..offset + 1 + count]
+    }
+
+    /// Built-in metric the index was built with, `None` for a custom `Distance`.
+    /// Version 1 files were always normalized, i.e. cosine.
+    pub fn metric(&self) -> Option<Metric> {
+        header_metric(self.header())
+    }
+
+    pub fn distance(&self) -> &D {
+        &self.distance
+    }
+
+    /// Stage-1 representation the file stores.
+    pub fn quantization(&self) -> Quantization {
+        match (&self.pq, &self.sq) {
+            (Some(pq), _) => Quantization::Product { subspaces: pq.subspaces() },
+            (None, Some(_)) => Quantization::TrainedScalar8,
+            (None, None) if self.header().quantization == QUANTIZATION_SCALAR4 => Quantization::Scalar4,
+            (None, None) => Quantization::Scalar8,
+        }
+    }
+
+    pub fn scalar_quantizer(&self) -> Option<&TrainedQuantizer> {
+        self.sq.as_ref()
+    }
+
+    pub fn product_quantizer(&self) -> Option<&ProductQuantizer> {
+        self.pq.as_ref()
+    }
+
+    /// PQ code of a node, `Quantization::Product` indexes only.
+    pub fn get_pq_code(&self, id: usize) -> &[u8] {
+        let header = self.header();
+        let size = header.pq_subspaces as usize;
+        let start = header.pq_codes_offset as usize + id * size;
+        &self.mmap[start..start + size]
+    }
+
+    pub fn has_binary(&self) -> bool {
+        self.header().binary_offset != 0
+    }
+
+    /// Sign bits of a node (bit i = component i above its threshold), indexes saved with a binary arena only.
+    pub fn get_binary_code(&self, id: usize) -> &[u64] {
+        let header = self.header();
+        let words = (header.dimension as usize).div_ceil(64);
+        let start = header.binary_offset as usize + id * words * 8;
+        bytemuck::cast_slice(&self.mmap[start..start + words * 8])
+    }
+
+    /// Per-dimension thresholds of the binary arena, `None` without one.
+    pub fn binary_thresholds(&self) -> Option<&[f32]> {
+        let header = self.header();
+        if header.binary_offset == 0 {
+            return None;
+        }
+        let start = header.binary_thresholds_offset as usize;
+        Some(bytemuck::cast_slice(&self.mmap[start..start + header.dimension as usize * 4]))
+    }
+
+    /// Scale the quantized arena was divided by (see `Header::max_norm`).
+    pub fn max_norm(&self) -> f32 {
+        let header = self.header();
+        if header.version < 2 { 1.0 } else { header.max_norm as f32 }
+    }
+
+    /// Squared norm of each quantized-arena vector (before quantization), L2 indexes only.
+    pub fn norms(&self) -> Option<&[f32]> {
+        let header = self.header();
+        if header.norms_offset == 0 {
+            return None;
+        }
+        let start = header.norms_offset as usize;
+        Some(bytemuck::cast_slice(&self.mmap[start..start + header.num_elements as usize * 4]))
+    }
+
+    /// Deleted bitmap (bit i = node i), `None` if the index has no deletions.
+    pub fn deleted_bitmap(&self) -> Option<&[u64]> {
+        let header = self.header();
+        if header.deleted_offset == 0 {
+            return None;
+        }
+        let start = header.deleted_offset as usize;
+        let words = (header.num_elements as usize).div_ceil(64);
+        Some(bytemuck::cast_slice(&self.mmap[start..start + words * 8]))
+    }
+
+    pub fn is_deleted(&self, id: usize) -> bool {
+        self.deleted_bitmap().is_some_and(|bits| bits[id / 64] & (1 << (id % 64)) != 0)
+    }
+
+    /// External key of a node. Files written without an ID mapping use the node ID.
+    pub fn get_key(&self, id: usize) -> u64 {
+        let header = self.header();
+        if header.keys_offset == 0 {
+            return id as u64;
+        }
+        let start = header.keys_offset as usize + id * 8;
+        *bytemuck::from_bytes::<u64>(&self.mmap[start..start + 8])
+    }
+
+    /// Node ID of a live key (binary search over the on-disk key index).
+    pub fn get_id(&self, key: u64) -> Option<usize> {
+        let header = self.header();
+        if header.keys_offset == 0 {
+            let id = key as usize;
+            return (id < header.num_elements as usize && !self.is_deleted(id)).then_some(id);
+        }
+        let start = header.key_index_offset as usize;
+        let size = header.num_keys as usize * std::mem::size_of::<KeyEntry>();
+        let index: &[KeyEntry] = bytemuck::cast_slice(&self.mmap[start..start + size]);
+        index.binary_search_by_key(&key, |e| e.key).ok().map(|i| index[i].id as usize)
+    }
+
+    /// Full-precision vector of a key; `None` also when the file has no full vectors.
+    pub fn get_vector_by_key(&self, key: u64) -> Option<FullVector<'_>> {
+        self.get_id(key).filter(|_| self.has_full_vectors()).map(|id| self.full_vector(id))
+    }
     
-    /// Zero-Copy Accessor for Quantized Vectors (u8)
-    /// Returns a slice directly from mmap.
+    /// Zero-Copy Accessor for Quantized Vectors (u8, or nibble-packed u4 for `Quantization::Scalar4`)
+    /// Returns a slice directly from mmap. Scalar quantization only, not `Quantization::Product`.
     pub fn get_quantized_vector(&self, id: usize) -> &[u8] {
-        let dim = self.header().dimension as usize;
-        let start = self.header().quantized_vectors_offset as usize + (id * dim);
-        let end = start + dim;
+        let size = self.scalar_code_size();
+        let start = self.header().quantized_vectors_offset as usize + (id * size);
+        let end = start + size;
         &self.mmap[start..end]
     }
 
-    /// Zero-Copy Accessor for Full Precision Vectors (f32)
+    /// Bytes per node in the scalar arena.
+    fn scalar_code_size(&self) -> usize {
+        let header = self.header();
+        match header.quantization {
+            QUANTIZATION_SCALAR4 => (header.dimension as usize).div_ceil(2),
+            _ => header.dimension as usize,
+        }
+    }
+
+    /// Element type of the full-precision arena.
+    pub fn element_type(&self) -> ElementType {
+        match self.header().element_type {
+            ELEMENT_F16 => ElementType::F16,
+            ELEMENT_BF16 => ElementType::BF16,
+            _ => ElementType::F32,
+        }
+    }
+
+    /// False for an index saved with `VectorPlacement::Omitted`, or `Sidecar` without its sidecar file.
+    /// Such an index only serves quantized (`Rerank::Skip`) searches.
+    pub fn has_full_vectors(&self) -> bool {
+        self.header().vector_placement == VECTORS_INLINE || self.sidecar.is_some()
+    }
+
+    /// Zero-Copy Accessor for Full Precision Vectors, typed by `element_type`
     /// Returns a slice directly from mmap (using bytemuck for safety).
-    pub fn get_full_vector(&self, id: usize) -> &[f32] {
-        let dim = self.header().dimension as usize;
-        let start = self.header().vectors_offset as usize + (id * dim * 4);
-        let end = start + dim * 4;
-        bytemuck::cast_slice(&self.mmap[start..end])
+    /// Panics unless `has_full_vectors`.
+    pub fn full_vector(&self, id: usize) -> FullVector<'_> {
+        let (arena, stride) = self.full_vector_arena().expect("index has no full-precision vectors");
+        let bytes = &arena[id * stride..(id + 1) * stride];
+        match self.element_type() {
+            ElementType::F32 => FullVector::F32(bytemuck::cast_slice(bytes)),
+            ElementType::F16 => FullVector::F16(bytemuck::cast_slice(bytes)),
+            ElementType::BF16 => FullVector::BF16(bytemuck::cast_slice(bytes)),
+        }
+    }
+
+    /// The full-precision arena, inline or in the sidecar, and its stride in bytes (2 per
+    /// element for f16/bf16, 4 for f32). `None` unless `has_full_vectors`.
+    fn full_vector_arena(&self) -> Option<(&[u8], usize)> {
+        let stride = self.header().dimension as usize * if self.decode.is_some() { 2 } else { 4 };
+        match &self.sidecar {
+            Some(sidecar) => Some((&sidecar[std::mem::size_of::<VectorsHeader>()..], stride)),
+            None if self.has_full_vectors() => Some((&self.mmap[self.header().vectors_offset as usize..], stride)),
+            None => None,
+        }
+    }
+
+    /// `full_vector` as f32: zero-copy for an f32 arena, otherwise widened into `buf` with the
+    /// kernel chosen at load.
+    pub fn full_vector_into<'b>(&'b self, id: usize, buf: &'b mut Vec<f32>) -> &'b [f32] {
+        match self.full_vector(id) {
+            FullVector::F32(v) => v,
+            FullVector::F16(v) | FullVector::BF16(v) => {
+                buf.resize(v.len(), 0.0);
+                // Resolved for this arena's element type in `load_with_distance`
+                unsafe { (self.decode.unwrap())(v, buf) };
+                buf
+            }
+        }
     }
 
     // Deprecated: Old XOR get_vector (Removed)
@@ -125,81 +530,176 @@ impl MmapIndex {
     /// Two-Stage Search (Production Grade)
     /// Stage 1: Coarse Search using Quantized u8 vectors (AVX2/Scalar)
     /// Stage 2: Rerank top K candidates using Full Precision f32 vectors
-    pub fn search_two_stage(&self, query: &[f32], k: usize, ef_search: usize) -> Vec<(usize, f32)> {
-        use crate::core::quantization::Quantizer;
-        use crate::core::hardware::CpuFeatures;
-        
-        // 1. Quantize Query
-        let q_i8 = Quantizer::quantize_query(query);
-        
-        // 2. Select Metric
-        let features = CpuFeatures::detect();
-        let dist_func_u8: fn(&[i8], &[u8]) -> f32 = if features.avx2 {
-            // Unsafe wrapper? No, dot_product_u8_avx2 is unsafe.
-            // We need a safe wrapper or unsafe block.
-            // For max performance, we use function pointer to unsafe fn and call it likely?
-            // But fn types must match.
-            // wrapper:
-            |q, v| unsafe { crate::simd::int8::dot_product_u8_avx2(q, v) }
-        } else {
-            crate::simd::int8::dot_product_u8_scalar
+    /// (`search_with_params` with `Cascade::Binary` adds a Hamming stage in front.)
+    /// Returns `(key, distance)` pairs, closest first.
+    /// Scratch lives in a `SearchContext` dropped on return, so nothing sized to the index
+    /// outlives the call; hot loops should own one and call `search_two_stage_with` instead.
+    pub fn search_two_stage(&self, query: &[f32], k: usize, ef_search: usize) -> Vec<(u64, f32)> {
+        self.search_with_params(query, k, SearchParams::new(ef_search))
+    }
+
+    /// Two-Stage Search driven by `SearchParams` (same parameters as `HNSW::search_with_params`).
+    pub fn search_with_params(&self, query: &[f32], k: usize, params: SearchParams) -> Vec<(u64, f32)> {
+        self.search_with_context(&mut SearchContext::new(), query, k, params).to_vec()
+    }
+
+    /// `search_two_stage` with caller-owned buffers: once `ctx` has warmed up, no heap allocation.
+    /// The returned slice lives in `ctx` and is overwritten by its next search.
+    pub fn search_two_stage_with<'c>(&self, ctx: &'c mut SearchContext, query: &[f32], k: usize, ef_search: usize) -> &'c [(u64, f32)] {
+        self.search_with_context(ctx, query, k, SearchParams::new(ef_search))
+    }
+
+    /// `search_with_params` with caller-owned buffers (see `search_two_stage_with`).
+    pub fn search_with_context<'c>(&self, ctx: &'c mut SearchContext, query: &[f32], k: usize, params: SearchParams) -> &'c [(u64, f32)] {
+        use crate::core::quantization::{QuantizedScorer, Quantizer};
+
+        // 1. Prepare Query
+        let mut q = std::mem::take(&mut ctx.query);
+        q.clear();
+        q.extend_from_slice(query);
+        self.distance.prepare(&mut q);
+
+        let ef = k.max(params.ef);
+        let metric = match self.metric() {
+            Some(metric) if self.distance.metric() == Some(metric) => metric,
+            // The quantized arena only approximates the built-in metrics: score exactly in f32.
+            _ => {
+                let arena = self.full_vector_arena();
+                let mut buf = std::mem::take(&mut ctx.decoded);
+                self.search_graph(ctx, ef, arena, |id| self.distance.distance(&q, self.full_vector_into(id, &mut buf)));
+                ctx.decoded = buf;
+                ctx.scored.clear();
+                ctx.scored.extend(ctx.w.drain().map(|c| (c.node_id, c.distance)));
+                ctx.query = q;
+                return self.take_results(ctx, k);
+            }
         };
 
-        // 3. Search Graph (Coarse)
-        // Returns candidates (NodeID, Distance)
-        // We use ef_search for the graph traversal
-        let candidates = self.search_graph_u8(&q_i8, k.max(ef_search), dist_func_u8);
+        // 2. Search Graph (Coarse)
+        // Traverses on the binary or stage-1 arena and leaves `(node, stage-1 score)` pairs in ctx.scored.
+        let mut table = std::mem::take(&mut ctx.pq_table);
+        let mut q_i8 = std::mem::take(&mut ctx.query_i8);
+        let norms = self.norms().filter(|_| metric == Metric::L2);
+        let scorer = match (&self.pq, &self.sq) {
+            // ADC against the prepared query, which is what the codes were encoded from
+            (Some(pq), _) => {
+                pq.distance_table(&q, metric, &mut table);
+                None
+            }
+            // Trained ranges were learned on the stored (prepared) vectors
+            (None, Some(sq)) => {
+                let transform = sq.quantize_query_into(&q, &mut q_i8);
+                Some(QuantizedScorer::trained(&self.kernels, norms.is_some(), self.max_norm(), transform))
+            }
+            (None, None) => {
+                Quantizer::quantize_query_into(query, &mut q_i8);
+                Some(match self.quantization() {
+                    Quantization::Scalar4 => QuantizedScorer::int4(&self.kernels, norms.is_some(), self.max_norm(), query, &q_i8),
+                    _ => QuantizedScorer::new(&self.kernels, norms.is_some(), self.max_norm(), query, &q_i8),
+                })
+            }
+        };
+        let stage1 = |id: usize| match (&self.pq, &scorer) {
+            (Some(pq), _) => pq.adc(&table, self.get_pq_code(id)),
+            (None, Some(scorer)) => scorer.score(&q_i8, self.get_quantized_vector(id), norms.map_or(0.0, |norms| norms[id])),
+            (None, None) => unreachable!(),
+        };
 
-        
-        // 4. Rerank (Fine)
-        // We take ALL candidates found (or top N? usually ef_search results)
-        // And re-calculate f32 distance.
-        let mut results: Vec<(usize, f32)> = candidates.iter().map(|c| {
-            let f_vec = self.get_full_vector(c.node_id);
-            // Use standard Euclidean for f32 rerank (High Precision)
-            // Note: query is NOT normalized in f32 space for distance calc? 
-            // Phase 1 says "vectors are L2 normalized". 
-            // If we want Dot Product logic for f32, we should use Dot Product?
-            // The user asked for "L2 Distance" in Phase 1 setup.
-            // But if vectors are normalized, L2 and Dot Product are equivalent monotonic.
-            // Let's use Euclidean to be safe and precise as requested in "Stage 2".
-            let dist = unsafe { crate::simd::avx2::euclidean_distance_avx2(query, f_vec) };
-            (c.node_id, dist)
-        }).collect();
-        
-        // 5. Sort and Take K
-        results.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap());
-        results.truncate(k);
-        
+        let keep = match (params.cascade, self.binary_thresholds()) {
+            (Cascade::Binary { keep }, Some(thresholds)) => {
+                // Hamming traversal, then the ef candidates are rescored on the stage-1 arena
+                let mut bits = std::mem::take(&mut ctx.query_bits);
+                sign_bits_into(&q, thresholds, &mut bits);
+                let hamming = self.kernels.hamming;
+                let words = bits.len();
+                let arena = (&self.mmap[self.header().binary_offset as usize..], words * 8);
+                self.search_graph(ctx, ef, Some(arena), |id| unsafe {
+                    hamming(&bits, self.get_binary_code(id)) as f32
+                });
+                ctx.query_bits = bits;
+                ctx.scored.clear();
+                ctx.scored.extend(ctx.w.drain().map(|c| (c.node_id, stage1(c.node_id))));
+                keep.max(k)
+            }
+            _ => {
+                self.search_graph(ctx, ef, Some(self.stage1_arena()), stage1);
+                ctx.scored.clear();
+                ctx.scored.extend(ctx.w.drain().map(|c| (c.node_id, c.distance)));
+                ef
+            }
+        };
+        ctx.pq_table = table;
+        ctx.query_i8 = q_i8;
+
+        // 3. Rerank (Fine)
+        // The best `keep` candidates (capped by params.rerank) are re-scored in full precision
+        // against the prepared query. Without a rerank the stage-1 scores are the result.
+        let rerank = match params.rerank {
+            _ if !self.has_full_vectors() => None,
+            Rerank::All => Some(keep),
+            Rerank::Top(n) => Some(keep.min(n.max(k))),
+            Rerank::Skip => None,
+        };
+        if let Some(keep) = rerank {
+            if keep < ctx.scored.len() {
+                ctx.scored.select_nth_unstable_by(keep, |a, b| a.1.partial_cmp(&b.1).unwrap());
+                ctx.scored.truncate(keep);
+            }
+            for (id, dist) in ctx.scored.iter_mut() {
+                *dist = self.distance.distance(&q, self.full_vector_into(*id, &mut ctx.decoded));
+            }
+        }
+        ctx.query = q;
+
+        self.take_results(ctx, k)
+    }
+
+    /// Closest `k` of `ctx.scored`, mapped to keys.
+    fn take_results<'c>(&self, ctx: &'c mut SearchContext, k: usize) -> &'c [(u64, f32)] {
+        let SearchContext { scored, results, .. } = ctx;
+        // 4. Sort and Take K
+        scored.sort_unstable_by(|a, b| a.1.partial_cmp(&b.1).unwrap());
+        scored.truncate(k);
+
+        results.clear();
+        results.extend(scored.iter().map(|&(id, dist)| (self.get_key(id), dist)));
         results
     }
 
-    fn search_graph_u8(&self, q_i8: &[i8], ef: usize, dist_func: fn(&[i8], &[u8]) -> f32) -> Vec<Candidate> {
-        use std::collections::BinaryHeap;
+    /// `(offset, stride)` of the stage-1 arena (u8/u4 vectors or PQ codes).
+    fn stage1_arena(&self) -> (&[u8], usize) {
+        let header = self.header();
+        match self.pq {
+            Some(_) => (&self.mmap[header.pq_codes_offset as usize..], header.pq_subspaces as usize),
+            None => (&self.mmap[header.quantized_vectors_offset as usize..], self.scalar_code_size()),
+        }
+    }
+
+    /// Greedy descent to layer 0, then an `ef`-wide beam search there, scoring nodes with
+    /// `score(node)`. `arena` is the `(bytes, stride)` of what `score` reads, for prefetching;
+    /// `None` skips the prefetch. The best `ef` live candidates are left in `ctx.w`.
+    fn search_graph(&self, ctx: &mut SearchContext, ef: usize, arena: Option<(&[u8], usize)>, mut score: impl FnMut(usize) -> f32) {
         use std::cmp::Reverse;
-        use std::cell::RefCell;
         #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
         use std::arch::x86_64::{_mm_prefetch, _MM_HINT_T0};
 
-        thread_local! {
-            static VISITED_VERSIONS: RefCell<Vec<u64>> = RefCell::new(Vec::new());
-            static CURRENT_VERSION: RefCell<u64> = RefCell::new(0);
-        }
-
         let header = self.header();
         let num_nodes = header.num_elements as usize;
         let entry_point = header.entry_point_id as usize;
         let max_layer = header.max_layer as usize;
 
+        ctx.reset(num_nodes);
         if num_nodes == 0 {
-            return Vec::new();
+            return;
         }
-        
+        let SearchContext { visited, epoch, candidates, w, .. } = ctx;
+        let epoch = *epoch;
+
         // 1. Zoom Logic (Layers max down to 1)
         // We use greedy search here.
         let mut curr_obj = entry_point;
 
-        let mut curr_dist = dist_func(q_i8, self.get_quantized_vector(curr_obj));
+        let mut curr_dist = score(curr_obj);
 
         let nodes = self.nodes();
         let connections_arena = self.connections();
@@ -210,30 +710,23 @@ impl MmapIndex {
                 changed = false;
                 let node = &nodes[curr_obj];
                 let mut offset = (node.connections_offset as usize) / 4;
-                
-                // Skip to level
+
                 // Offset structure: [Layer 0 Count, L0 Neighbors..., Layer 1 Count...]
-                // We need to traverse to find the level offset.
-                // This linear scan of levels is slow?
-                // Connection offset points to START of connection block.
-                // We have explicit layer_count.
-                // But variable size per layer.
-                // Loop to reach 'level'.
+                // Lists are variable size, so walk the counts up to 'level'.
                 // Since max_layer is small (e.g. 5-7), loop is fine.
-                
                 for _ in 0..level {
                     let count = connections_arena[offset] as usize;
                     offset += 1 + count;
                 }
-                
+
                 let count = connections_arena[offset] as usize;
                 offset += 1;
-                
+
                 for _ in 0..count {
                     let neighbor_id = connections_arena[offset] as usize;
                     offset += 1;
-                    
-                    let d = dist_func(q_i8, self.get_quantized_vector(neighbor_id));
+
+                    let d = score(neighbor_id);
                     if d < curr_dist {
                         curr_dist = d;
                         curr_obj = neighbor_id;
@@ -242,124 +735,71 @@ impl MmapIndex {
                 }
             }
         }
-        
+
         // 2. Layer 0 Search (EF Search)
-        VISITED_VERSIONS.with(|v_cell| {
-            CURRENT_VERSION.with(|c_cell| {
-                let mut visited = v_cell.borrow_mut();
-                let mut version = c_cell.borrow_mut();
-                
-                *version += 1;
-                let my_version = *version;
-
-                if visited.len() < num_nodes {
-                    visited.resize(num_nodes, 0);
+        // candidates: Min-Heap (Reverse) of nodes to expand, closest to query first.
+        // w: Max-Heap of the 'ef' best found so far, worst on top.
+        visited[curr_obj] = epoch;
+
+        // Deleted nodes are expanded like any other but never enter W.
+        let deleted = self.deleted_bitmap();
+        let is_deleted = |id: usize| deleted.is_some_and(|bits| bits[id / 64] & (1 << (id % 64)) != 0);
+
+        let c = Candidate { distance: curr_dist, node_id: curr_obj };
+        candidates.push(Reverse(c.clone()));
+        if !is_deleted(curr_obj) {
+            w.push(c);
+        }
+
+        while let Some(Reverse(c_closest)) = candidates.pop() {
+            let c_dist = c_closest.distance;
+            let c_node_id = c_closest.node_id;
+
+            // Stop condition: if closest candidate in heap is worse than worst in W, and W is full
+            if let Some(w_worst) = w.peek() {
+                if c_dist > w_worst.distance && w.len() >= ef {
+                    break;
                 }
-                
-                // Start EF Search
-                let mut candidates = BinaryHeap::new(); // Min-Heap of candidates (Reverse) to expand
-                let mut w = BinaryHeap::new(); // Max-Heap of found results to keep top EF
-                 // Actually logic is: W is set of found candidates.
-                 // We want W to maintain 'ef' best.
-                 // So W is Max-Heap of distance (pop worst).
-                 // candidates is Min-Heap of distance (pop closest to query to explore).
-                
-                visited[curr_obj] = my_version;
-                
-                // We already have curr_obj from Zoom phase
-                // Insert to heaps
-                // Wait, w shoud store Candidate. Max-Heap.
-                // candidates stores Reverse(Candidate). Min-Heap.
-                
-                let c = Candidate { distance: curr_dist, node_id: curr_obj };
-                candidates.push(Reverse(c.clone()));
-                w.push(c);
-                
-                while let Some(Reverse(c_closest)) = candidates.pop() {
-                    let c_dist = c_closest.distance;
-                    let c_node_id = c_closest.node_id;
-                    
-                    // Stop condition: if closest candidate in heap is worse than worst in W, and W is full
-                    if let Some(w_worst) = w.peek() {
-                         if c_dist > w_worst.distance && w.len() >= ef {
-                             break;
-                         }
-                    }
+            }
 
-                    let node = &nodes[c_node_id];
-                    let mut offset = (node.connections_offset as usize) / 4;
-                    // Layer 0 is first. Simple.
-                    let count = connections_arena[offset] as usize;
-                    offset += 1;
-                    
-                    let neighbor_ids = &connections_arena[offset..offset+count];
-                    
-                    // PREFETCH NEXT
-                    // Issue prefetch for first neighbor's vector
-                    /* 
+            let node = &nodes[c_node_id];
+            let mut offset = (node.connections_offset as usize) / 4;
+            // Layer 0 is first. Simple.
+            let count = connections_arena[offset] as usize;
+            offset += 1;
+
+            let neighbor_ids = &connections_arena[offset..offset+count];
+
+            for &neighbor_id in neighbor_ids {
+                let nid = neighbor_id as usize;
+                if visited[nid] != epoch {
+                    visited[nid] = epoch;
+
+                    // Prefetch vector (L1), only within the arena's mapping
                     #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-                    if !neighbor_ids.is_empty() {
-                         let next_ptr = self.get_quantized_vector(neighbor_ids[0] as usize).as_ptr();
-                         unsafe { _mm_prefetch(next_ptr as *const i8, _MM_HINT_T0); }
+                    if let Some(bytes) = arena.and_then(|(bytes, stride)| bytes.get(nid * stride..)) {
+                        unsafe { _mm_prefetch(bytes.as_ptr() as *const i8, _MM_HINT_T0) };
                     }
-                    */
-
-                    for &neighbor_id in neighbor_ids {
-                        let nid = neighbor_id as usize;
-                        if visited[nid] != my_version {
-                            visited[nid] = my_version;
-                            
-                            // Prefetch vector (L1)
-                            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-                            unsafe {
-                                let ptr = self.header().quantized_vectors_offset as usize + (nid * self.header().dimension as usize);
-                                let ptr_addr = self.mmap.as_ptr().add(ptr);
-                                _mm_prefetch(ptr_addr as *const i8, _MM_HINT_T0);
-                            }
-
-                            let dist = dist_func(q_i8, self.get_quantized_vector(nid));
-                            
-                            // Logic to add to W
-                            let do_add = if w.len() < ef {
-                                true
-                            } else {
-                                dist < w.peek().unwrap().distance
-                            };
-                            
-                            if do_add {
-                                let nc = Candidate { distance: dist, node_id: nid };
-                                candidates.push(Reverse(nc.clone()));
-                                w.push(nc);
-                                if w.len() > ef {
-                                    w.pop();
-                                }
-                            }
+
+                    let dist = score(nid);
+
+                    // Logic to add to W
+                    let do_add = w.len() < ef || w.peek().is_some_and(|worst| dist < worst.distance);
+
+                    if do_add {
+                        let nc = Candidate { distance: dist, node_id: nid };
+                        candidates.push(Reverse(nc.clone()));
+                        if is_deleted(nid) {
+                            continue;
+                        }
+                        w.push(nc);
+                        if w.len() > ef {
+                            w.pop();
                         }
                     }
                 }
-                
-                // Return contents of W (candidates)
-                // W is a BinaryHeap (Max Heap).
-                w.into_vec()
-            })
-        })
-    }
-}
-
-#[derive(Debug, Clone, PartialEq)]
-struct Candidate {
-    distance: f32,
-    node_id: usize,
-}
-impl Eq for Candidate {}
-impl PartialOrd for Candidate {
-    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        other.distance.partial_cmp(&self.distance)
-    }
-}
-impl Ord for Candidate {
-    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        self.partial_cmp(other).unwrap_or(std::cmp::Ordering::Equal)
+            }
+        }
     }
 }
 
@@ -367,16 +807,19 @@ impl Ord for Candidate {
 mod tests {
     use super::*;
     use crate::core::hnsw::HNSW;
+    use crate::core::config::SaveOptions;
+    use crate::core::flat::FlatIndex;
+    use crate::core::hnsw::tests::{test_builder, test_config};
     use tempfile::NamedTempFile;
 
     #[test]
     fn test_save_load_quantized() -> Result<(), Box<dyn std::error::Error>> {
-        let mut index = HNSW::new(4, 10, 5, 10);
+        let mut index = HNSW::new(test_builder(3, 4, 10, 5, 10).metric(Metric::Cosine).build()?)?;
         
         // Insert 3 vectors
-        index.insert(vec![1.0, 1.0, 1.0]); 
-        index.insert(vec![-1.0, -1.0, -1.0]); 
-        index.insert(vec![0.0, 0.0, 0.0]); 
+        index.insert(vec![1.0, 1.0, 1.0])?; 
+        index.insert(vec![-1.0, -1.0, -1.0])?; 
+        index.insert(vec![0.0, 0.0, 0.0])?; 
 
         let temp_file = NamedTempFile::new()?;
         let path = temp_file.path();
@@ -391,7 +834,7 @@ mod tests {
         assert_eq!(header.dimension, 3);
         
         // Verify Quantized Vector (ID 2 is 0.0 -> normalized is INVALID? No, 0,0,0 norm is 0 handled safely?)
-        // HNSW insert does NOT normalize, but save() DOES.
+        // Cosine indexes normalize on insert.
         // vec![0,0,0] norm is 0. Quantizer should handle it?
         // Quantizer::l2_normalize checks sum > epsilon. If 0, stays 0.
         // Quantizer::quantize_u8: 0 maps to ((0+1)/2)*255 = 127.
@@ -401,7 +844,7 @@ mod tests {
         assert_eq!(q_vec[0], 127); // Expecting mid-range
         
         // Verify Full Vector
-        let f_vec = mmap_index.get_full_vector(0);
+        let f_vec = mmap_index.full_vector(0).to_vec();
         assert_eq!(f_vec.len(), 3);
         // [1,1,1] normalized is [0.577, 0.577, 0.577]
         assert!((f_vec[0] - 0.577).abs() < 0.001);
@@ -411,15 +854,15 @@ mod tests {
 
     #[test]
     fn test_search_two_stage() -> Result<(), Box<dyn std::error::Error>> {
-        let mut index = HNSW::new(4, 10, 5, 10);
+        let mut index = HNSW::new(test_config(3, 4, 10, 5, 10))?;
         
         // Insert data: standard basis
         // ID 0: X axis
         // ID 1: Y axis
         // ID 2: Z axis
-        index.insert(vec![1.0, 0.0, 0.0]); 
-        index.insert(vec![0.0, 1.0, 0.0]); 
-        index.insert(vec![0.0, 0.0, 1.0]); 
+        index.insert(vec![1.0, 0.0, 0.0])?; 
+        index.insert(vec![0.0, 1.0, 0.0])?; 
+        index.insert(vec![0.0, 0.0, 1.0])?; 
 
         let temp_file = NamedTempFile::new()?;
         let path = temp_file.path();
@@ -441,4 +884,679 @@ mod tests {
         
         Ok(())
     }
+
+    #[test]
+    fn test_metrics_rank_like_brute_force() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::simd::distance::{dot_product, euclidean_distance};
+        use rand::rngs::StdRng;
+        use rand::{Rng, SeedableRng};
+
+        // Norms spread over 0.5..5 so the three metrics disagree about the nearest neighbors.
+        let mut rng = StdRng::seed_from_u64(13);
+        let data: Vec<Vec<f32>> = (0..1000).map(|_| {
+            let scale = rng.gen_range(0.5..5.0);
+            (0..16).map(|_| rng.gen_range(-1.0f32..1.0) * scale).collect()
+        }).collect();
+        let queries: Vec<Vec<f32>> = (0..30).map(|_| (0..16).map(|_| rng.gen_range(-2.0f32..2.0)).collect()).collect();
+
+        for metric in [Metric::L2, Metric::InnerProduct, Metric::Cosine] {
+            let exact = |q: &[f32], v: &[f32]| match metric {
+                Metric::L2 => euclidean_distance(q, v),
+                Metric::InnerProduct => -dot_product(q, v),
+                Metric::Cosine => 1.0 - crate::simd::distance::cosine_similarity(q, v),
+            };
+
+            // Serial and seeded, so the graph is the same on every run
+            let mut index = HNSW::new(test_builder(16, 6, 100, 12, 24).metric(metric).seed(7).build()?)?;
+            for v in &data {
+                index.insert(v.clone())?;
+            }
+            let temp_file = NamedTempFile::new()?;
+            index.save(temp_file.path())?;
+            let mmap_index = MmapIndex::load(temp_file.path())?;
+            assert_eq!(mmap_index.metric(), Some(metric));
+
+            let (mut hnsw_hits, mut mmap_hits) = (0, 0);
+            for query in &queries {
+                let mut truth: Vec<(u64, f32)> = data.iter().enumerate().map(|(id, v)| (id as u64, exact(query, v))).collect();
+                truth.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap());
+                let truth: Vec<u64> = truth.iter().take(10).map(|(id, _)| *id).collect();
+
+                let from_hnsw = index.search_with_params(query, 10, SearchParams::new(200));
+                let from_mmap = mmap_index.search_two_stage(query, 10, 200);
+                hnsw_hits += from_hnsw.iter().filter(|(key, _)| truth.contains(key)).count();
+                mmap_hits += from_mmap.iter().filter(|(key, _)| truth.contains(key)).count();
+
+                // Reported distances are the exact metric distance
+                let (key, dist) = from_mmap[0];
+                assert!((dist - exact(query, &data[key as usize])).abs() < 1e-3, "{:?}: {} vs {}", metric, dist, exact(query, &data[key as usize]));
+            }
+            let total = (queries.len() * 10) as f32;
+            let (hnsw_recall, mmap_recall) = (hnsw_hits as f32 / total, mmap_hits as f32 / total);
+            assert!(hnsw_recall >= 0.9, "{:?} hnsw recall {}", metric, hnsw_recall);
+            // The quantized stage must not lose what the graph finds
+            assert!(mmap_recall >= 0.9, "{:?} mmap recall {}", metric, mmap_recall);
+        }
+
+        Ok(())
+    }
+
+    /// Vectors drawn uniformly from `range`, the same on every run.
+    fn uniform_data(n: usize, dim: usize, range: std::ops::Range<f32>, seed: u64) -> Vec<Vec<f32>> {
+        use rand::rngs::StdRng;
+        use rand::{Rng, SeedableRng};
+
+        let mut rng = StdRng::seed_from_u64(seed);
+        (0..n).map(|_| (0..dim).map(|_| rng.gen_range(range.clone())).collect()).collect()
+    }
+
+    /// `uniform_data` over -1..1 with norms spread over 0.5..5, so the three metrics disagree.
+    fn scaled_data(n: usize, dim: usize, seed: u64) -> Vec<Vec<f32>> {
+        use rand::rngs::StdRng;
+        use rand::{Rng, SeedableRng};
+
+        let mut rng = StdRng::seed_from_u64(seed);
+        uniform_data(n, dim, -1.0..1.0, seed).into_iter().map(|v| {
+            let scale = rng.gen_range(0.5..5.0);
+            v.into_iter().map(|x| x * scale).collect()
+        }).collect()
+    }
+
+    /// A graph over `data` inserted serially with a seeded config, so it is the same on every run,
+    /// and the exhaustive index that is its ground truth.
+    fn seeded_index(data: &[Vec<f32>], metric: Metric) -> Result<(HNSW, FlatIndex), Box<dyn std::error::Error>> {
+        let dim = data[0].len();
+        let mut index = HNSW::new(test_builder(dim, 6, 100, 12, 24).metric(metric).seed(7).build()?)?;
+        let mut flat = FlatIndex::new(dim, metric);
+        for v in data {
+            index.insert(v.clone())?;
+            flat.insert(v.clone())?;
+        }
+        Ok((index, flat))
+    }
+
+    /// Saves `index` with `options` and maps it back; the file lives as long as the returned handle.
+    fn save_and_load(index: &HNSW, options: &SaveOptions) -> Result<(NamedTempFile, MmapIndex), Box<dyn std::error::Error>> {
+        let temp_file = NamedTempFile::new()?;
+        index.save_with(temp_file.path(), options)?;
+        let mmap_index = MmapIndex::load(temp_file.path())?;
+        Ok((temp_file, mmap_index))
+    }
+
+    /// How many of `results` are among `truth`.
+    fn hits(results: &[(u64, f32)], truth: &[(u64, f32)]) -> usize {
+        results.iter().filter(|(key, _)| truth.iter().any(|t| t.0 == *key)).count()
+    }
+
+    #[test]
+    fn test_product_quantized_search() -> Result<(), Box<dyn std::error::Error>> {
+        let data = scaled_data(2000, 16, 1);
+        let queries = uniform_data(30, 16, -2.0..2.0, 2);
+        let options = SaveOptions { quantization: Quantization::Product { subspaces: 4 }, ..SaveOptions::default() };
+
+        for metric in [Metric::L2, Metric::InnerProduct, Metric::Cosine] {
+            let (index, flat) = seeded_index(&data, metric)?;
+            let temp_file = NamedTempFile::new()?;
+            assert!(index.save_with(temp_file.path(), &SaveOptions { quantization: Quantization::Product { subspaces: 5 }, ..SaveOptions::default() }).is_err());
+            let (_file, mmap_index) = save_and_load(&index, &options)?;
+            assert_eq!(mmap_index.quantization(), options.quantization);
+            assert_eq!((mmap_index.header().quantized_vectors_offset, mmap_index.get_pq_code(0).len()), (0, 4));
+
+            let (mut hnsw_hits, mut mmap_hits) = (0, 0);
+            for query in &queries {
+                let truth = flat.search(query, 10);
+                let from_mmap = mmap_index.search_two_stage(query, 10, 200);
+                hnsw_hits += hits(&index.search_with_params(query, 10, SearchParams::new(200)), &truth);
+                mmap_hits += hits(&from_mmap, &truth);
+
+                // Codes only steer the traversal, the f32 rerank reports exact distances
+                let (key, dist) = from_mmap[0];
+                let expected = flat.search(query, 2000).into_iter().find(|t| t.0 == key).unwrap().1;
+                assert!((dist - expected).abs() < 1e-4, "{:?}: {} vs {}", metric, dist, expected);
+            }
+            let total = (queries.len() * 10) as f32;
+            let (hnsw_recall, mmap_recall) = (hnsw_hits as f32 / total, mmap_hits as f32 / total);
+            assert!(mmap_recall >= hnsw_recall - 0.05, "{:?} pq recall {} vs hnsw {}", metric, mmap_recall, hnsw_recall);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_binary_cascade_search() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::core::search::Cascade;
+
+        // Off-center data: the per-dimension thresholds, not zero, must split it
+        let data = uniform_data(2000, 96, 0.0..1.0, 3);
+        let queries = uniform_data(20, 96, 0.0..1.0, 4);
+        let binary = SearchParams::new(400).with_cascade(Cascade::Binary { keep: 100 });
+
+        for metric in [Metric::L2, Metric::Cosine] {
+            let (index, flat) = seeded_index(&data, metric)?;
+            let (temp_file, mmap_index) = save_and_load(&index, &SaveOptions { binary: true, ..SaveOptions::default() })?;
+            assert!(mmap_index.has_binary());
+            assert_eq!(mmap_index.get_binary_code(0).len(), 2);
+
+            let (mut quantized_hits, mut binary_hits) = (0, 0);
+            for query in &queries {
+                let truth = flat.search(query, 10);
+                let from_binary = mmap_index.search_with_params(query, 10, binary);
+                quantized_hits += hits(&mmap_index.search_with_params(query, 10, SearchParams::new(200)), &truth);
+                binary_hits += hits(&from_binary, &truth);
+
+                // Survivors are reranked in f32
+                let expected = flat.search(query, 2000).into_iter().find(|t| t.0 == from_binary[0].0).unwrap().1;
+                assert!((from_binary[0].1 - expected).abs() < 1e-4);
+            }
+            let total = (queries.len() * 10) as f32;
+            let (quantized_recall, binary_recall) = (quantized_hits as f32 / total, binary_hits as f32 / total);
+            assert!(binary_recall >= quantized_recall - 0.1, "{:?} binary recall {} vs quantized {}", metric, binary_recall, quantized_recall);
+
+            // Without a binary arena the cascade falls back to the stage-1 arena
+            index.save(temp_file.path())?;
+            let plain = MmapIndex::load(temp_file.path())?;
+            assert!(!plain.has_binary());
+            assert_eq!(plain.search_with_params(&queries[0], 10, binary), plain.search_two_stage(&queries[0], 10, 400));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_trained_scalar_recall() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::core::quantization::Quantizer;
+        use crate::simd::int8::dot_product_u8_scalar;
+
+        // Normalized 256-d embeddings: components are around +-0.06, a sliver of the fixed [-1, 1] range
+        let data = uniform_data(1000, 256, -1.0..1.0, 5);
+        let queries = uniform_data(200, 256, -1.0..1.0, 6);
+        let (index, flat) = seeded_index(&data, Metric::Cosine)?;
+        let (_fixed_file, fixed) = save_and_load(&index, &SaveOptions::default())?;
+        let (_trained_file, trained) = save_and_load(&index, &SaveOptions { quantization: Quantization::TrainedScalar8, ..SaveOptions::default() })?;
+        assert_eq!(trained.quantization(), Quantization::TrainedScalar8);
+        let sq = trained.scalar_quantizer().unwrap();
+
+        // Stage-1 recall@10 of a full scan over each u8 arena
+        let (mut fixed_hits, mut trained_hits, mut reranked_hits, mut q8) = (0, 0, 0, Vec::new());
+        for query in &queries {
+            let truth = flat.search(query, 10);
+            let top10 = |index: &MmapIndex, q8: &[i8]| {
+                let mut scores: Vec<(usize, f32)> = (0..1000).map(|id| (id, dot_product_u8_scalar(q8, index.get_quantized_vector(id)))).collect();
+                scores.sort_by(|a, b| a.1.total_cmp(&b.1));
+                scores.into_iter().take(10).filter(|(id, _)| truth.iter().any(|t| t.0 == *id as u64)).count()
+            };
+            fixed_hits += top10(&fixed, &Quantizer::quantize_query(query));
+            let mut unit = query.clone();
+            Quantizer::l2_normalize(&mut unit);
+            sq.quantize_query_into(&unit, &mut q8);
+            trained_hits += top10(&trained, &q8);
+
+            // End to end the reranked results are as good as the exact scan
+            reranked_hits += hits(&trained.search_two_stage(query, 10, 100), &truth);
+        }
+        let total = (queries.len() * 10) as f32;
+        let (fixed_recall, trained_recall) = (fixed_hits as f32 / total, trained_hits as f32 / total);
+        assert!(trained_recall > fixed_recall + 0.03, "trained stage-1 recall {} vs fixed {}", trained_recall, fixed_recall);
+        assert!(reranked_hits as f32 / total >= 0.95, "reranked recall {}", reranked_hits as f32 / total);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_int4_search() -> Result<(), Box<dyn std::error::Error>> {
+        let data = scaled_data(2000, 33, 7);
+        let queries = uniform_data(30, 33, -2.0..2.0, 8);
+        let options = SaveOptions { quantization: Quantization::Scalar4, ..SaveOptions::default() };
+
+        for metric in [Metric::L2, Metric::InnerProduct, Metric::Cosine] {
+            let (index, flat) = seeded_index(&data, metric)?;
+            let (_file, mmap_index) = save_and_load(&index, &options)?;
+            assert_eq!(mmap_index.quantization(), Quantization::Scalar4);
+            assert_eq!(mmap_index.get_quantized_vector(1999).len(), 17);
+
+            let (mut hnsw_hits, mut mmap_hits) = (0, 0);
+            for query in &queries {
+                let truth = flat.search(query, 10);
+                hnsw_hits += hits(&index.search_with_params(query, 10, SearchParams::new(200)), &truth);
+                mmap_hits += hits(&mmap_index.search_two_stage(query, 10, 200), &truth);
+            }
+            let total = (queries.len() * 10) as f32;
+            let (hnsw_recall, mmap_recall) = (hnsw_hits as f32 / total, mmap_hits as f32 / total);
+            assert!(mmap_recall >= hnsw_recall - 0.05, "{:?} int4 recall {} vs hnsw {}", metric, mmap_recall, hnsw_recall);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_half_precision_arena() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::core::config::ElementType;
+
+        let data = uniform_data(1000, 37, -3.0..3.0, 9);
+        let queries = uniform_data(20, 37, -3.0..3.0, 10);
+
+        for metric in [Metric::L2, Metric::Cosine] {
+            let (mut index, _) = seeded_index(&data, metric)?;
+            index.delete(3);
+            let (full_file, full) = save_and_load(&index, &SaveOptions::default())?;
+
+            for element_type in [ElementType::F16, ElementType::BF16] {
+                let (temp_file, half) = save_and_load(&index, &SaveOptions { element_type, ..SaveOptions::default() })?;
+                assert_eq!(half.element_type(), element_type);
+                assert!(matches!(crate::core::diagnostics::Diagnostics::check_health(&half), crate::core::diagnostics::HealthStatus::Healthy));
+                // The rerank arena shrinks from 4 to 2 bytes per component
+                let saved = std::fs::metadata(full_file.path())?.len() - std::fs::metadata(temp_file.path())?.len();
+                assert!(saved >= 1000 * 37 * 2, "{:?} saved {} bytes", element_type, saved);
+                assert!(half.is_deleted(3));
+
+                // Stored components are within the type's rounding of the f32 ones
+                let tolerance = if element_type == ElementType::F16 { 1.0 / 1024.0 } else { 1.0 / 128.0 };
+                let widened = half.full_vector(7).to_vec();
+                for (a, b) in full.full_vector(7).to_vec().iter().zip(&widened) {
+                    assert!((a - b).abs() <= a.abs() * tolerance, "{} vs {}", a, b);
+                }
+                let mut buf = Vec::new();
+                assert_eq!(half.full_vector_into(7, &mut buf), &widened[..]);
+
+                // Reranking on the narrower arena returns nearly the same neighbors and distances
+                let mut overlap = 0;
+                for query in &queries {
+                    let expected = full.search_two_stage(query, 10, 100);
+                    let results = half.search_two_stage(query, 10, 100);
+                    overlap += results.iter().filter(|(key, _)| expected.iter().any(|e| e.0 == *key)).count();
+                    assert!((results[0].1 - expected[0].1).abs() <= expected[0].1.abs() * tolerance * 4.0 + 1e-3);
+                }
+                assert!(overlap >= queries.len() * 10 * 9 / 10, "{:?} {:?} overlap {}", metric, element_type, overlap);
+
+                // Reloading into memory widens the vectors back
+                let restored = HNSW::from_mmap(&half)?;
+                assert_eq!(restored.get_vector_by_key(7).unwrap(), &widened[..]);
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_rerank_depth_and_vector_placement() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::core::config::VectorPlacement;
+        use crate::core::diagnostics::{Diagnostics, HealthStatus};
+        use crate::core::distance::Distance;
+        use crate::core::search::Rerank;
+        use crate::storage::format::vectors_sidecar_path;
+
+        let data = uniform_data(1000, 24, -1.0..1.0, 11);
+        let queries = uniform_data(20, 24, -1.0..1.0, 12);
+        let (index, _) = seeded_index(&data, Metric::L2)?;
+        let (inline_file, inline) = save_and_load(&index, &SaveOptions::default())?;
+
+        // A rerank depth at least as deep as the candidate list changes nothing; a shallower one
+        // still reranks the top of it, and skipping returns stage-1 scores with decent overlap
+        let all = SearchParams::new(100);
+        let (mut top_overlap, mut skip_overlap) = (0, 0);
+        for query in &queries {
+            let expected = inline.search_with_params(query, 10, all);
+            assert_eq!(inline.search_with_params(query, 10, all.with_rerank(Rerank::Top(1000))), expected);
+            let top = inline.search_with_params(query, 10, all.with_rerank(Rerank::Top(40)));
+            top_overlap += top.iter().filter(|(key, _)| expected.iter().any(|e| e.0 == *key)).count();
+            assert!(top.iter().all(|(key, dist)| (*dist - inline.distance.distance(query, &data[*key as usize])).abs() < 1e-5));
+            let skipped = inline.search_with_params(query, 10, all.with_rerank(Rerank::Skip));
+            assert_eq!(skipped.len(), 10);
+            skip_overlap += skipped.iter().filter(|(key, _)| expected.iter().any(|e| e.0 == *key)).count();
+        }
+        assert!(top_overlap >= 190, "top-40 overlap {}", top_overlap);
+        assert!(skip_overlap >= 120, "quantized-only overlap {}", skip_overlap);
+
+        // Omitted: a smaller file that only searches on stage-1 codes
+        let omitted_file = NamedTempFile::new()?;
+        index.save_with(omitted_file.path(), &SaveOptions { vectors: VectorPlacement::Omitted, ..SaveOptions::default() })?;
+        let saved = std::fs::metadata(inline_file.path())?.len() - std::fs::metadata(omitted_file.path())?.len();
+        assert!(saved >= 1000 * 24 * 4, "saved {} bytes", saved);
+        let omitted = MmapIndex::load(omitted_file.path())?;
+        assert!(!omitted.has_full_vectors());
+        assert!(matches!(Diagnostics::check_health(&omitted), HealthStatus::Healthy));
+        assert!(omitted.get_vector_by_key(3).is_none());
+        assert!(HNSW::from_mmap(&omitted).is_err());
+        for query in &queries {
+            assert_eq!(omitted.search_two_stage(query, 10, 100), inline.search_with_params(query, 10, all.with_rerank(Rerank::Skip)));
+        }
+
+        // Sidecar: identical results while the sidecar is present, quantized-only without it
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("index.hnsw");
+        index.save_with(&path, &SaveOptions { vectors: VectorPlacement::Sidecar, ..SaveOptions::default() })?;
+        assert!(vectors_sidecar_path(&path).exists());
+        let sidecar = MmapIndex::load(&path)?;
+        assert!(sidecar.has_full_vectors());
+        assert!(matches!(Diagnostics::check_health(&sidecar), HealthStatus::Healthy));
+        for query in &queries {
+            assert_eq!(sidecar.search_two_stage(query, 10, 100), inline.search_two_stage(query, 10, 100));
+        }
+        assert_eq!(HNSW::from_mmap(&sidecar)?.get_vector_by_key(5).unwrap(), &data[5][..]);
+        drop(sidecar);
+        std::fs::remove_file(vectors_sidecar_path(&path))?;
+        let detached = MmapIndex::load(&path)?;
+        assert!(!detached.has_full_vectors());
+        assert_eq!(detached.search_two_stage(&queries[0], 10, 100), omitted.search_two_stage(&queries[0], 10, 100));
+
+        // A custom distance cannot rank stage-1 codes, so it keeps its vectors
+        struct Chebyshev;
+        impl Distance for Chebyshev {
+            fn distance(&self, a: &[f32], b: &[f32]) -> f32 {
+                a.iter().zip(b).map(|(x, y)| (x - y).abs()).fold(0.0, f32::max)
+            }
+        }
+        let mut custom = HNSW::with_distance(test_config(24, 6, 100, 12, 24), Chebyshev)?;
+        custom.insert(data[0].clone())?;
+        assert!(custom.save_with(omitted_file.path(), &SaveOptions { vectors: VectorPlacement::Omitted, ..SaveOptions::default() }).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_forced_scalar_kernels() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::core::config::ElementType;
+        use crate::simd::Kernels;
+
+        let data = uniform_data(800, 40, -1.0..1.0, 13);
+        let queries = uniform_data(20, 40, -1.0..1.0, 14);
+        assert!(Kernels::scalar().is_scalar());
+
+        for metric in [Metric::L2, Metric::InnerProduct, Metric::Cosine] {
+            let config = test_builder(40, 6, 100, 12, 24).metric(metric).seed(7).build()?;
+            let mut index = HNSW::with_kernels(config, &Kernels::scalar())?;
+            for v in &data {
+                index.insert(v.clone())?;
+            }
+
+            let options = [
+                SaveOptions::default(),
+                SaveOptions { quantization: Quantization::Scalar4, binary: true, ..SaveOptions::default() },
+                SaveOptions { quantization: Quantization::TrainedScalar8, element_type: ElementType::F16, ..SaveOptions::default() },
+            ];
+            for options in options {
+                let temp_file = NamedTempFile::new()?;
+                index.save_with(temp_file.path(), &options)?;
+                let detected = MmapIndex::load(temp_file.path())?;
+                let scalar = MmapIndex::load_with_kernels(temp_file.path(), Kernels::scalar())?;
+
+                // Integer kernels are exact, so only f32 summation order separates the two paths
+                let params = SearchParams::new(64).with_cascade(if options.binary { Cascade::Binary { keep: 40 } } else { Cascade::Quantized });
+                for query in &queries {
+                    let expected = detected.search_with_params(query, 10, params);
+                    let results = scalar.search_with_params(query, 10, params);
+                    assert_eq!(results.len(), expected.len());
+                    for ((_, a), (_, b)) in results.iter().zip(&expected) {
+                        assert!((a - b).abs() < 1e-4, "{:?} {:?}: {} vs {}", metric, options, a, b);
+                    }
+                    let overlap = results.iter().filter(|(key, _)| expected.iter().any(|e| e.0 == *key)).count();
+                    assert!(overlap >= 9, "{:?} {:?} overlap {}", metric, options, overlap);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_newer_version_rejected() -> Result<(), Box<dyn std::error::Error>> {
+        let mut index = HNSW::new(test_config(3, 4, 10, 5, 10))?;
+        index.insert(vec![1.0, 0.0, 0.0])?;
+        let temp_file = NamedTempFile::new()?;
+        index.save(temp_file.path())?;
+
+        let mut bytes = std::fs::read(temp_file.path())?;
+        let offset = std::mem::offset_of!(Header, version);
+        bytes[offset..offset + 4].copy_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+        std::fs::write(temp_file.path(), &bytes)?;
+        assert!(matches!(MmapIndex::load(temp_file.path()), Err(StorageError::InvalidHeader(_))));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_custom_distance_end_to_end() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::core::distance::Distance;
+        use rand::rngs::StdRng;
+        use rand::{Rng, SeedableRng};
+
+        struct Manhattan;
+        impl Distance for Manhattan {
+            fn distance(&self, a: &[f32], b: &[f32]) -> f32 {
+                a.iter().zip(b).map(|(x, y)| (x - y).abs()).sum()
+            }
+        }
+
+        // Serial and seeded, so the graph is the same on every run
+        let mut rng = StdRng::seed_from_u64(14);
+        let data: Vec<Vec<f32>> = (0..500).map(|_| (0..8).map(|_| rng.gen_range(-1.0f32..1.0)).collect()).collect();
+        let mut index = HNSW::with_distance(test_builder(8, 6, 100, 12, 24).seed(7).build()?, Manhattan)?;
+        for v in &data {
+            index.insert(v.clone())?;
+        }
+        let temp_file = NamedTempFile::new()?;
+        index.save(temp_file.path())?;
+
+        // The built-in loaders refuse a file they cannot search correctly
+        assert!(matches!(MmapIndex::load(temp_file.path()), Err(StorageError::InvalidHeader(_))));
+        let mmap_index = MmapIndex::load_with_distance(temp_file.path(), Manhattan)?;
+        assert_eq!(mmap_index.metric(), None);
+        assert!(HNSW::from_mmap(&mmap_index).is_err());
+        let reopened = HNSW::from_mmap_with_distance(&mmap_index, Manhattan)?;
+
+        let mut hits = 0;
+        for _ in 0..20 {
+            let query: Vec<f32> = (0..8).map(|_| rng.gen_range(-1.0f32..1.0)).collect();
+            let mut truth: Vec<(u64, f32)> = data.iter().enumerate().map(|(id, v)| (id as u64, Manhattan.distance(&query, v))).collect();
+            truth.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap());
+            let truth: Vec<u64> = truth.iter().take(10).map(|(id, _)| *id).collect();
+
+            let from_mmap = mmap_index.search_two_stage(&query, 10, 100);
+            assert_eq!(from_mmap, index.search_with_params(&query, 10, SearchParams::new(100)));
+            assert_eq!(from_mmap, reopened.search_with_params(&query, 10, SearchParams::new(100)));
+            let (key, dist) = from_mmap[0];
+            assert_eq!(dist, Manhattan.distance(&query, &data[key as usize]));
+            hits += from_mmap.iter().filter(|(key, _)| truth.contains(key)).count();
+        }
+        assert!(hits as f32 / 200.0 >= 0.9, "recall {}", hits as f32 / 200.0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_deleted_bitmap_roundtrip() -> Result<(), Box<dyn std::error::Error>> {
+        let mut index = HNSW::new(test_config(3, 4, 10, 5, 10))?;
+        index.insert(vec![1.0, 0.0, 0.0])?;
+        index.insert(vec![0.0, 1.0, 0.0])?;
+        index.insert(vec![0.0, 0.0, 1.0])?;
+        index.insert(vec![0.1, 0.9, 0.0])?;
+        assert!(index.delete(1));
+
+        let temp_file = NamedTempFile::new()?;
+        index.save(temp_file.path())?;
+        let mmap_index = MmapIndex::load(temp_file.path())?;
+
+        assert!(mmap_index.is_deleted(1));
+        assert!(!mmap_index.is_deleted(3));
+        assert_eq!(mmap_index.deleted_bitmap().map(|b| b.len()), Some(1));
+
+        let results = mmap_index.search_two_stage(&[0.0, 1.0, 0.0], 2, 10);
+        assert_eq!(results[0].0, 3);
+        assert!(results.iter().all(|(id, _)| *id != 1));
+
+        // No deletions: no bitmap section
+        let mut clean = HNSW::new(test_config(3, 4, 10, 5, 10))?;
+        clean.insert(vec![1.0, 0.0, 0.0])?;
+        clean.save(temp_file.path())?;
+        let mmap_clean = MmapIndex::load(temp_file.path())?;
+        assert!(mmap_clean.deleted_bitmap().is_none());
+        assert!(!mmap_clean.is_deleted(0));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_external_key_mapping() -> Result<(), Box<dyn std::error::Error>> {
+        let mut index = HNSW::new(test_config(3, 4, 10, 5, 10))?;
+        index.insert_with_key(900, vec![1.0, 0.0, 0.0])?;
+        index.insert_with_key(17, vec![0.0, 1.0, 0.0])?;
+        index.insert_with_key(u64::MAX - 1, vec![0.0, 0.0, 1.0])?;
+        index.insert_with_key(5, vec![1.0, 1.0, 0.0])?;
+        index.delete_by_key(5);
+
+        let temp_file = NamedTempFile::new()?;
+        index.save(temp_file.path())?;
+        let mmap_index = MmapIndex::load(temp_file.path())?;
+
+        assert_eq!(mmap_index.header().num_keys, 3);
+        assert_eq!(mmap_index.get_key(2), u64::MAX - 1);
+        assert_eq!(mmap_index.get_id(17), Some(1));
+        assert_eq!(mmap_index.get_id(5), None);
+        assert_eq!(mmap_index.get_id(6), None);
+        assert!((mmap_index.get_vector_by_key(u64::MAX - 1).unwrap().to_vec()[2] - 1.0).abs() < 1e-6);
+
+        let results = mmap_index.search_two_stage(&[0.1, 0.9, 0.0], 1, 10);
+        assert_eq!(results[0].0, 17);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_reopen_and_append() -> Result<(), Box<dyn std::error::Error>> {
+        use rand::rngs::StdRng;
+        use rand::{Rng, SeedableRng};
+        let mut rng = StdRng::seed_from_u64(5);
+        let mut random_vec = || -> Vec<f32> { (0..8).map(|_| rng.gen::<f32>() - 0.5).collect() };
+
+        let mut index = HNSW::new(test_builder(8, 5, 32, 8, 16).seed(5).build()?)?;
+        for key in 0..200u64 {
+            index.insert_with_key(key * 10, random_vec())?;
+        }
+        index.delete_by_key(30);
+
+        let temp_file = NamedTempFile::new()?;
+        index.save(temp_file.path())?;
+
+        let day_two: Vec<Vec<f32>> = (0..100).map(|_| random_vec()).collect();
+        {
+            let mmap_index = MmapIndex::load(temp_file.path())?;
+            let mut reopened = HNSW::from_mmap(&mmap_index)?;
+
+            assert_eq!(reopened.len(), 200);
+            assert_eq!(reopened.config, index.config);
+            assert_eq!(reopened.entry_point, index.entry_point);
+            assert!(reopened.is_deleted(3));
+            assert_eq!(reopened.id_for_key(30), None);
+            for id in 0..index.len() {
+                assert_eq!(reopened.level(id), index.level(id));
+                for level in 0..=index.level(id) {
+                    assert_eq!(reopened.neighbors(id, level), index.neighbors(id, level));
+                }
+            }
+
+            for (i, v) in day_two.iter().enumerate() {
+                reopened.insert_with_key(5000 + i as u64, v.clone())?;
+            }
+            // Auto keys continue after the persisted ones
+            let extra = reopened.insert(random_vec())?;
+            assert_eq!(reopened.key(extra), 5100);
+            reopened.save(temp_file.path())?;
+        }
+
+        let appended = MmapIndex::load(temp_file.path())?;
+        assert_eq!(appended.header().num_elements, 301);
+        assert!(appended.is_deleted(3));
+        assert_eq!(appended.get_id(1990), Some(199));
+        let mut found = 0;
+        for (i, v) in day_two.iter().enumerate() {
+            if appended.search_two_stage(v, 1, 64)[0].0 == 5000 + i as u64 {
+                found += 1;
+            }
+        }
+        assert!(found >= 95, "only {} of 100 appended vectors found", found);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_from_mmap_rejects_bad_layer_count() -> Result<(), Box<dyn std::error::Error>> {
+        let mut index = HNSW::new(test_config(4, 5, 16, 4, 8))?;
+        for i in 0..20 {
+            index.insert(vec![i as f32, 1.0, 0.0, 0.0])?;
+        }
+        let temp_file = NamedTempFile::new()?;
+        index.save(temp_file.path())?;
+
+        let header_size = std::mem::size_of::<Header>();
+        for layer_count in [0u8, 6] {
+            // Node 0 claims an impossible depth, with a checksum that still matches
+            let mut bytes = std::fs::read(temp_file.path())?;
+            let mut header = *bytemuck::from_bytes::<Header>(&bytes[..header_size]);
+            bytes[header.nodes_offset as usize] = layer_count;
+            header.checksum = crc32fast::hash(&bytes[header_size..]) as u64;
+            bytes[..header_size].copy_from_slice(bytemuck::bytes_of(&header));
+            let corrupt_file = NamedTempFile::new()?;
+            std::fs::write(corrupt_file.path(), &bytes)?;
+
+            let mmap_index = MmapIndex::load(corrupt_file.path())?;
+            assert!(matches!(HNSW::from_mmap(&mmap_index), Err(crate::core::hnsw::HnswError::InvalidConfig(_))), "layer_count {}", layer_count);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_search_with_params_matches_two_stage() -> Result<(), Box<dyn std::error::Error>> {
+        let mut index = HNSW::new(test_config(3, 4, 10, 5, 10))?;
+        index.insert(vec![1.0, 0.0, 0.0])?;
+        index.insert(vec![0.0, 1.0, 0.0])?;
+        index.insert(vec![0.0, 0.0, 1.0])?;
+
+        let temp_file = NamedTempFile::new()?;
+        index.save(temp_file.path())?;
+        let mmap_index = MmapIndex::load(temp_file.path())?;
+
+        let query = [0.1, 0.9, 0.0];
+        let params = SearchParams { ef: 10, ..SearchParams::default() };
+        assert_eq!(mmap_index.search_with_params(&query, 2, params), mmap_index.search_two_stage(&query, 2, 10));
+        assert_eq!(mmap_index.search_with_params(&query, 1, params)[0].0, index.search_with_params(&query, 1, params)[0].0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_persisted_in_header() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::core::config::HnswConfig;
+        use crate::core::hnsw::NeighborSelection;
+
+        let config = HnswConfig::builder(3)
+            .m(6)
+            .m0(9)
+            .ef_construction(20)
+            .level_multiplier(0.7)
+            .max_layers(7)
+            .seed(1234)
+            .neighbor_selection(NeighborSelection::Heuristic { extend_candidates: false, keep_pruned_connections: true })
+            .metric(Metric::InnerProduct)
+            .build()?;
+        let mut index = HNSW::new(config)?;
+        index.insert(vec![1.0, 0.0, 0.0])?;
+        index.insert(vec![0.0, -4.0, 0.0])?;
+
+        let temp_file = NamedTempFile::new()?;
+        index.save(temp_file.path())?;
+        let mmap_index = MmapIndex::load(temp_file.path())?;
+
+        let header = mmap_index.header();
+        assert_eq!((header.m_max, header.m_max_0, header.ef_construction, header.max_layers), (6, 9, 20, 7));
+        assert_eq!((header.level_multiplier, header.seed), (0.7, 1234));
+        assert_eq!((mmap_index.metric(), header.max_norm), (Some(Metric::InnerProduct), 4.0));
+        let reopened = HNSW::from_mmap(&mmap_index)?;
+        assert_eq!(reopened.config, config);
+        // Inner product vectors are stored as inserted, only the u8 arena is scaled
+        assert_eq!(reopened.vector(1), &[0.0, -4.0, 0.0]);
+        assert_eq!(mmap_index.get_quantized_vector(1)[1], 0);
+
+        Ok(())
+    }
 }
@@ -1,2 +1,3 @@
 pub mod mmap;
 pub mod format;
+pub mod ivf;
@@ -0,0 +1,66 @@
+//! Allocation counting needs its own `#[global_allocator]`, so it lives in its own test binary
+//! instead of slowing down every unit test.
+
+use rand::Rng;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use tempfile::NamedTempFile;
+use vector_engine::core::config::HnswConfig;
+use vector_engine::core::hnsw::HNSW;
+use vector_engine::core::search::SearchContext;
+use vector_engine::storage::mmap::MmapIndex;
+
+/// Counts heap allocations made by the current thread.
+struct CountingAlloc;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|n| n.set(n.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|n| n.set(n.get() + 1));
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+#[test]
+fn test_search_context_does_not_allocate() -> Result<(), Box<dyn std::error::Error>> {
+    let mut rng = rand::thread_rng();
+    let data: Vec<Vec<f32>> = (0..500).map(|_| (0..16).map(|_| rng.gen::<f32>()).collect()).collect();
+
+    let config = HnswConfig::builder(16).max_layers(5).ef_construction(32).m(8).m0(16).build()?;
+    let mut index = HNSW::new(config)?;
+    index.insert_parallel(data.clone())?;
+    let temp_file = NamedTempFile::new()?;
+    index.save(temp_file.path())?;
+    let mmap_index = MmapIndex::load(temp_file.path())?;
+
+    let mut ctx = SearchContext::new();
+    // Warm-up grows the buffers
+    for query in data.iter().take(20) {
+        let expected = mmap_index.search_two_stage(query, 10, 64);
+        assert_eq!(mmap_index.search_two_stage_with(&mut ctx, query, 10, 64), expected.as_slice());
+    }
+
+    let before = ALLOCATIONS.with(|n| n.get());
+    for query in &data {
+        let results = mmap_index.search_two_stage_with(&mut ctx, query, 10, 64);
+        assert_eq!(results.len(), 10);
+    }
+    assert_eq!(ALLOCATIONS.with(|n| n.get()), before);
+
+    Ok(())
+}